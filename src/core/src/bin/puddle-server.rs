@@ -10,9 +10,11 @@ extern crate env_logger;
 #[macro_use]
 extern crate log;
 
+extern crate serde_json;
+
 use std::env;
 use std::fs::File;
-use std::io::Read;
+use std::io::{self, Read};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
@@ -20,7 +22,7 @@ use rouille::{Request, Response};
 
 use jsonrpc_core::IoHandler;
 
-use clap::{App, Arg, ArgMatches};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 
 use puddle_core::*;
 
@@ -40,7 +42,178 @@ fn handle(ioh: &IoHandler, req: &Request) -> Response {
     resp
 }
 
+fn gen_arch(matches: &ArgMatches) -> Result<(), Box<::std::error::Error>> {
+    let mut params = GenParams::default();
+    // all of these have defaults, so they're safe to unwrap
+    params.height = matches.value_of("height").unwrap().parse()?;
+    params.width = matches.value_of("width").unwrap().parse()?;
+    params.staggered = matches.occurrences_of("staggered") > 0;
+    params.reservoirs = matches.value_of("reservoirs").unwrap().parse()?;
+    params.heaters = matches.value_of("heaters").unwrap().parse()?;
+    params.sensors = matches.value_of("sensors").unwrap().parse()?;
+    params.zone_size = matches.value_of("zone-size").unwrap().parse()?;
+    params.hole_fraction = matches.value_of("holes").unwrap().parse()?;
+    params.seed = matches.value_of("seed").unwrap().parse()?;
+
+    let grid = Grid::generate(&params)?;
+    serde_json::to_writer_pretty(io::stdout(), &grid)?;
+    println!();
+    Ok(())
+}
+
+fn parse_dims(s: &str) -> Result<Location, Box<::std::error::Error>> {
+    let mut parts = s.split('x');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(y), Some(x), None) => Ok(Location {
+            y: y.parse()?,
+            x: x.parse()?,
+        }),
+        _ => Err(format!("dimensions should look like 2x3, not '{}'", s).into()),
+    }
+}
+
+fn parse_location(s: &str) -> Result<Location, Box<::std::error::Error>> {
+    let mut parts = s.split(',');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(y), Some(x), None) => Ok(Location {
+            y: y.trim().parse()?,
+            x: x.trim().parse()?,
+        }),
+        _ => Err(format!("locations should look like 2,3, not '{}'", s).into()),
+    }
+}
+
+/// The wash given with `--wash-reservoir` and `--wash-waste`, if any.
+fn wash_option(
+    matches: &ArgMatches,
+    grid: &Grid,
+) -> Result<Option<Wash>, Box<::std::error::Error>> {
+    let reservoir = match matches.value_of("wash-reservoir") {
+        Some(reservoir) => parse_location(reservoir)?,
+        None => return Ok(None),
+    };
+    // clap makes sure the waste comes along
+    let waste = parse_location(matches.value_of("wash-waste").unwrap())?;
+    for loc in &[reservoir, waste] {
+        if grid.get_cell(loc).is_none() {
+            return Err(format!("the wash can't use {:?}, it isn't on the board", loc).into());
+        }
+    }
+    // wash droplets are drained at the waste, which only a reservoir can do
+    if grid.get_cell(&waste).unwrap().kind != CellKind::Reservoir {
+        return Err(format!("the wash waste {:?} has to be a reservoir", waste).into());
+    }
+    Ok(Some(Wash::new(grid, reservoir, waste)))
+}
+
+/// Draws the board with the bottleneck cells marked: `X` for articulations
+/// and `=` for corridors.
+fn draw_bottlenecks(grid: &Grid, bottlenecks: &Bottlenecks) -> String {
+    let articulations = bottlenecks.covered(&bottlenecks.articulations);
+    let cells = bottlenecks.cells();
+
+    let mut s = String::new();
+    for y in 0..grid.max_height() {
+        for x in 0..grid.max_width() {
+            let loc = Location {
+                y: y as i32,
+                x: x as i32,
+            };
+            s.push(if grid.get_cell(&loc).is_none() {
+                ' '
+            } else if articulations.contains(&loc) {
+                'X'
+            } else if cells.contains(&loc) {
+                '='
+            } else {
+                '.'
+            });
+        }
+        s.push('\n');
+    }
+    s
+}
+
+fn validate(matches: &ArgMatches) -> Result<(), Box<::std::error::Error>> {
+    // required argument is safe to unwrap
+    let path = matches.value_of("arch").unwrap();
+    let reader = File::open(path)?;
+    let grid = if Path::new(path).extension().map_or(false, |ext| ext == "json") {
+        Grid::from_reader(reader)?
+    } else {
+        InitialState::from_reader(reader)?.grid
+    };
+
+    println!(
+        "{}x{} board with {} electrodes",
+        grid.max_height(),
+        grid.max_width(),
+        grid.locations().count()
+    );
+    if !grid.is_connected() {
+        return Err("the board is not connected".into());
+    }
+
+    for dims in matches.values_of("dims").unwrap() {
+        let dims = parse_dims(dims)?;
+        let bottlenecks = grid.bottlenecks(&dims);
+        println!(
+            "\n{}x{} droplets: {} articulation positions, {} corridor positions",
+            dims.y,
+            dims.x,
+            bottlenecks.articulations.len(),
+            bottlenecks.corridors.len()
+        );
+        if !bottlenecks.is_empty() {
+            print!("{}", draw_bottlenecks(&grid, &bottlenecks));
+        }
+    }
+
+    Ok(())
+}
+
+/// The manager options given on the command line, for the board at `path`.
+fn manager_options(
+    matches: &ArgMatches,
+    path: &str,
+) -> Result<ManagerOptions, Box<::std::error::Error>> {
+    let mut manager_opts = ManagerOptions::default();
+    if matches.occurrences_of("wear") > 0 {
+        let wear_path = Wear::sidecar_path(Path::new(path));
+        // make sure it's readable now rather than in the manager
+        Wear::load(&wear_path)?;
+        info!("Tracking wear in {:?}", wear_path);
+        manager_opts.wear_path = Some(wear_path);
+    }
+    if let Some(weight) = matches.value_of("wear-weight") {
+        manager_opts.wear_weight = weight.parse()?;
+    }
+    if let Some(seed) = matches.value_of("seed") {
+        manager_opts.seed = Some(seed.parse()?);
+    }
+    // clap already made sure these are all built in
+    if let Some(name) = matches.value_of("cost-model") {
+        manager_opts.cost_model = builtin_cost_model(name);
+    }
+    if let Some(name) = matches.value_of("placer") {
+        manager_opts.placer = builtin_placer(name);
+    }
+    if let Some(name) = matches.value_of("router") {
+        manager_opts.router = builtin_router(name);
+    }
+    // this one has a default, so it's safe to unwrap
+    manager_opts.max_evictions = matches.value_of("max-evictions").unwrap().parse()?;
+    Ok(manager_opts)
+}
+
 fn run(matches: ArgMatches) -> Result<(), Box<::std::error::Error>> {
+    if let Some(matches) = matches.subcommand_matches("gen-arch") {
+        return gen_arch(matches);
+    }
+    if let Some(matches) = matches.subcommand_matches("validate") {
+        return validate(matches);
+    }
+
     // required argument is safe to unwrap
     let path = matches.value_of("arch").unwrap();
     let reader = File::open(path)?;
@@ -54,8 +227,20 @@ fn run(matches: ArgMatches) -> Result<(), Box<::std::error::Error>> {
     //     manager_opts.split_error_stdev = err.parse()?;
     // };
 
-    let grid = Grid::from_reader(reader)?;
-    let manager = Manager::new(should_sync, grid);
+    let mut manager_opts = manager_options(&matches, path)?;
+
+    // json files are plain boards, anything else is drawn in ascii and may
+    // have droplets on it
+    let manager = if Path::new(path).extension().map_or(false, |ext| ext == "json") {
+        let grid = Grid::from_reader(reader)?;
+        manager_opts.wash = wash_option(&matches, &grid)?;
+        Manager::new(should_sync, grid, manager_opts)
+    } else {
+        let state = InitialState::from_reader(reader)?;
+        manager_opts.wash = wash_option(&matches, &state.grid)?;
+        Manager::from_initial_state(should_sync, state, manager_opts)
+            .map_err(|e| format!("{:?}", e))?
+    };
     let arc = Arc::new(manager);
 
     let mut ioh = IoHandler::new();
@@ -106,18 +291,16 @@ fn check_dir(dir: String) -> Result<(), String> {
     }
 }
 
-fn main() {
-    // enable logging
-    let _ = env_logger::try_init();
-
-    let matches = App::new("puddle")
+fn app() -> App<'static, 'static> {
+    App::new("puddle")
         .version("0.1")
         .author("Max Willsey <me@mwillsey.com>")
         .about("Runs a server for Puddle")
+        .setting(AppSettings::SubcommandsNegateReqs)
         .arg(
             Arg::with_name("arch")
                 .value_name("ARCH_FILE")
-                .help("The architecture file")
+                .help("The architecture file, either json or an ascii drawing")
                 .takes_value(true)
                 .required(true),
         )
@@ -146,7 +329,156 @@ fn main() {
                 .takes_value(true),
         )
         .arg(Arg::with_name("sync").long("sync"))
-        .get_matches();
+        .arg(
+            Arg::with_name("wear")
+                .long("wear")
+                .help("Keep electrode wear counts in a file next to the architecture file"),
+        )
+        .arg(
+            Arg::with_name("wear-weight")
+                .long("wear-weight")
+                .help("How hard to avoid worn electrodes when placing and routing")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("seed")
+                .long("seed")
+                .help("Seed for planning, to replay an earlier run; the log says which was used")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("cost-model")
+                .long("cost-model")
+                .help("What routing tries to keep low besides wear: distance, energy, or heaters")
+                .possible_values(BUILTIN_COST_MODELS)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("placer")
+                .long("placer")
+                .help("How to place commands: scored, or first-fit")
+                .possible_values(BUILTIN_PLACERS)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("router")
+                .long("router")
+                .help("How to route droplets: prioritized, or conflict-based")
+                .possible_values(BUILTIN_ROUTERS)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-evictions")
+                .long("max-evictions")
+                .help("How many idle droplets may be moved aside for a command that doesn't fit")
+                .default_value("2")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("wash-reservoir")
+                .long("wash-reservoir")
+                .value_name("Y,X")
+                .help("Where wash droplets come from; defaults to the board's first reservoir")
+                .requires("wash-waste")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("wash-waste")
+                .long("wash-waste")
+                .value_name("Y,X")
+                .help("The reservoir wash droplets are thrown away in; defaults to the last one")
+                .requires("wash-reservoir")
+                .takes_value(true),
+        )
+        .subcommand(
+            SubCommand::with_name("gen-arch")
+                .about("Prints a generated architecture file")
+                .arg(
+                    Arg::with_name("height")
+                        .long("height")
+                        .default_value("7")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("width")
+                        .long("width")
+                        .default_value("9")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("staggered")
+                        .long("staggered")
+                        .help("Offset every other row by half a cell"),
+                )
+                .arg(
+                    Arg::with_name("reservoirs")
+                        .long("reservoirs")
+                        .help("How many reservoirs to put along the left and right edges")
+                        .default_value("0")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("heaters")
+                        .long("heaters")
+                        .help("How many heater zones to place")
+                        .default_value("0")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("sensors")
+                        .long("sensors")
+                        .help("How many sensor zones to place")
+                        .default_value("0")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("zone-size")
+                        .long("zone-size")
+                        .help("The side length of heater and sensor zones")
+                        .default_value("2")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("holes")
+                        .long("holes")
+                        .help("The fraction of electrodes to knock out")
+                        .default_value("0")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("seed")
+                        .long("seed")
+                        .default_value("0")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("validate")
+                .about("Checks an architecture file and shows where droplets will get stuck")
+                .arg(
+                    Arg::with_name("arch")
+                        .value_name("ARCH_FILE")
+                        .help("The architecture file, either json or an ascii drawing")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("dims")
+                        .long("dims")
+                        .help("Droplet dimensions to check, like 2x3")
+                        .takes_value(true)
+                        .multiple(true)
+                        .use_delimiter(true)
+                        .default_value("1x1,2x2"),
+                ),
+        )
+}
+
+fn main() {
+    // enable logging
+    let _ = env_logger::try_init();
+
+    let matches = app().get_matches();
 
     ::std::process::exit(match run(matches) {
         Ok(_) => 0,
@@ -156,3 +488,23 @@ fn main() {
         }
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manager_options() {
+        let options = |extra: &[&str]| {
+            let mut args = vec!["puddle-server", "board.json", "--static", "."];
+            args.extend(extra);
+            let matches = app().get_matches_from(args);
+            manager_options(&matches, "board.json").unwrap()
+        };
+
+        // the server moves droplets out of the way unless told not to
+        assert_eq!(options(&[]).max_evictions, 2);
+        assert_eq!(options(&["--max-evictions", "0"]).max_evictions, 0);
+        assert!(options(&["--router", "conflict-based"]).router.is_some());
+    }
+}
@@ -2,9 +2,12 @@ use grid::gridview::{GridSubView, GridView};
 use std::fmt;
 use std::sync::mpsc::Sender;
 
-use grid::{Droplet, DropletId, DropletInfo, Grid, Location, Snapshot};
+use grid::{
+    ActuationError, Droplet, DropletId, DropletInfo, Footprint, Grid, Location, Reagent, Snapshot,
+};
 
-use process::{ProcessId, PuddleResult};
+use plan::PlanError;
+use process::{ProcessId, PuddleError, PuddleResult};
 
 pub trait Command: fmt::Debug + Send {
     fn input_droplets(&self) -> Vec<DropletId> {
@@ -21,7 +24,76 @@ pub trait Command: fmt::Debug + Send {
     fn trust_placement(&self) -> bool {
         false
     }
+    /// Whether placement may turn or flip the command's shape. If it does,
+    /// `run` sees everything turned to match, droplets and directions alike.
+    fn orientable(&self) -> bool {
+        true
+    }
+    /// Where a trusted command puts droplets other than its inputs.
+    fn fixed_locations(&self) -> Vec<Location> {
+        vec![]
+    }
     fn finalize(&mut self, &Snapshot) {}
+    /// Called instead of `finalize` when the command will never run because
+    /// the board stopped.
+    fn abort(&mut self, &ActuationError) {}
+}
+
+/// Looks up an input droplet as it will be once everything planned so far
+/// has run.
+fn get_droplet<'a>(gridview: &'a GridView, id: &DropletId) -> PuddleResult<&'a Droplet> {
+    if let Some(location) = gridview.stranded(id) {
+        return Err(PuddleError::PlanError(PlanError::StrandedDroplet {
+            id: *id,
+            location,
+        }));
+    }
+    gridview
+        .snapshot()
+        .droplets
+        .get(id)
+        .ok_or(PuddleError::NonExistentDropletId(id.id))
+}
+
+/// Checks that the droplet `id` would have a volume within the board's
+/// limits.
+fn check_bounds(grid: &Grid, id: DropletId, volume: f64) -> PuddleResult<()> {
+    if grid.volume_in_bounds(volume) {
+        Ok(())
+    } else {
+        Err(PuddleError::VolumeOutOfBounds { id, volume })
+    }
+}
+
+/// The footprint of a droplet made by a command. Boards that know their
+/// physical parameters get a footprint sized to the volume; others just use
+/// `default`.
+fn output_footprint(grid: &Grid, volume: f64, default: Footprint) -> PuddleResult<Footprint> {
+    let footprint = grid.footprint(volume).map_or(default, Footprint::Rect);
+    check_fits(grid, volume, &footprint.dimensions())?;
+    Ok(footprint)
+}
+
+/// Checks that `volume` fits over a droplet of the given dimensions, and that
+/// such a droplet fits on the board. Boards without physical parameters
+/// accept anything.
+fn check_fits(grid: &Grid, volume: f64, dimensions: &Location) -> PuddleResult<()> {
+    let fits = match grid.capacity(dimensions) {
+        Some(capacity) => {
+            volume <= capacity + 1e-9
+                && dimensions.y as usize <= grid.max_height()
+                && dimensions.x as usize <= grid.max_width()
+        }
+        None => true,
+    };
+    if fits {
+        Ok(())
+    } else {
+        Err(PuddleError::VolumeDoesNotFit {
+            volume,
+            dimensions: *dimensions,
+        })
+    }
 }
 
 //
@@ -33,9 +105,10 @@ pub struct Input {
     inputs: Vec<DropletId>,
     outputs: Vec<DropletId>,
     location: Location,
-    dimensions: Location,
+    footprint: Footprint,
     volume: f64,
     trusted: bool,
+    reagent: Option<Reagent>,
 }
 
 #[derive(Debug)]
@@ -44,23 +117,49 @@ pub struct DynamicCommandInfo {
     pub input_locations: Vec<Location>,
 }
 
-// TODO: dimensions probably shouldn't be optional?
 impl Input {
+    /// Without `dim`, the droplet gets a footprint sized to its volume, or a
+    /// single cell if the board doesn't know its physical parameters.
     pub fn new(
         loc: Option<Location>,
         vol: f64,
         dim: Option<Location>,
         out_id: DropletId,
+        grid: &Grid,
     ) -> PuddleResult<Input> {
+        let footprint = match dim {
+            Some(dim) => Footprint::Rect(dim),
+            None => output_footprint(grid, vol, Footprint::Rect(Location { y: 1, x: 1 }))?,
+        };
+        Input::with_footprint(loc, vol, footprint, out_id, grid)
+    }
+
+    pub fn with_footprint(
+        loc: Option<Location>,
+        vol: f64,
+        footprint: Footprint,
+        out_id: DropletId,
+        grid: &Grid,
+    ) -> PuddleResult<Input> {
+        check_bounds(grid, out_id, vol)?;
+        check_fits(grid, vol, &footprint.dimensions())?;
         Ok(Input {
             inputs: vec![],
             outputs: vec![out_id],
             location: loc.unwrap_or(Location { y: 0, x: 0 }),
-            dimensions: dim.unwrap_or(Location { y: 1, x: 1 }),
+            footprint,
             volume: vol,
             trusted: loc.is_some(),
+            reagent: None,
         })
     }
+
+    /// Says what the droplet is made of, so routing can keep it off of the
+    /// residue of anything it's incompatible with.
+    pub fn with_reagent(mut self, reagent: Option<Reagent>) -> Input {
+        self.reagent = reagent;
+        self
+    }
 }
 
 impl Command for Input {
@@ -73,7 +172,8 @@ impl Command for Input {
     }
 
     fn dynamic_info(&self, _gridview: &GridView) -> DynamicCommandInfo {
-        let grid = Grid::rectangle(self.dimensions.y as usize, self.dimensions.x as usize);
+        let dim = self.footprint.dimensions();
+        let grid = Grid::rectangle(dim.y as usize, dim.x as usize);
 
         DynamicCommandInfo {
             shape: grid,
@@ -82,18 +182,33 @@ impl Command for Input {
     }
 
     fn run(&self, gridview: &mut GridSubView) {
-        gridview.insert(Droplet::new(
+        let mut droplet = Droplet::with_footprint(
             self.outputs[0],
             self.volume,
             self.location,
-            self.dimensions,
-        ));
+            self.footprint.clone(),
+        );
+        droplet.reagents.extend(self.reagent.clone());
+        gridview.insert(droplet);
         gridview.tick();
     }
 
     fn trust_placement(&self) -> bool {
         self.trusted
     }
+
+    // the droplet should come out the way it was asked for
+    fn orientable(&self) -> bool {
+        false
+    }
+
+    fn fixed_locations(&self) -> Vec<Location> {
+        self.footprint
+            .offsets()
+            .iter()
+            .map(|off| &self.location + off)
+            .collect()
+    }
 }
 
 //
@@ -103,11 +218,11 @@ impl Command for Input {
 #[derive(Debug)]
 pub struct Flush {
     pid: ProcessId,
-    tx: Sender<Vec<DropletInfo>>,
+    tx: Sender<PuddleResult<Vec<DropletInfo>>>,
 }
 
 impl Flush {
-    pub fn new(pid: ProcessId, tx: Sender<Vec<DropletInfo>>) -> Flush {
+    pub fn new(pid: ProcessId, tx: Sender<PuddleResult<Vec<DropletInfo>>>) -> Flush {
         Flush { pid, tx }
     }
 }
@@ -126,7 +241,13 @@ impl Command for Flush {
 
     fn finalize(&mut self, gv: &Snapshot) {
         let info = gv.droplet_info(Some(self.pid));
-        self.tx.send(info).unwrap();
+        self.tx.send(Ok(info)).unwrap();
+    }
+
+    fn abort(&mut self, err: &ActuationError) {
+        let err = PuddleError::ActuationError(err.clone());
+        // the process may have given up waiting, which is fine
+        let _ = self.tx.send(Err(err));
     }
 }
 
@@ -162,7 +283,7 @@ impl Command for Move {
 
     fn dynamic_info(&self, gridview: &GridView) -> DynamicCommandInfo {
         let old_id = self.inputs[0];
-        let dim = gridview.snapshot().droplets[&old_id].dimensions;
+        let dim = gridview.snapshot().droplets[&old_id].dimensions();
         DynamicCommandInfo {
             shape: Grid::rectangle(dim.y as usize, dim.x as usize),
             input_locations: vec![self.destination[0]],
@@ -192,13 +313,30 @@ impl Command for Move {
 pub struct Mix {
     inputs: Vec<DropletId>,
     outputs: Vec<DropletId>,
+    footprint: Footprint,
 }
 
 impl Mix {
-    pub fn new(id1: DropletId, id2: DropletId, out_id: DropletId) -> PuddleResult<Mix> {
+    pub fn new(
+        id1: DropletId,
+        id2: DropletId,
+        out_id: DropletId,
+        gridview: &GridView,
+    ) -> PuddleResult<Mix> {
+        let d0 = get_droplet(gridview, &id1)?;
+        let d1 = get_droplet(gridview, &id2)?;
+        let volume = d0.volume + d1.volume;
+        check_bounds(&gridview.grid, out_id, volume)?;
+        // by default, the droplets just end up side by side
+        let beside = Location {
+            y: 0,
+            x: d0.dimensions().x,
+        };
+        let default = d0.footprint.union(&d1.footprint, &beside);
         Ok(Mix {
             inputs: vec![id1, id2],
             outputs: vec![out_id],
+            footprint: output_footprint(&gridview.grid, volume, default)?,
         })
     }
 }
@@ -221,10 +359,12 @@ impl Command for Mix {
         let (grid, input_locations) = {
             let d0 = droplets.get(&self.inputs[0]).unwrap();
             let d1 = droplets.get(&self.inputs[1]).unwrap();
-            let y_dim = (d0.dimensions.y.max(d1.dimensions.y) as usize) + MIX_PADDING;
-            let x_dim = (d0.dimensions.x as usize) + (d1.dimensions.x as usize) + MIX_PADDING;
+            let dim = self.footprint.dimensions();
+            let (dim0, dim1) = (d0.dimensions(), d1.dimensions());
+            let y_dim = (dim0.y.max(dim1.y).max(dim.y) as usize) + MIX_PADDING;
+            let x_dim = ((dim0.x + dim1.x).max(dim.x) as usize) + MIX_PADDING;
 
-            let start_d1 = d0.dimensions.x + 1;
+            let start_d1 = dim0.x + 1;
 
             (
                 Grid::rectangle(y_dim, x_dim),
@@ -251,14 +391,11 @@ impl Command for Mix {
         let d1 = gridview.remove(&in1);
         let vol = d0.volume + d1.volume;
         // TODO right now this only mixes horizontally
-        // it should somehow communicate with the Mix command to control the mixed droplets dimensions
-        let dim = Location {
-            y: d0.dimensions.y.max(d1.dimensions.y),
-            x: d0.dimensions.x + d1.dimensions.x,
-        };
         assert_eq!(d0.location.y, d1.location.y);
-        assert_eq!(d0.location.x + d0.dimensions.x, d1.location.x);
-        gridview.insert(Droplet::new(out, vol, d0.location, dim));
+        assert_eq!(d0.location.x + d0.dimensions().x, d1.location.x);
+        let mut mixed = Droplet::with_footprint(out, vol, d0.location, self.footprint.clone());
+        mixed.reagents = d0.reagents.union(&d1.reagents).cloned().collect();
+        gridview.insert(mixed);
 
         gridview.tick();
         gridview.move_south(out);
@@ -280,15 +417,38 @@ impl Command for Mix {
 pub struct Split {
     inputs: Vec<DropletId>,
     outputs: Vec<DropletId>,
+    dimensions: Location,
 }
 
 impl Split {
-    pub fn new(id: DropletId, out_id1: DropletId, out_id2: DropletId) -> PuddleResult<Split> {
+    pub fn new(
+        id: DropletId,
+        out_id1: DropletId,
+        out_id2: DropletId,
+        gridview: &GridView,
+    ) -> PuddleResult<Split> {
+        let d = get_droplet(gridview, &id)?;
+        check_bounds(&gridview.grid, out_id1, d.volume / 2.0)?;
+        // by default, take the ceiling of the division of the split by two
+        let default = Location {
+            y: d.dimensions().y,
+            x: (d.dimensions().x + 1) / 2,
+        };
+        let default = Footprint::Rect(default);
+        let dimensions = output_footprint(&gridview.grid, d.volume / 2.0, default)?.dimensions();
         Ok(Split {
             inputs: vec![id],
             outputs: vec![out_id1, out_id2],
+            dimensions,
         })
     }
+
+    /// The width of the shape needed to split a droplet of width `input_x`.
+    /// The halves are pulled apart with at least a cell between them.
+    fn x_dim(&self, input_x: i32) -> usize {
+        let halves = 2 * self.dimensions.x as usize + 3;
+        (input_x as usize + SPLIT_PADDING).max(halves)
+    }
 }
 
 const SPLIT_PADDING: usize = 4;
@@ -306,8 +466,8 @@ impl Command for Split {
         let droplets = &gridview.snapshot().droplets;
         let d0 = droplets.get(&self.inputs[0]).unwrap();
         // we only split in the x right now, so we don't need y padding
-        let x_dim = (d0.dimensions.x as usize) + SPLIT_PADDING;
-        let y_dim = d0.dimensions.y as usize;
+        let x_dim = self.x_dim(d0.dimensions().x);
+        let y_dim = d0.dimensions().y.max(self.dimensions.y) as usize;
         let grid = Grid::rectangle(y_dim, x_dim);
 
         let input_locations = vec![Location { y: 0, x: 2 }];
@@ -322,7 +482,7 @@ impl Command for Split {
         let x_dim = {
             // limit the scope of d0 borrow
             let d0 = gridview.get(&self.inputs[0]);
-            (d0.dimensions.x as usize) + SPLIT_PADDING
+            self.x_dim(d0.dimensions().x)
         };
 
         let inp = self.inputs[0];
@@ -331,13 +491,7 @@ impl Command for Split {
 
         let d = gridview.remove(&inp);
         let vol = d.volume / 2.0;
-
-        // TODO: this should be related to volume in some fashion
-        // currently, take the ceiling of the division of the split by two
-        let dim = Location {
-            y: d.dimensions.y,
-            x: (d.dimensions.x + 1) / 2,
-        };
+        let dim = self.dimensions;
 
         let loc0 = Location { y: 0, x: 1 };
         let loc1 = Location {
@@ -345,8 +499,11 @@ impl Command for Split {
             x: x_dim as i32 - (dim.x + 1),
         };
 
-        gridview.insert(Droplet::new(out0, vol, loc0, dim));
-        gridview.insert(Droplet::new(out1, vol, loc1, dim));
+        for &(out, loc) in &[(out0, loc0), (out1, loc1)] {
+            let mut half = Droplet::new(out, vol, loc, dim);
+            half.reagents = d.reagents.clone();
+            gridview.insert(half);
+        }
 
         gridview.tick();
         gridview.move_west(out0);
@@ -1,27 +1,55 @@
+use std::path::PathBuf;
+use std::sync::mpsc::Sender;
 use std::sync::{Arc, Mutex};
 use std::thread::sleep;
 use std::time::Duration;
 
 use rand::Rng;
-use rand::prelude::thread_rng;
 
-use grid::{DropletInfo, ExecResponse, GridView};
+use grid::{ActuationFrame, DropletInfo, ExecResponse, GridView};
 use util::endpoint::Endpoint;
+use util::seed::rng_from_seed;
 
 pub struct Executor {
     blocking: bool,
     gridview: Arc<Mutex<GridView>>,
+    wear_path: Option<PathBuf>,
+    /// Where to send each frame once it's been checked, for the hardware.
+    frames: Option<Sender<ActuationFrame>>,
+    seed: u64,
 }
 
 impl Executor {
-    pub fn new(blocking: bool, gridview: Arc<Mutex<GridView>>) -> Self {
-        Executor { blocking, gridview }
+    pub fn new(
+        blocking: bool,
+        gridview: Arc<Mutex<GridView>>,
+        wear_path: Option<PathBuf>,
+        frames: Option<Sender<ActuationFrame>>,
+        seed: u64,
+    ) -> Self {
+        Executor {
+            blocking,
+            gridview,
+            wear_path,
+            frames,
+            seed,
+        }
+    }
+
+    fn save_wear(&self, gv: &GridView) {
+        if let Some(ref path) = self.wear_path {
+            match gv.wear.save(path) {
+                Ok(()) => debug!("Saved wear to {:?}", path),
+                Err(err) => error!("Couldn't save wear to {:?}: {}", path, err),
+            }
+        }
     }
 
     pub fn run(&self, endpoint: Endpoint<Vec<DropletInfo>, ()>) {
         let sleep_time = Duration::from_millis(100);
 
-        let mut rng = thread_rng();
+        let mut rng = rng_from_seed(self.seed);
+        let mut unsaved_wear = false;
 
         loop {
             if self.blocking {
@@ -41,7 +69,19 @@ impl Executor {
 
             use self::ExecResponse::*;
             match gv.execute() {
-                Step {} => {
+                Step(frame) => {
+                    trace!("Actuating pins {:?}", frame.pins);
+                    let active = frame.active_locations(&gv.grid);
+                    gv.wear.record(active);
+                    unsaved_wear = true;
+
+                    if let Some(ref frames) = self.frames {
+                        // nobody may be listening anymore, which is fine
+                        if frames.send(frame).is_err() {
+                            debug!("Nobody is taking actuation frames");
+                        }
+                    }
+
                     if self.blocking {
                         endpoint.send(gv.exec_droplet_info(None)).unwrap()
                     }
@@ -55,12 +95,69 @@ impl Executor {
                     }
                 }
                 NotReady => {
+                    // don't bother the disk until we have some down time
+                    if unsaved_wear {
+                        self.save_wear(&gv);
+                        unsaved_wear = false;
+                    }
                     // drop the lock before sleeping
                     ::std::mem::drop(gv);
                     sleep(sleep_time);
                 }
-                Done => return,
+                Done => {
+                    self.save_wear(&gv);
+                    return;
+                }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc::channel;
+    use std::thread;
+
+    use command::Flush;
+    use grid::{Droplet, DropletId, Grid, Location, Orientation};
+    use process::PuddleError;
+    use util::collections::Map;
+
+    #[test]
+    fn test_bad_actuation() {
+        let mut gv = GridView::new(Grid::rectangle(3, 5));
+        let id = DropletId {
+            id: 0,
+            process_id: 0,
+        };
+        let droplet = Droplet::new(id, 1.0, Location { y: 1, x: 0 }, Location { y: 1, x: 1 });
+        gv.snapshot_mut().droplets.insert(id, droplet);
+        gv.subview(vec![], Map::new(), Orientation::default()).tick();
+
+        // no frame can pull a droplet three cells over in one step
+        gv.snapshot_mut().droplets.get_mut(&id).unwrap().location = Location { y: 1, x: 3 };
+        gv.subview(vec![], Map::new(), Orientation::default()).tick();
+        let (tx, rx) = channel();
+        gv.register(Box::new(Flush::new(0, tx)));
+
+        let gv = Arc::new(Mutex::new(gv));
+        let executor = Executor::new(false, Arc::clone(&gv), None, None, 0);
+        let (_endpoint, execs) = Endpoint::pair();
+        let exec_thread = thread::spawn(move || executor.run(execs));
+
+        // the waiting flush hears about it instead of hanging
+        match rx.recv().unwrap() {
+            Err(PuddleError::ActuationError(_)) => (),
+            other => panic!("expected an actuation error, got {:?}", other),
+        }
+
+        // let the executor go around a few more times
+        sleep(Duration::from_millis(300));
+        assert!(!exec_thread.is_finished());
+        let gv = gv.lock().unwrap();
+        assert!(gv.failure().is_some());
+        assert_eq!(gv.exec_droplet_info(None)[0].location, Location { y: 1, x: 3 });
+    }
+}
@@ -0,0 +1,178 @@
+use grid::{CellKind, DropletId, Grid, Location, Snapshot};
+use util::collections::Set;
+
+/// The pins to turn on during one tick.
+///
+/// A droplet is pulled onto the active electrodes that overlap or border it,
+/// so the frame that takes one snapshot to the next just turns on every
+/// electrode under the droplets in the next snapshot.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ActuationFrame {
+    pub pins: Set<u32>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub enum ActuationError {
+    /// The frame pulls a droplet somewhere other than where it was planned to
+    /// go.
+    WrongMove {
+        id: DropletId,
+        expected: Set<Location>,
+        actual: Set<Location>,
+    },
+    /// A droplet that was consumed by a command isn't pulled into any of the
+    /// droplets the command made.
+    Abandoned { id: DropletId },
+}
+
+impl ActuationFrame {
+    /// Builds the frame that moves the droplets in `before` to where they are
+    /// in `after`, and checks that it does exactly that.
+    pub fn between(
+        grid: &Grid,
+        before: &Snapshot,
+        after: &Snapshot,
+    ) -> Result<ActuationFrame, ActuationError> {
+        let pins = after
+            .droplets
+            .values()
+            .flat_map(|d| d.locations())
+            .filter_map(|loc| grid.get_cell(&loc).map(|cell| cell.pin))
+            .collect();
+
+        let frame = ActuationFrame { pins };
+        frame.verify(grid, before, after)?;
+        Ok(frame)
+    }
+
+    /// The locations of all the electrodes this frame turns on. This may be
+    /// more than the droplets asked for if several electrodes share a pin.
+    pub fn active_locations(&self, grid: &Grid) -> Set<Location> {
+        grid.locations()
+            .filter(|(_, cell)| self.pins.contains(&cell.pin))
+            .map(|(loc, _)| loc)
+            .collect()
+    }
+
+    fn verify(&self, grid: &Grid, before: &Snapshot, after: &Snapshot) -> Result<(), ActuationError> {
+        let active = self.active_locations(grid);
+
+        // the cells that newly made droplets cover; consumed droplets have to
+        // be pulled into these
+        let created: Set<Location> = after
+            .droplets
+            .values()
+            .filter(|d| !before.droplets.contains_key(&d.id))
+            .flat_map(|d| d.locations())
+            .collect();
+
+        for (id, droplet) in before.droplets.iter() {
+            let mut reach: Set<Location> = droplet.locations().into_iter().collect();
+            for loc in droplet.locations() {
+                reach.extend(grid.neighbors4(&loc));
+            }
+            let pulled: Set<Location> = reach.intersection(&active).cloned().collect();
+
+            match after.droplets.get(id) {
+                Some(moved) => {
+                    let expected: Set<Location> = moved
+                        .locations()
+                        .into_iter()
+                        .filter(|loc| grid.get_cell(loc).is_some())
+                        .collect();
+                    if pulled != expected {
+                        return Err(ActuationError::WrongMove {
+                            id: *id,
+                            expected,
+                            actual: pulled,
+                        });
+                    }
+                }
+                None => {
+                    // a droplet on a reservoir can be drained into it, like a
+                    // used up wash droplet
+                    let drained = droplet.locations().iter().any(|loc| {
+                        grid.get_cell(loc).map(|cell| cell.kind) == Some(CellKind::Reservoir)
+                    });
+                    if drained {
+                        continue;
+                    }
+                    if pulled.is_empty() || !pulled.is_subset(&created) {
+                        return Err(ActuationError::Abandoned { id: *id });
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use grid::Droplet;
+
+    fn snapshot(droplets: &[(usize, Location, Location)]) -> Snapshot {
+        let mut snapshot = Snapshot::default();
+        for &(id, location, dimensions) in droplets {
+            let id = DropletId { id, process_id: 0 };
+            let droplet = Droplet::new(id, 1.0, location, dimensions);
+            snapshot.droplets.insert(id, droplet);
+        }
+        snapshot
+    }
+
+    fn loc(y: i32, x: i32) -> Location {
+        Location { y, x }
+    }
+
+    #[test]
+    fn test_frame_moves_droplets() {
+        let grid = Grid::rectangle(4, 4);
+        let before = snapshot(&[(0, loc(0, 0), loc(1, 1)), (1, loc(2, 2), loc(2, 2))]);
+        let after = snapshot(&[(0, loc(0, 1), loc(1, 1)), (1, loc(2, 2), loc(2, 2))]);
+
+        let frame = ActuationFrame::between(&grid, &before, &after).unwrap();
+        let active = frame.active_locations(&grid);
+
+        let expected: Set<_> = vec![loc(0, 1), loc(2, 2), loc(2, 3), loc(3, 2), loc(3, 3)]
+            .into_iter()
+            .collect();
+        assert_eq!(active, expected);
+    }
+
+    #[test]
+    fn test_frame_merges_droplets() {
+        let grid = Grid::rectangle(3, 3);
+        let before = snapshot(&[(0, loc(0, 0), loc(1, 1)), (1, loc(0, 2), loc(1, 1))]);
+        let after = snapshot(&[(2, loc(0, 0), loc(1, 2))]);
+
+        assert!(ActuationFrame::between(&grid, &before, &after).is_ok());
+    }
+
+    #[test]
+    fn test_frame_catches_bad_moves() {
+        let grid = Grid::rectangle(3, 3);
+
+        // jumping two cells can't be done in one tick
+        let before = snapshot(&[(0, loc(0, 0), loc(1, 1))]);
+        let after = snapshot(&[(0, loc(0, 2), loc(1, 1))]);
+        match ActuationFrame::between(&grid, &before, &after) {
+            Err(ActuationError::WrongMove { .. }) => (),
+            r => panic!("expected WrongMove, got {:?}", r),
+        }
+
+        // a droplet that just disappears isn't pulled anywhere
+        let after = snapshot(&[]);
+        match ActuationFrame::between(&grid, &before, &after) {
+            Err(ActuationError::Abandoned { .. }) => (),
+            r => panic!("expected Abandoned, got {:?}", r),
+        }
+
+        // unless it's drained into a reservoir
+        let mut grid = grid;
+        grid.vec[0][0].as_mut().unwrap().kind = CellKind::Reservoir;
+        assert!(ActuationFrame::between(&grid, &before, &after).is_ok());
+    }
+}
@@ -0,0 +1,219 @@
+use grid::{Grid, Location};
+use util::collections::{Map, Set};
+
+/// The places on a board where droplets of some size get in each other's way.
+///
+/// Positions are where the top-left corner of such a droplet can sit while
+/// the whole droplet stays on the board.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bottlenecks {
+    pub dimensions: Location,
+    /// Positions that a droplet has to pass through to get from one part of
+    /// the board to another. Anything parked here cuts the board in two.
+    pub articulations: Set<Location>,
+    /// Positions in passages only one droplet wide, where a droplet can only
+    /// go straight ahead or back.
+    pub corridors: Set<Location>,
+}
+
+impl Bottlenecks {
+    pub fn is_empty(&self) -> bool {
+        self.articulations.is_empty() && self.corridors.is_empty()
+    }
+
+    /// The cells covered by a droplet in any of the bottleneck positions.
+    pub fn cells(&self) -> Set<Location> {
+        let mut cells = self.covered(&self.articulations);
+        cells.extend(self.covered(&self.corridors));
+        cells
+    }
+
+    /// The cells covered by a droplet in any of `positions`.
+    pub fn covered(&self, positions: &Set<Location>) -> Set<Location> {
+        let dim = self.dimensions;
+        positions
+            .iter()
+            .flat_map(|pos| {
+                (0..dim.y).flat_map(move |i| {
+                    (0..dim.x).map(move |j| pos + &Location { y: i, x: j })
+                })
+            })
+            .collect()
+    }
+}
+
+impl Grid {
+    /// Finds the bottlenecks for droplets of the given dimensions.
+    pub fn bottlenecks(&self, dimensions: &Location) -> Bottlenecks {
+        let fits = |pos: &Location| {
+            (0..dimensions.y).all(|i| {
+                (0..dimensions.x).all(|j| self.get_cell(&(pos + &Location { y: i, x: j })).is_some())
+            })
+        };
+        let positions: Vec<Location> = self
+            .locations()
+            .map(|(loc, _)| loc)
+            .filter(|loc| fits(loc))
+            .collect();
+        let index: Map<Location, usize> = positions
+            .iter()
+            .enumerate()
+            .map(|(i, loc)| (*loc, i))
+            .collect();
+        let adjacent: Vec<Vec<usize>> = positions
+            .iter()
+            .map(|pos| {
+                self.neighbors4(pos)
+                    .iter()
+                    .filter_map(|n| index.get(n).cloned())
+                    .collect()
+            })
+            .collect();
+
+        let articulations = articulation_points(&adjacent)
+            .into_iter()
+            .map(|i| positions[i])
+            .collect();
+
+        // a corridor position only connects to the positions straight ahead
+        // and straight behind it
+        let corridors = positions
+            .iter()
+            .zip(&adjacent)
+            .filter(|&(_, adj)| {
+                adj.len() == 2 && {
+                    let (a, b) = (positions[adj[0]], positions[adj[1]]);
+                    a.y == b.y || a.x == b.x
+                }
+            })
+            .map(|(pos, _)| *pos)
+            .collect();
+
+        Bottlenecks {
+            dimensions: *dimensions,
+            articulations,
+            corridors,
+        }
+    }
+}
+
+/// Tarjan's articulation point algorithm, done with an explicit stack so big
+/// boards don't blow the real one.
+fn articulation_points(adjacent: &[Vec<usize>]) -> Vec<usize> {
+    const UNSEEN: usize = usize::MAX;
+
+    let n = adjacent.len();
+    let mut discovered = vec![UNSEEN; n];
+    let mut low = vec![0; n];
+    let mut parent = vec![UNSEEN; n];
+    let mut is_articulation = vec![false; n];
+    let mut time = 0;
+
+    for root in 0..n {
+        if discovered[root] != UNSEEN {
+            continue;
+        }
+        discovered[root] = time;
+        low[root] = time;
+        time += 1;
+
+        let mut root_children = 0;
+        // each entry is a node and the next neighbor of it to look at
+        let mut stack = vec![(root, 0)];
+
+        while let Some(&(u, next)) = stack.last() {
+            if next < adjacent[u].len() {
+                stack.last_mut().unwrap().1 += 1;
+                let v = adjacent[u][next];
+                if discovered[v] == UNSEEN {
+                    parent[v] = u;
+                    discovered[v] = time;
+                    low[v] = time;
+                    time += 1;
+                    if u == root {
+                        root_children += 1;
+                    }
+                    stack.push((v, 0));
+                } else if v != parent[u] {
+                    low[u] = low[u].min(discovered[v]);
+                }
+            } else {
+                stack.pop();
+                if let Some(&(p, _)) = stack.last() {
+                    low[p] = low[p].min(low[u]);
+                    if p != root && low[u] >= discovered[p] {
+                        is_articulation[p] = true;
+                    }
+                }
+            }
+        }
+
+        if root_children > 1 {
+            is_articulation[root] = true;
+        }
+    }
+
+    (0..n).filter(|&i| is_articulation[i]).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn loc(y: i32, x: i32) -> Location {
+        Location { y, x }
+    }
+
+    #[test]
+    fn test_open_board() {
+        let grid = Grid::rectangle(4, 5);
+        assert!(grid.bottlenecks(&loc(1, 1)).is_empty());
+        assert!(grid.bottlenecks(&loc(2, 2)).is_empty());
+    }
+
+    #[test]
+    fn test_dumbbell() {
+        // two rooms joined by a passage one cell wide
+        let grid = Grid::from_ascii(
+            &[
+                "...   ...",
+                ".........",
+                "...   ...",
+            ].join("\n"),
+        ).unwrap();
+
+        // small droplets have to go through the passage
+        let small = grid.bottlenecks(&loc(1, 1));
+        let expected: Set<_> = (2..7).map(|x| loc(1, x)).collect();
+        assert_eq!(small.articulations, expected);
+        let expected: Set<_> = (3..6).map(|x| loc(1, x)).collect();
+        assert_eq!(small.corridors, expected);
+
+        // 2x2 droplets can't get through at all, so each room is its own
+        // little open board
+        let big = grid.bottlenecks(&loc(2, 2));
+        assert!(big.articulations.is_empty());
+        assert!(big.corridors.is_empty());
+    }
+
+    #[test]
+    fn test_wide_passage() {
+        // the passage is wide enough for 1x1 droplets to pass each other,
+        // but 2x2 droplets have to go single file
+        let grid = Grid::from_ascii(
+            &[
+                "....    ....",
+                "............",
+                "............",
+                "....    ....",
+            ].join("\n"),
+        ).unwrap();
+
+        assert!(grid.bottlenecks(&loc(1, 1)).articulations.is_empty());
+
+        let big = grid.bottlenecks(&loc(2, 2));
+        let expected: Set<_> = (2..9).map(|x| loc(1, x)).collect();
+        assert_eq!(big.articulations, expected);
+        assert!(big.cells().contains(&loc(2, 5)));
+    }
+}
@@ -1,8 +1,9 @@
 use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering::Relaxed;
 
-use super::Location;
+use super::{Footprint, Location, Reagent};
 use process::ProcessId;
+use util::collections::Set;
 
 static NEXT_COLLISION_GROUP: AtomicUsize = AtomicUsize::new(0);
 
@@ -18,11 +19,17 @@ pub struct Droplet {
     // are globally unique by construction.
     pub id: DropletId,
     pub location: Location,
-    pub dimensions: Location,
+    pub footprint: Footprint,
     pub volume: f64,
     // TODO should droplets really know about their destinations?
     pub destination: Option<Location>,
     pub collision_group: usize,
+    /// What the droplet is made of, if the client said. Mixing droplets
+    /// mixes their reagents.
+    pub reagents: Set<Reagent>,
+    /// The dead cell the droplet is stuck on, if it was sitting on one when
+    /// it was marked faulty. It won't move again.
+    pub stranded: Option<Location>,
 }
 
 #[derive(Debug, Serialize)]
@@ -30,7 +37,18 @@ pub struct DropletInfo {
     pub id: DropletId,
     pub location: Location,
     pub volume: f64,
+    /// The dimensions of the droplet's bounding box.
     pub dimensions: Location,
+    /// The cells the droplet covers relative to its location, only given when
+    /// it isn't a rectangle.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cells: Option<Vec<Location>>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub reagents: Vec<Reagent>,
+    /// The dead cell the droplet is stuck on, if any; it can't be used
+    /// anymore.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stranded: Option<Location>,
 }
 
 impl Droplet {
@@ -39,37 +57,100 @@ impl Droplet {
         if dimensions.y <= 0 || dimensions.x <= 0 {
             panic!("Dimensions for a droplet must be positive integers")
         }
+        Droplet::with_footprint(id, volume, location, Footprint::Rect(dimensions))
+    }
+
+    /// Creates a new Droplet covering `footprint` offset by `location`.
+    pub fn with_footprint(
+        id: DropletId,
+        volume: f64,
+        location: Location,
+        footprint: Footprint,
+    ) -> Droplet {
+        if footprint.cell_count() == 0 {
+            panic!("A droplet must cover at least one cell")
+        }
         Droplet {
             id,
             location,
-            dimensions,
+            footprint,
             destination: None,
             volume: volume,
             collision_group: NEXT_COLLISION_GROUP.fetch_add(1, Relaxed),
+            reagents: Set::new(),
+            stranded: None,
         }
     }
 
+    /// The dimensions of the droplet's bounding box.
+    pub fn dimensions(&self) -> Location {
+        self.footprint.dimensions()
+    }
+
     fn corners(&self) -> [Location; 4] {
+        let dimensions = self.dimensions();
         [
             self.location,
             // subtract one, because the unit square is account for by
             // min_distance_to_box
             &self.location + &Location {
-                y: self.dimensions.y - 1,
+                y: dimensions.y - 1,
                 x: 0,
             },
             &self.location + &Location {
                 y: 0,
-                x: self.dimensions.x - 1,
+                x: dimensions.x - 1,
             },
             &self.location + &Location {
-                y: self.dimensions.y - 1,
-                x: self.dimensions.x - 1,
+                y: dimensions.y - 1,
+                x: dimensions.x - 1,
             },
         ]
     }
 
+    /// Returns all the locations that the droplet covers.
+    pub fn locations(&self) -> Vec<Location> {
+        self.footprint
+            .offsets()
+            .iter()
+            .map(|off| &self.location + off)
+            .collect()
+    }
+
+    /// Tests if the droplet is sitting on the given location.
+    pub fn covers(&self, loc: &Location) -> bool {
+        self.footprint.contains(&(loc - &self.location))
+    }
+
+    /// How far `loc` is from the droplet, in the same sense as
+    /// `collision_distance`: negative if the droplet covers it, 0 if they
+    /// touch, even diagonally.
+    pub fn distance_to(&self, loc: &Location) -> i32 {
+        self.footprint
+            .boxes(&self.location)
+            .into_iter()
+            .map(|(corner1, corner2)| loc.min_distance_to_box(corner1, corner2))
+            .min()
+            .unwrap()
+    }
+
     pub fn collision_distance(&self, other: &Droplet) -> i32 {
+        if !self.footprint.is_rect() || !other.footprint.is_rect() {
+            // check cell by cell, against whichever footprint is a rectangle
+            // if either is
+            let (cells, boxes) = if other.footprint.is_rect() {
+                (self, other)
+            } else {
+                (other, self)
+            };
+            return cells
+                .locations()
+                .iter()
+                .map(|loc| boxes.distance_to(loc))
+                .min()
+                .unwrap();
+        }
+
         let my_corners = self.corners();
         let their_corners = other.corners();
 
@@ -96,15 +177,21 @@ impl Droplet {
         DropletInfo {
             id: self.id,
             location: self.location,
-            dimensions: self.dimensions,
+            dimensions: self.dimensions(),
             volume: self.volume,
+            cells: match self.footprint {
+                Footprint::Rect(_) => None,
+                Footprint::Cells(_) => Some(self.footprint.offsets()),
+            },
+            reagents: self.reagents.iter().cloned().collect(),
+            stranded: self.stranded,
         }
     }
 }
 
 #[cfg(test)]
 pub mod tests {
-    use super::{Droplet, DropletId, Location};
+    use super::{Droplet, DropletId, Footprint, Location};
 
     #[test]
     #[should_panic]
@@ -119,4 +206,30 @@ pub mod tests {
             Location { y: 0, x: 0 },
         );
     }
+
+    #[test]
+    fn test_footprint_collision() {
+        let id = |id| DropletId { id, process_id: 0 };
+        let loc = |y, x| Location { y, x };
+
+        // an L with its corner cut out at (0, 1)
+        let cells = vec![loc(0, 0), loc(1, 0), loc(1, 1)];
+        let (corner, l) = Footprint::from_locations(cells).unwrap();
+        let l = Droplet::with_footprint(id(0), 1.0, corner, l);
+        assert!(l.covers(&loc(1, 1)));
+        assert!(!l.covers(&loc(0, 1)));
+
+        // the bounding boxes would overlap, but they're still touching
+        let rect = Droplet::new(id(1), 1.0, loc(0, 1), loc(1, 2));
+        assert_eq!(l.collision_distance(&rect), 0);
+        assert_eq!(rect.collision_distance(&l), 0);
+
+        // the cut out corner leaves room diagonally
+        let far = Droplet::new(id(2), 1.0, loc(0, 3), loc(1, 1));
+        assert_eq!(l.collision_distance(&far), 1);
+        assert_eq!(far.collision_distance(&l), 1);
+
+        // and a rectangle against a rectangle still works the same way
+        assert_eq!(rect.collision_distance(&far), 0);
+    }
 }
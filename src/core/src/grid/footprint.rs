@@ -0,0 +1,162 @@
+use grid::Location;
+use util::collections::Set;
+
+/// The cells a droplet covers, as offsets from its location.
+///
+/// Most droplets are rectangles, which are cheap to check against each other,
+/// so those are kept as just their dimensions. Anything else is kept as the
+/// set of offsets it covers, with the top-left of its bounding box at the
+/// origin.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Footprint {
+    Rect(Location),
+    Cells(Set<Location>),
+}
+
+impl Footprint {
+    /// Builds the footprint covering exactly `locs`, along with where its
+    /// bounding box starts. Returns `None` if `locs` is empty.
+    pub fn from_locations<I>(locs: I) -> Option<(Location, Footprint)>
+    where
+        I: IntoIterator<Item = Location>,
+    {
+        let locs: Set<Location> = locs.into_iter().collect();
+        let corner = Location {
+            y: locs.iter().map(|l| l.y).min()?,
+            x: locs.iter().map(|l| l.x).min()?,
+        };
+        let offsets: Set<Location> = locs.iter().map(|l| l - &corner).collect();
+        let dimensions = Location {
+            y: offsets.iter().map(|l| l.y).max()? + 1,
+            x: offsets.iter().map(|l| l.x).max()? + 1,
+        };
+
+        // keep the fast representation if it fills its bounding box
+        let footprint = if (dimensions.y * dimensions.x) as usize == offsets.len() {
+            Footprint::Rect(dimensions)
+        } else {
+            Footprint::Cells(offsets)
+        };
+        Some((corner, footprint))
+    }
+
+    /// The dimensions of the bounding box.
+    pub fn dimensions(&self) -> Location {
+        match self {
+            Footprint::Rect(dim) => *dim,
+            Footprint::Cells(offsets) => Location {
+                y: offsets.iter().map(|l| l.y).max().unwrap_or(-1) + 1,
+                x: offsets.iter().map(|l| l.x).max().unwrap_or(-1) + 1,
+            },
+        }
+    }
+
+    pub fn is_rect(&self) -> bool {
+        match self {
+            Footprint::Rect(_) => true,
+            Footprint::Cells(_) => false,
+        }
+    }
+
+    pub fn offsets(&self) -> Vec<Location> {
+        match self {
+            Footprint::Rect(dim) => {
+                let mut offsets = Vec::new();
+                for y in 0..dim.y {
+                    for x in 0..dim.x {
+                        offsets.push(Location { y, x });
+                    }
+                }
+                offsets
+            }
+            Footprint::Cells(offsets) => offsets.iter().cloned().collect(),
+        }
+    }
+
+    pub fn cell_count(&self) -> usize {
+        match self {
+            Footprint::Rect(dim) => (dim.y * dim.x) as usize,
+            Footprint::Cells(offsets) => offsets.len(),
+        }
+    }
+
+    pub fn contains(&self, offset: &Location) -> bool {
+        match self {
+            Footprint::Rect(dim) => {
+                0 <= offset.y && offset.y < dim.y && 0 <= offset.x && offset.x < dim.x
+            }
+            Footprint::Cells(offsets) => offsets.contains(offset),
+        }
+    }
+
+    /// The footprint covering both this one and `other` shifted by `offset`.
+    /// The offset can't be negative, so this stays anchored at the origin.
+    pub fn union(&self, other: &Footprint, offset: &Location) -> Footprint {
+        assert!(offset.y >= 0 && offset.x >= 0);
+        let locs = self.offsets()
+            .into_iter()
+            .chain(other.offsets().into_iter().map(|l| &l + offset));
+        let (corner, footprint) = Footprint::from_locations(locs).unwrap();
+        assert_eq!(corner, Location { y: 0, x: 0 });
+        footprint
+    }
+
+    /// The boxes that make up this footprint placed at `location`, as pairs of
+    /// corners like `Location::min_distance_to_box` takes.
+    pub fn boxes(&self, location: &Location) -> Vec<(Location, Location)> {
+        let unit = Location { y: 1, x: 1 };
+        match self {
+            Footprint::Rect(dim) => vec![(*location, location + dim)],
+            Footprint::Cells(offsets) => offsets
+                .iter()
+                .map(|off| {
+                    let loc = location + off;
+                    (loc, &loc + &unit)
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn loc(y: i32, x: i32) -> Location {
+        Location { y, x }
+    }
+
+    #[test]
+    fn test_from_locations() {
+        let (corner, fp) = Footprint::from_locations(vec![loc(2, 3), loc(2, 4)]).unwrap();
+        assert_eq!(corner, loc(2, 3));
+        assert_eq!(fp, Footprint::Rect(loc(1, 2)));
+
+        // an L
+        let (corner, fp) =
+            Footprint::from_locations(vec![loc(1, 1), loc(2, 1), loc(2, 2)]).unwrap();
+        assert_eq!(corner, loc(1, 1));
+        assert!(!fp.is_rect());
+        assert_eq!(fp.dimensions(), loc(2, 2));
+        assert_eq!(fp.cell_count(), 3);
+        assert!(fp.contains(&loc(1, 1)));
+        assert!(!fp.contains(&loc(0, 1)));
+
+        assert_eq!(Footprint::from_locations(vec![]), None);
+    }
+
+    #[test]
+    fn test_union() {
+        // a tall droplet and a short one side by side make an L
+        let tall = Footprint::Rect(loc(2, 1));
+        let short = Footprint::Rect(loc(1, 2));
+        let l = tall.union(&short, &loc(0, 1));
+        assert_eq!(l.dimensions(), loc(2, 3));
+        assert_eq!(l.cell_count(), 4);
+        assert!(!l.contains(&loc(1, 2)));
+
+        // two that line up stay a rectangle
+        let rect = short.union(&short, &loc(0, 2));
+        assert_eq!(rect, Footprint::Rect(loc(1, 4)));
+    }
+}
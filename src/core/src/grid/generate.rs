@@ -0,0 +1,309 @@
+use std::error::Error;
+use std::fmt;
+
+use rand::Rng;
+
+use grid::{Cell, CellKind, Grid, Location};
+use util::seed::rng_from_seed;
+
+/// Describes a board for `Grid::generate` to build.
+#[derive(Debug, Clone)]
+pub struct GenParams {
+    pub height: usize,
+    pub width: usize,
+    /// Offset every other row by half a cell, like `arch01.json`. The odd
+    /// rows lose their first and last electrodes.
+    pub staggered: bool,
+    /// How many reservoirs to spread along the left and right edges.
+    pub reservoirs: usize,
+    /// How many square heater zones to scatter over the board.
+    pub heaters: usize,
+    /// How many square sensor zones to scatter over the board.
+    pub sensors: usize,
+    /// The side length of the heater and sensor zones.
+    pub zone_size: usize,
+    /// The fraction of plain electrodes to try to knock out. Holes that
+    /// would disconnect the board are skipped, so there may be fewer.
+    pub hole_fraction: f64,
+    pub seed: u64,
+}
+
+impl Default for GenParams {
+    fn default() -> GenParams {
+        GenParams {
+            height: 7,
+            width: 9,
+            staggered: false,
+            reservoirs: 0,
+            heaters: 0,
+            sensors: 0,
+            zone_size: 2,
+            hole_fraction: 0.0,
+            seed: 0,
+        }
+    }
+}
+
+/// Why `Grid::generate` couldn't build a board.
+#[derive(Debug, PartialEq)]
+pub enum GenError {
+    /// A staggered board this narrow falls apart.
+    TooNarrow { width: usize },
+    /// There aren't enough rows for every reservoir to get a cell of its own
+    /// on the left or right edge.
+    TooManyReservoirs { reservoirs: usize, height: usize },
+}
+
+impl fmt::Display for GenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::GenError::*;
+        match self {
+            TooNarrow { width } => {
+                write!(f, "staggered boards must be at least 3 wide, not {}", width)
+            }
+            TooManyReservoirs { reservoirs, height } => write!(
+                f,
+                "{} reservoirs don't fit along the edges of a board {} tall",
+                reservoirs, height
+            ),
+        }
+    }
+}
+
+impl Error for GenError {}
+
+impl Grid {
+    /// Builds a board from `params`. The same parameters always build the
+    /// same board, and the board is always connected.
+    ///
+    /// Returns an error if the parameters can't make such a board, like a
+    /// staggered board less than 3 cells wide, or more reservoirs than the
+    /// edges have room for.
+    pub fn generate(params: &GenParams) -> Result<Grid, GenError> {
+        let mut rng = rng_from_seed(params.seed);
+        let (height, width) = (params.height, params.width);
+        if params.staggered && width < 3 {
+            return Err(GenError::TooNarrow { width });
+        }
+
+        let staggered_out = |loc: &Location| {
+            params.staggered && loc.y % 2 == 1 && (loc.x == 0 || loc.x as usize == width - 1)
+        };
+        let electrode = |loc: Location| {
+            if staggered_out(&loc) {
+                None
+            } else {
+                Some(Cell {
+                    pin: 0,
+                    kind: CellKind::Electrode,
+                })
+            }
+        };
+        let mut grid = Grid::from_function(electrode, height, width);
+        assert!(
+            grid.is_connected(),
+            "a {}x{} board isn't connected to begin with",
+            height,
+            width
+        );
+
+        if grid.add_reservoirs(params.reservoirs) < params.reservoirs {
+            return Err(GenError::TooManyReservoirs {
+                reservoirs: params.reservoirs,
+                height,
+            });
+        }
+
+        let zones = (0..params.heaters)
+            .map(|_| CellKind::Heater)
+            .chain((0..params.sensors).map(|_| CellKind::Sensor));
+        for kind in zones {
+            let size = params.zone_size;
+            let corner = Location {
+                y: rng.gen_range(0, height.saturating_sub(size) + 1) as i32,
+                x: rng.gen_range(0, width.saturating_sub(size) + 1) as i32,
+            };
+            grid.mark_zone(corner, size, kind);
+        }
+
+        // only plain electrodes are up for removal
+        let mut candidates: Vec<Location> = grid
+            .locations()
+            .filter(|(_, cell)| cell.kind == CellKind::Electrode)
+            .map(|(loc, _)| loc)
+            .collect();
+        let n_holes = (params.hole_fraction * candidates.len() as f64).round() as usize;
+        rng.shuffle(&mut candidates);
+
+        let mut holes = 0;
+        for loc in candidates {
+            if holes >= n_holes {
+                break;
+            }
+            let cell = grid.remove(&loc);
+            if grid.is_connected() {
+                holes += 1;
+            } else {
+                grid.vec[loc.y as usize][loc.x as usize] = cell;
+            }
+        }
+
+        // number the pins in row-major order, just like the arch files
+        let mut next_pin = 0;
+        for cell in grid.vec.iter_mut().flat_map(|row| row.iter_mut()) {
+            if let Some(cell) = cell.as_mut() {
+                cell.pin = next_pin;
+                next_pin += 1;
+            }
+        }
+
+        Ok(grid)
+    }
+
+    /// Turns the outermost cells of evenly spaced rows into reservoirs,
+    /// alternating between the left and right edges. Returns how many there
+    /// are, which is fewer than `n` if some had to share a cell.
+    fn add_reservoirs(&mut self, n: usize) -> usize {
+        let n_left = n - n / 2;
+        let n_right = n / 2;
+        let height = self.max_height();
+
+        let spaced_rows = |count: usize| (1..count + 1).map(move |k| k * height / (count + 1));
+
+        for i in spaced_rows(n_left) {
+            if let Some(cell) = self.vec[i].iter_mut().flat_map(|c| c.as_mut()).next() {
+                cell.kind = CellKind::Reservoir;
+            }
+        }
+        for i in spaced_rows(n_right) {
+            if let Some(cell) = self.vec[i].iter_mut().rev().flat_map(|c| c.as_mut()).next() {
+                cell.kind = CellKind::Reservoir;
+            }
+        }
+
+        self.locations()
+            .filter(|(_, cell)| cell.kind == CellKind::Reservoir)
+            .count()
+    }
+
+    /// Marks the plain electrodes in a square zone as `kind`.
+    fn mark_zone(&mut self, corner: Location, size: usize, kind: CellKind) {
+        for i in 0..size {
+            for j in 0..size {
+                let y = corner.y as usize + i;
+                let x = corner.x as usize + j;
+                let cell = self.vec.get_mut(y).and_then(|row| row.get_mut(x));
+                if let Some(&mut Some(ref mut cell)) = cell {
+                    if cell.kind == CellKind::Electrode {
+                        cell.kind = kind;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json as sj;
+    use std::fs::File;
+
+    fn count(grid: &Grid, kind: CellKind) -> usize {
+        grid.locations().filter(|(_, c)| c.kind == kind).count()
+    }
+
+    #[test]
+    fn test_generate_staggered() {
+        let params = GenParams {
+            staggered: true,
+            ..GenParams::default()
+        };
+        let grid = Grid::generate(&params).unwrap();
+
+        let reader = File::open("../../tests/arches/arch01.json").unwrap();
+        let arch01: Grid = sj::from_reader(reader).unwrap();
+
+        let locs = |g: &Grid| g.locations().collect::<Vec<_>>();
+        assert_eq!(locs(&grid), locs(&arch01));
+    }
+
+    #[test]
+    fn test_generate_features() {
+        let params = GenParams {
+            height: 12,
+            width: 15,
+            reservoirs: 5,
+            heaters: 2,
+            sensors: 1,
+            zone_size: 3,
+            hole_fraction: 0.3,
+            seed: 42,
+            ..GenParams::default()
+        };
+        let grid = Grid::generate(&params).unwrap();
+
+        assert!(grid.is_connected());
+        assert_eq!(count(&grid, CellKind::Reservoir), 5);
+        assert!(count(&grid, CellKind::Heater) > 0);
+        assert!(count(&grid, CellKind::Sensor) > 0);
+        assert!(grid.locations().count() < 12 * 15);
+
+        // the same seed makes the same board, a different one doesn't
+        assert_eq!(grid, Grid::generate(&params).unwrap());
+        let other = GenParams { seed: 43, ..params };
+        assert_ne!(grid, Grid::generate(&other).unwrap());
+    }
+
+    #[test]
+    fn test_generate_errors() {
+        let params = GenParams {
+            height: 3,
+            reservoirs: 6,
+            ..GenParams::default()
+        };
+        let grid = Grid::generate(&params).unwrap();
+        assert_eq!(count(&grid, CellKind::Reservoir), 6);
+
+        // a seventh would have to share a cell with one of the others
+        let params = GenParams {
+            reservoirs: 7,
+            ..params
+        };
+        assert_eq!(
+            Grid::generate(&params),
+            Err(GenError::TooManyReservoirs {
+                reservoirs: 7,
+                height: 3
+            })
+        );
+
+        let params = GenParams {
+            width: 2,
+            staggered: true,
+            ..GenParams::default()
+        };
+        assert_eq!(Grid::generate(&params), Err(GenError::TooNarrow { width: 2 }));
+    }
+
+    #[test]
+    fn test_generate_stays_connected() {
+        for seed in 0..10 {
+            let params = GenParams {
+                height: 6,
+                width: 6,
+                staggered: true,
+                hole_fraction: 1.0,
+                seed,
+                ..GenParams::default()
+            };
+            let grid = Grid::generate(&params).unwrap();
+            assert!(grid.is_connected());
+
+            // round trips through the arch format, kinds and all
+            let s = sj::to_string(&grid).unwrap();
+            assert_eq!(grid, sj::from_str(&s).unwrap());
+        }
+    }
+}
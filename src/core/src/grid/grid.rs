@@ -1,13 +1,35 @@
+use serde::de::Error as DeError;
 use serde_json;
 use std::collections::HashSet;
 use std::io::Read;
 
-use super::{Location, Snapshot};
+use super::location::connected_components;
+use super::parse::{self, ParseError};
+use super::{Footprint, Location, Snapshot};
 use util::collections::Map;
 
 #[derive(Debug, PartialEq, Eq, Hash, Serialize, Deserialize, Clone, Copy)]
 pub struct Cell {
     pub pin: u32,
+    #[serde(default)]
+    pub kind: CellKind,
+}
+
+/// What sits under an electrode besides the electrode itself.
+#[derive(Debug, PartialEq, Eq, Hash, Serialize, Deserialize, Clone, Copy)]
+pub enum CellKind {
+    Electrode,
+    Heater,
+    Sensor,
+    /// Where droplets are dispensed from or discarded to, usually at the
+    /// edge of the board.
+    Reservoir,
+}
+
+impl Default for CellKind {
+    fn default() -> CellKind {
+        CellKind::Electrode
+    }
 }
 
 impl Cell {
@@ -17,11 +39,23 @@ impl Cell {
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct Grid {
     #[serde(rename = "board")]
     #[serde(with = "super::parse")]
     pub vec: Vec<Vec<Option<Cell>>>,
+    /// Distance between the centers of neighboring electrodes, in mm.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pitch: Option<f64>,
+    /// Distance between the electrodes and the top plate, in mm.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gap_height: Option<f64>,
+    /// The smallest volume a droplet can have and still be moved reliably.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_volume: Option<f64>,
+    /// The largest volume a droplet can have before it's too unwieldy.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_volume: Option<f64>,
 }
 
 #[cfg_attr(rustfmt, rustfmt_skip)]
@@ -50,7 +84,10 @@ impl Grid {
     pub fn rectangle(h: usize, w: usize) -> Self {
         let mut pin = 0;
         let always_cell = |_| {
-            let cell = Some(Cell { pin: pin });
+            let cell = Some(Cell {
+                pin: pin,
+                kind: CellKind::Electrode,
+            });
             pin += 1;
             cell
         };
@@ -65,8 +102,23 @@ impl Grid {
         self.vec.iter().map(|row| row.len()).max().unwrap_or(0)
     }
 
+    /// Reads a board from JSON, rejecting volume limits that leave no
+    /// volume in between.
     pub fn from_reader<R: Read>(reader: R) -> Result<Grid, serde_json::Error> {
-        serde_json::from_reader(reader)
+        let grid: Grid = serde_json::from_reader(reader)?;
+        if let (Some(min), Some(max)) = (grid.min_volume, grid.max_volume) {
+            if min > max {
+                let msg = format!("min_volume {} is more than max_volume {}", min, max);
+                return Err(serde_json::Error::custom(msg));
+            }
+        }
+        Ok(grid)
+    }
+
+    /// Parses a grid drawn in ASCII. Any droplets drawn on the board are
+    /// treated as plain electrodes; see `InitialState` to keep them.
+    pub fn from_ascii(s: &str) -> Result<Grid, ParseError> {
+        parse::parse_ascii(s).map(|state| state.grid)
     }
 
     pub fn locations<'a>(&'a self) -> Box<Iterator<Item = (Location, Cell)> + 'a> {
@@ -88,15 +140,20 @@ impl Grid {
 
     /// Tests if this grid is compatible within `bigger` when `offset` is applied
     /// to `self`
-    fn is_compatible_within(&self, offset: Location, bigger: &Self, snapshot: &Snapshot) -> bool {
+    pub fn is_compatible_within(
+        &self,
+        offset: Location,
+        bigger: &Self,
+        snapshot: &Snapshot,
+    ) -> bool {
         self.locations().all(|(loc, my_cell)| {
             let their_loc = &loc + &offset;
             bigger.get_cell(&their_loc).map_or(false, |theirs| {
-                my_cell.is_compatible(&theirs) && !snapshot.droplets.values().any(|droplet| {
-                    let corner1 = droplet.location;
-                    let corner2 = &droplet.location + &droplet.dimensions;
-                    their_loc.min_distance_to_box(corner1, corner2) <= 0
-                })
+                my_cell.is_compatible(&theirs)
+                    && !snapshot
+                        .droplets
+                        .values()
+                        .any(|droplet| droplet.distance_to(&their_loc) <= 0)
             })
         })
     }
@@ -135,10 +192,8 @@ impl Grid {
         // verify the mapping by checking that each space is far enough away from the droplets
         result.as_ref().map(|mapping| {
             for droplet in snapshot.droplets.values() {
-                let corner1 = droplet.location;
-                let corner2 = &droplet.location + &droplet.dimensions;
                 for loc in mapping.values() {
-                    assert!(loc.min_distance_to_box(corner1, corner2) > 0);
+                    assert!(droplet.distance_to(loc) > 0);
                 }
             }
         });
@@ -163,9 +218,57 @@ impl Grid {
                         .collect()
                 })
                 .collect(),
+            pitch: None,
+            gap_height: None,
+            min_volume: None,
+            max_volume: None,
         }
     }
 
+    /// The volume of liquid (in µL) that fits over a single electrode, if the
+    /// board's physical parameters are known.
+    pub fn electrode_volume(&self) -> Option<f64> {
+        match (self.pitch, self.gap_height) {
+            (Some(pitch), Some(gap)) => Some(pitch * pitch * gap),
+            _ => None,
+        }
+    }
+
+    /// The most volume a droplet with the given dimensions can hold.
+    pub fn capacity(&self, dimensions: &Location) -> Option<f64> {
+        let cells = (dimensions.y * dimensions.x) as f64;
+        self.electrode_volume().map(|vol| cells * vol)
+    }
+
+    /// Whether a droplet of this volume is within the board's limits. Like
+    /// `footprint`, this leaves a little slack for floating point error.
+    pub fn volume_in_bounds(&self, volume: f64) -> bool {
+        let above_min = match self.min_volume {
+            Some(min) => volume >= min - 1e-9,
+            None => true,
+        };
+        let below_max = match self.max_volume {
+            Some(max) => volume <= max + 1e-9,
+            None => true,
+        };
+        above_min && below_max
+    }
+
+    /// The squarest footprint that holds `volume`, if the board's physical
+    /// parameters are known. Wider footprints are preferred to taller ones.
+    pub fn footprint(&self, volume: f64) -> Option<Location> {
+        self.electrode_volume().map(|vol| {
+            // a little slack so floating point error doesn't take an extra cell
+            let cells = ((volume / vol) - 1e-9).ceil().max(1.0);
+            let x = cells.sqrt().ceil();
+            let y = (cells / x).ceil();
+            Location {
+                y: y as i32,
+                x: x as i32,
+            }
+        })
+    }
+
     // from here on out, functions only return valid locations
 
     pub fn get_cell(&self, loc: &Location) -> Option<&Cell> {
@@ -179,6 +282,18 @@ impl Grid {
             .and_then(|row| row.get(j).and_then(|cell_opt| cell_opt.as_ref()))
     }
 
+    /// Takes the cell out of the grid, returning it if it was there.
+    pub fn remove(&mut self, loc: &Location) -> Option<Cell> {
+        if loc.x < 0 || loc.y < 0 {
+            return None;
+        }
+        let i = loc.y as usize;
+        let j = loc.x as usize;
+        self.vec
+            .get_mut(i)
+            .and_then(|row| row.get_mut(j).and_then(|cell_opt| cell_opt.take()))
+    }
+
     fn locations_from_offsets<'a, I>(&self, loc: &Location, offsets: I) -> Vec<Location>
     where
         I: Iterator<Item = &'a Location>,
@@ -203,27 +318,22 @@ impl Grid {
         vec
     }
 
-    /// Returns a Vec representing the neighbors of the location combined with
-    /// the dimensions of the droplet.
-    pub fn neighbors_dimensions(&self, loc: &Location, dimensions: &Location) -> Vec<Location> {
-        let mut dimensions_nbrhd: HashSet<Location> = HashSet::new();
-        for y in 0..dimensions.y {
-            for x in 0..dimensions.x {
-                let new_loc = loc + &Location { y, x };
-                dimensions_nbrhd.extend(self.neighbors9(&new_loc));
-            }
-        }
-        dimensions_nbrhd.iter().cloned().collect()
-    }
-}
-
-#[cfg(test)]
-impl Grid {
     pub fn is_connected(&self) -> bool {
-        use grid::location::tests::connected_components;
         let locs = self.locations().map(|(loc, _cell)| loc);
         let label_map = connected_components(locs);
-        label_map.values().all(|v| *v == 0)
+        let labels: HashSet<_> = label_map.values().collect();
+        labels.len() <= 1
+    }
+
+    /// Returns a Vec representing the neighbors of the location combined with
+    /// the footprint of the droplet.
+    pub fn neighbors_footprint(&self, loc: &Location, footprint: &Footprint) -> Vec<Location> {
+        let mut footprint_nbrhd: HashSet<Location> = HashSet::new();
+        for offset in footprint.offsets() {
+            let new_loc = loc + &offset;
+            footprint_nbrhd.extend(self.neighbors9(&new_loc));
+        }
+        footprint_nbrhd.iter().cloned().collect()
     }
 }
 
@@ -235,18 +345,68 @@ pub mod tests {
 
     #[test]
     fn test_connected() {
-        let cell = Some(Cell { pin: 0 });
+        let cell = Some(Cell {
+            pin: 0,
+            kind: CellKind::Electrode,
+        });
         let grid1 = Grid {
             vec: vec![vec![None, cell], vec![cell, None]],
+            pitch: None,
+            gap_height: None,
+            min_volume: None,
+            max_volume: None,
         };
         let grid2 = Grid {
             vec: vec![vec![cell, cell], vec![None, None]],
+            pitch: None,
+            gap_height: None,
+            min_volume: None,
+            max_volume: None,
         };
 
         assert!(!grid1.is_connected());
         assert!(grid2.is_connected())
     }
 
+    #[test]
+    fn test_footprint() {
+        let mut grid = Grid::rectangle(5, 5);
+        assert_eq!(grid.footprint(1.0), None);
+
+        // 2mm pitch, 0.25mm gap: an electrode holds 1µL
+        grid.pitch = Some(2.0);
+        grid.gap_height = Some(0.25);
+        assert_eq!(grid.electrode_volume(), Some(1.0));
+
+        let fp = |vol| grid.footprint(vol).unwrap();
+        assert_eq!(fp(0.1), Location { y: 1, x: 1 });
+        assert_eq!(fp(1.0), Location { y: 1, x: 1 });
+        assert_eq!(fp(2.0), Location { y: 1, x: 2 });
+        assert_eq!(fp(3.0), Location { y: 2, x: 2 });
+        assert_eq!(fp(4.0), Location { y: 2, x: 2 });
+        assert_eq!(fp(5.0), Location { y: 2, x: 3 });
+
+        assert_eq!(grid.capacity(&Location { y: 2, x: 3 }), Some(6.0));
+    }
+
+    #[test]
+    fn test_volume_bounds() {
+        let mut grid = Grid::rectangle(5, 5);
+        grid.min_volume = Some(0.3);
+        grid.max_volume = Some(0.3);
+        assert!(grid.volume_in_bounds(0.1 + 0.2));
+        assert!(grid.volume_in_bounds(0.7 - 0.4));
+        assert!(!grid.volume_in_bounds(0.29));
+        assert!(!grid.volume_in_bounds(0.31));
+
+        let json = serde_json::to_string(&grid).unwrap();
+        assert_eq!(Grid::from_reader(json.as_bytes()).unwrap(), grid);
+
+        grid.min_volume = Some(0.4);
+        let json = serde_json::to_string(&grid).unwrap();
+        assert!(Grid::from_reader(json.as_bytes()).is_err());
+    }
+
     #[test]
     fn grid_self_compatible() {
         let g1 = Grid::rectangle(5, 4);
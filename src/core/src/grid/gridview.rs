@@ -1,21 +1,45 @@
-use super::{Droplet, DropletId, DropletInfo, Grid, Location};
+use super::{
+    ActuationError, ActuationFrame, Compatibility, Droplet, DropletId, DropletInfo, Footprint,
+    Grid, Location, Orientation, Regions, Residue, Wash, Wear, WASH_PROCESS,
+};
 use command::Command;
-use plan::Path;
+use plan::{route_droplets, CostModel, Costs, Distance, Path, PlanError, Prioritized, Router};
 use process::ProcessId;
 use rand::Rng;
-use util::collections::{Map, Set};
+use std::sync::Arc;
+use util::collections::Map;
 
 pub struct GridView {
     pub grid: Grid,
+    pub wear: Wear,
+    pub regions: Regions,
+    /// How to route droplets; see `Router`.
+    pub router: Arc<Router>,
+    /// What routes cost; see `CostModel`.
+    pub cost_model: Arc<CostModel>,
+    /// What the droplets have left behind so far, up to what's executed.
+    /// Change its table with `set_compatibility`, so planning sees it too.
+    pub residue: Residue,
+    /// `residue` plus what the planned steps will leave; see
+    /// `residue_so_far`.
+    planned_residue: Residue,
+    /// Where wash droplets come from, if the board has anywhere.
+    pub wash: Option<Wash>,
+    washes: usize,
     history: Vec<Snapshot>,
     exec_time: usize,
     done: bool,
+    /// Why the board stopped, if it did.
+    failure: Option<ActuationError>,
 }
 
 #[derive(Default)]
 pub struct Snapshot {
     pub droplets: Map<DropletId, Droplet>,
     commands_to_finalize: Vec<Box<Command>>,
+    // whether this snapshot was reached from the previous one by routing,
+    // rather than by running a command
+    routed: bool,
 }
 
 impl Snapshot {
@@ -40,20 +64,56 @@ impl Snapshot {
     }
 }
 
+/// Everything that planning can change but hasn't run yet, so that a plan can
+/// be tried out and taken back.
+pub struct Checkpoint {
+    exec_time: usize,
+    snapshots: Vec<SavedSnapshot>,
+    planned_residue: Residue,
+}
+
+struct SavedSnapshot {
+    droplets: Map<DropletId, Droplet>,
+    n_commands: usize,
+    routed: bool,
+}
+
 #[derive(Debug)]
 pub enum ExecResponse {
-    Step,
+    /// A step was taken, and this is the frame that actuates it.
+    Step(ActuationFrame),
     NotReady,
     Done,
 }
 
+/// Notes the residue left by the droplets in `snapshot`, or cleaned up by the
+/// wash droplets in it.
+fn leave_residue(residue: &mut Residue, snapshot: &Snapshot, time: usize) {
+    for droplet in snapshot.droplets.values() {
+        if Wash::is_wash(&droplet.id) {
+            residue.wash(droplet);
+        } else {
+            residue.record(droplet, time);
+        }
+    }
+}
+
 impl GridView {
     pub fn new(grid: Grid) -> GridView {
         GridView {
             grid: grid,
+            wear: Wear::default(),
+            regions: Regions::default(),
+            router: Arc::new(Prioritized),
+            cost_model: Arc::new(Distance),
+            residue: Residue::default(),
+            planned_residue: Residue::default(),
+            wash: None,
+            washes: 0,
             history: vec![Snapshot::default()],
             exec_time: 0,
             done: false,
+            failure: None,
         }
     }
 
@@ -62,11 +122,29 @@ impl GridView {
 
         // compare with len - 1 because we wouldn't want to "write out" a state
         // that hasn't been fully planned
-        let resp = if self.exec_time < self.history.len() - 1 {
-            // TODO should probably do this later when things have been validated
-            self.history[self.exec_time].finalize();
-            self.exec_time += 1;
-            Step
+        let resp = if self.failure.is_some() {
+            // nothing more runs once the board has stopped
+            if self.done {
+                Done
+            } else {
+                NotReady
+            }
+        } else if self.exec_time < self.history.len() - 1 {
+            // refuse to go on if the hardware wouldn't do what we planned,
+            // before anything waiting on this step hears it went fine
+            match self.next_frame() {
+                Ok(frame) => {
+                    self.history[self.exec_time].finalize();
+                    leave_residue(&mut self.residue, &self.history[self.exec_time], self.exec_time);
+                    self.exec_time += 1;
+                    Step(frame)
+                }
+                Err(err) => {
+                    error!("Bad actuation, stopping: {:?}", err);
+                    self.fail(err);
+                    NotReady
+                }
+            }
         } else if self.done {
             Done
         } else {
@@ -82,6 +160,136 @@ impl GridView {
         resp
     }
 
+    /// Returns the frame that actuates the step the executor is about to take.
+    fn next_frame(&self) -> Result<ActuationFrame, ActuationError> {
+        let empty = Snapshot::default();
+        let before = match self.exec_time {
+            0 => &empty,
+            t => &self.history[t - 1],
+        };
+        let now = &self.history[self.exec_time];
+        ActuationFrame::between(&self.grid, before, now)
+    }
+
+    /// Stops the board because the next step couldn't be actuated.
+    /// Everything planned from here on is called off, and so is anything
+    /// planned later.
+    pub fn fail(&mut self, err: ActuationError) {
+        for snapshot in &mut self.history[self.exec_time..] {
+            for cmd in &mut snapshot.commands_to_finalize {
+                cmd.abort(&err);
+            }
+        }
+        self.failure = Some(err);
+    }
+
+    /// Why the board stopped, if it did; see `fail`.
+    pub fn failure(&self) -> Option<&ActuationError> {
+        self.failure.as_ref()
+    }
+
+    /// How many steps have been planned, counting the one being planned now.
+    pub fn planned_steps(&self) -> usize {
+        self.history.len()
+    }
+
+    pub fn checkpoint(&self) -> Checkpoint {
+        let snapshots = self.history[self.exec_time..]
+            .iter()
+            .map(|s| SavedSnapshot {
+                droplets: s.droplets.clone(),
+                n_commands: s.commands_to_finalize.len(),
+                routed: s.routed,
+            })
+            .collect();
+        Checkpoint {
+            exec_time: self.exec_time,
+            snapshots,
+            planned_residue: self.planned_residue.clone(),
+        }
+    }
+
+    /// Takes back everything planned since `checkpoint`. Nothing may have
+    /// been executed since then.
+    pub fn restore(&mut self, checkpoint: &Checkpoint) {
+        let from = checkpoint.exec_time;
+        assert_eq!(self.exec_time, from, "can't take back steps that already ran");
+        self.history.truncate(from + checkpoint.snapshots.len());
+        for (snapshot, saved) in self.history[from..].iter_mut().zip(&checkpoint.snapshots) {
+            snapshot.droplets = saved.droplets.clone();
+            snapshot.commands_to_finalize.truncate(saved.n_commands);
+            snapshot.routed = saved.routed;
+        }
+        self.planned_residue = checkpoint.planned_residue.clone();
+    }
+
+    /// A copy to try out plans on without holding up the executor. The steps
+    /// that already ran are left empty, and no commands come along, so the
+    /// copy can't be executed; plan for real on the original.
+    pub fn planning_copy(&self) -> GridView {
+        let history = self
+            .history
+            .iter()
+            .enumerate()
+            .map(|(t, snapshot)| {
+                if t < self.exec_time {
+                    return Snapshot::default();
+                }
+                Snapshot {
+                    droplets: snapshot.droplets.clone(),
+                    commands_to_finalize: Vec::new(),
+                    routed: snapshot.routed,
+                }
+            })
+            .collect();
+        GridView {
+            grid: self.grid.clone(),
+            wear: self.wear.clone(),
+            regions: self.regions.clone(),
+            router: Arc::clone(&self.router),
+            cost_model: Arc::clone(&self.cost_model),
+            residue: self.residue.clone(),
+            planned_residue: self.planned_residue.clone(),
+            wash: self.wash.clone(),
+            washes: self.washes,
+            history,
+            exec_time: self.exec_time,
+            done: self.done,
+            failure: self.failure.clone(),
+        }
+    }
+
+    /// The residue the droplets will have left once everything planned so
+    /// far has run, for planning what comes after. The step being planned
+    /// now doesn't count until it's done. The residue is only recorded for
+    /// good once a step is done executing.
+    pub fn residue_so_far(&self) -> &Residue {
+        &self.planned_residue
+    }
+
+    /// Sets which reagents don't get along, for both the residue that's
+    /// there and the residue that's planned.
+    pub fn set_compatibility(&mut self, compatibility: Compatibility) {
+        self.planned_residue.compatibility = compatibility.clone();
+        self.residue.compatibility = compatibility;
+    }
+
+    /// Works out `planned_residue` again after planned steps were changed,
+    /// rather than just added to.
+    fn replay_residue(&mut self) {
+        let mut residue = self.residue.clone();
+        let planned = self.history.len() - 1;
+        for t in self.exec_time..planned {
+            leave_residue(&mut residue, &self.history[t], t);
+        }
+        self.planned_residue = residue;
+    }
+
+    /// The cost model and wear that routing goes by.
+    pub fn costs(&self) -> Costs<'_> {
+        Costs::new(&*self.cost_model, &self.wear)
+    }
+
     pub fn snapshot(&self) -> &Snapshot {
         self.history.last().unwrap()
     }
@@ -108,6 +316,9 @@ impl GridView {
             panic!("collision: {:#?}", col);
         });
 
+        // the step is planned now, so its residue is too
+        leave_residue(&mut self.planned_residue, &self.history[now], now);
+
         let mut new_snapshot = Snapshot::default();
         new_snapshot.droplets = self.history[now].droplets.clone();
         self.history.push(new_snapshot);
@@ -170,20 +381,460 @@ impl GridView {
                     });
                 }
             }
+            self.snapshot_mut().routed = true;
             self.tick();
         }
     }
 
+    /// Routes just the droplets `ids` to their destinations, starting as soon
+    /// as they're free instead of after everything planned so far. They go
+    /// back into the steps that are planned but not executed yet, around
+    /// everyone else in them, so they can get going while other commands
+    /// are still running.
+    ///
+    /// Returns whether that worked. If it didn't, nothing has changed, and
+    /// they have to be routed from the end with `route` as usual.
+    pub fn route_windowed(&mut self, ids: &[DropletId]) -> bool {
+        // the last snapshot is the one being planned, so the ones before it
+        // are all that's planned so far
+        let planned = self.history.len() - 1;
+        if ids.is_empty() || planned <= self.exec_time + 1 {
+            return false;
+        }
+
+        let start = {
+            let now = &self.history[planned];
+            let unchanged = |snapshot: &Snapshot| {
+                ids.iter().all(|id| match (snapshot.droplets.get(id), now.droplets.get(id)) {
+                    (Some(then), Some(now)) => {
+                        then.location == now.location && then.footprint == now.footprint
+                    }
+                    _ => false,
+                })
+            };
+            let mut start = planned - 1;
+            while start > self.exec_time && unchanged(&self.history[start - 1]) {
+                start -= 1;
+            }
+            start
+        };
+        if start + 1 >= planned {
+            return false;
+        }
+
+        let droplets: Map<_, _> = ids.iter()
+            .map(|id| {
+                let mut droplet = self.history[start].droplets[id].clone();
+                droplet.destination = self.snapshot().droplets[id].destination;
+                (*id, droplet)
+            })
+            .collect();
+        let paths = {
+            let reserved: Vec<Vec<&Droplet>> = self.history[start..planned]
+                .iter()
+                .map(|s| s.droplets.values().filter(|d| !ids.contains(&d.id)).collect())
+                .collect();
+            self.router.route_reserved(
+                &droplets,
+                &reserved,
+                &self.grid,
+                &self.costs(),
+                &self.regions,
+                self.residue_so_far(),
+            )
+        };
+
+        match paths {
+            Some(ref paths) if self.contaminates_planned(start, &droplets, paths) => {
+                debug!("routing {:?} from {} would dirty droplets planned there", ids, start);
+                false
+            }
+            Some(paths) => {
+                debug!("routing {:?} from {} instead of {}", ids, start, planned);
+                self.take_paths_from(start, &paths);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Whether `droplets` taking `paths` from `start` would leave residue
+    /// where a droplet that's already planned goes afterward and minds it.
+    /// That droplet was planned before the residue was there, so nothing
+    /// kept it away.
+    fn contaminates_planned(
+        &self,
+        start: usize,
+        droplets: &Map<DropletId, Droplet>,
+        paths: &Map<DropletId, Path>,
+    ) -> bool {
+        let planned = self.history.len() - 1;
+        let mut left = Residue::default();
+        left.compatibility = self.residue.compatibility.clone();
+        for t in start..planned {
+            for (id, path) in paths.iter() {
+                let mut droplet = droplets[id].clone();
+                droplet.location = path[(t - start).min(path.len() - 1)];
+                left.record(&droplet, t);
+            }
+            let others = self.history[t]
+                .droplets
+                .values()
+                .filter(|d| !paths.contains_key(&d.id));
+            for other in others {
+                let locations = other.locations();
+                if locations.iter().any(|loc| left.contaminates(loc, &other.reagents)) {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    /// Like `take_paths`, but the paths start back at `start`, in the steps
+    /// that are already planned.
+    fn take_paths_from(&mut self, start: usize, paths: &Map<DropletId, Path>) {
+        let planned = self.history.len() - 1;
+        for (id, path) in paths.iter() {
+            assert_eq!(self.history[start].droplets[id].location, path[0]);
+        }
+
+        for t in start + 1..planned {
+            for (id, path) in paths.iter() {
+                let location = path[(t - start).min(path.len() - 1)];
+                let droplet = self.history[t].droplets.get_mut(id).unwrap();
+                droplet.location = location;
+            }
+            if let Some(col) = self.get_collision_at_time(t) {
+                panic!("collision: {:#?}", col);
+            }
+        }
+
+        // whatever is left gets planned from the end like any other route
+        let rest: Map<_, _> = paths
+            .iter()
+            .map(|(&id, path)| {
+                let done = (planned - 1 - start).min(path.len() - 1);
+                (id, path[done..].to_vec())
+            })
+            .collect();
+        for (&id, path) in rest.iter() {
+            self.update(id, |droplet| droplet.location = path[0]);
+        }
+        self.replay_residue();
+        self.take_paths(&rest);
+    }
+
+    /// Returns where the droplet was stranded, if it was sitting on a cell
+    /// when it was marked faulty.
+    pub fn stranded(&self, id: &DropletId) -> Option<Location> {
+        self.snapshot().droplets.get(id).and_then(|d| d.stranded)
+    }
+
+    /// Removes a dead cell from the grid, and replans the steps that haven't
+    /// been executed yet so no droplet crosses it. The droplets sitting on the
+    /// cell right now are stranded there; their ids are returned. They stay
+    /// put from now on, so their planned moves are dropped, and they say
+    /// they're stranded in their `DropletInfo`.
+    ///
+    /// Only the routes between commands are replanned. If a planned command
+    /// itself uses the cell or a stranded droplet, the plan can't be repaired
+    /// and nothing is changed at all, so it can be tried again later.
+    pub fn mark_faulty(
+        &mut self,
+        loc: Location,
+        rng: &mut impl Rng,
+    ) -> Result<Vec<DropletId>, PlanError> {
+        let cell = match self.grid.remove(&loc) {
+            Some(cell) => cell,
+            // it's either already dead or was never there
+            None => return Ok(vec![]),
+        };
+
+        let result = self.replan_without(loc, rng);
+        if result.is_err() {
+            self.grid.vec[loc.y as usize][loc.x as usize] = Some(cell);
+        }
+        result
+    }
+
+    /// Does the work for `mark_faulty` once the cell is gone from the grid.
+    /// Nothing is changed until every repair has been found.
+    fn replan_without(
+        &mut self,
+        loc: Location,
+        rng: &mut impl Rng,
+    ) -> Result<Vec<DropletId>, PlanError> {
+        let now = self.exec_time;
+        let pinned: Map<DropletId, Droplet> = self.history[now]
+            .droplets
+            .values()
+            .filter(|d| d.covers(&loc))
+            .map(|d| {
+                let mut droplet = d.clone();
+                droplet.destination = None;
+                droplet.stranded = Some(loc);
+                (d.id, droplet)
+            })
+            .collect();
+        let stranded: Vec<DropletId> = pinned.keys().cloned().collect();
+
+        // routes can be changed to leave the stranded droplets where they
+        // are, but commands can't
+        for t in now + 1..self.history.len() {
+            if self.history[t].routed {
+                continue;
+            }
+            let used = pinned.keys().any(|id| {
+                let before = &self.history[t - 1].droplets[id];
+                match self.history[t].droplets.get(id) {
+                    Some(after) => after.location != before.location,
+                    None => true,
+                }
+            });
+            if used {
+                return Err(PlanError::ReplanError {
+                    location: loc,
+                    time: t,
+                });
+            }
+        }
+
+        // whether some other droplet is on the cell or up against a stranded
+        // one
+        let uses_cell = |snapshot: &Snapshot| {
+            let in_the_way = |d: &Droplet| {
+                pinned.values().any(|p| {
+                    p.collision_group != d.collision_group && p.collision_distance(d) <= 0
+                })
+            };
+            snapshot
+                .droplets
+                .values()
+                .filter(|d| !pinned.contains_key(&d.id))
+                .any(|d| d.covers(&loc) || in_the_way(d))
+        };
+
+        // find all the routes that need replanning before changing anything
+        let mut repairs = Vec::new();
+        let mut t = now + 1;
+        while t < self.history.len() {
+            if !uses_cell(&self.history[t]) {
+                t += 1;
+                continue;
+            }
+            if !self.history[t].routed {
+                return Err(PlanError::ReplanError {
+                    location: loc,
+                    time: t,
+                });
+            }
+
+            // the route starts at the last unrouted snapshot, but we can't
+            // change anything that has already been executed
+            let mut start = t - 1;
+            while start > now && self.history[start].routed {
+                start -= 1;
+            }
+            let mut end = t;
+            while end + 1 < self.history.len() && self.history[end + 1].routed {
+                end += 1;
+            }
+
+            if uses_cell(&self.history[end]) {
+                return Err(PlanError::ReplanError {
+                    location: loc,
+                    time: end,
+                });
+            }
+
+            let snapshots = self.reroute(start, end, &pinned, rng).ok_or(PlanError::ReplanError {
+                location: loc,
+                time: t,
+            })?;
+            repairs.push((start, end, snapshots));
+            t = end + 1;
+        }
+
+        // splice in the new routes from back to front so indices stay valid
+        for (start, end, snapshots) in repairs.into_iter().rev() {
+            let last = start + snapshots.len();
+            let removed: Vec<_> = self.history.splice(start + 1..end + 1, snapshots).collect();
+            // commands finalize at the end of the route instead
+            for mut snapshot in removed {
+                let cmds = snapshot.commands_to_finalize.drain(..);
+                self.history[last].commands_to_finalize.extend(cmds);
+            }
+        }
+
+        for snapshot in &mut self.history[now..] {
+            snapshot.droplets.extend(pinned.clone());
+        }
+        self.replay_residue();
+
+        Ok(stranded)
+    }
+
+    /// Routes the droplets at time `start` to where they are at time `end`,
+    /// returning the snapshots after `start`. The `pinned` droplets stay
+    /// where they are given instead.
+    fn reroute(
+        &self,
+        start: usize,
+        end: usize,
+        pinned: &Map<DropletId, Droplet>,
+        rng: &mut impl Rng,
+    ) -> Option<Vec<Snapshot>> {
+        let mut goals = self.history[end].droplets.clone();
+        goals.extend(pinned.clone());
+        let mut droplets = self.history[start].droplets.clone();
+        droplets.extend(pinned.clone());
+        for (id, droplet) in droplets.iter_mut() {
+            droplet.destination = Some(goals[id].location);
+        }
+
+        let paths = route_droplets(
+            &droplets,
+            &self.grid,
+            &self.costs(),
+            &self.regions,
+            self.residue_so_far(),
+            &*self.router,
+            rng,
+        ).ok()?;
+        let max_len = paths.values().map(|path| path.len()).max().unwrap_or(0);
+
+        let snapshots = (1..max_len)
+            .map(|i| {
+                let mut snapshot = Snapshot {
+                    droplets: goals.clone(),
+                    routed: true,
+                    ..Snapshot::default()
+                };
+                for (id, path) in paths.iter() {
+                    let droplet = snapshot.droplets.get_mut(id).unwrap();
+                    droplet.location = path[i.min(path.len() - 1)];
+                }
+                snapshot
+            })
+            .collect();
+
+        Some(snapshots)
+    }
+
+    /// Cleans up residue after everything planned so far: a wash droplet
+    /// comes out of the wash reservoir, goes over up to `Wash::max_cells` of
+    /// the dirty cells it can get to, nearest first, and is thrown away at the
+    /// waste. The residue is gone as soon as the wash droplet has been over
+    /// it.
+    ///
+    /// Returns whether a wash was planned. If there's nothing to wash or no
+    /// way to do it, nothing changes.
+    pub fn wash(&mut self, rng: &mut impl Rng) -> bool {
+        let wash = match self.wash {
+            Some(ref wash) => wash.clone(),
+            None => return false,
+        };
+        if self.done || self.failure.is_some() {
+            return false;
+        }
+
+        let free = |gv: &GridView, loc: &Location| {
+            gv.grid.get_cell(loc).is_some()
+                && gv.snapshot().droplets.values().all(|d| d.distance_to(loc) > 0)
+        };
+        let mut dirty = self.residue_so_far().dirty_cells();
+        dirty.retain(|loc| free(self, loc));
+        if dirty.is_empty() || !free(self, &wash.reservoir) || !free(self, &wash.waste) {
+            return false;
+        }
+
+        let checkpoint = self.checkpoint();
+        let id = DropletId {
+            id: self.washes,
+            process_id: WASH_PROCESS,
+        };
+        let unit = Location { y: 1, x: 1 };
+        self.insert(Droplet::new(id, wash.volume, wash.reservoir, unit));
+        self.tick();
+
+        let mut washed = 0;
+        let mut at = wash.reservoir;
+        while washed < wash.max_cells {
+            let target = match dirty.iter().cloned().min_by_key(|loc| loc.distance_to(&at)) {
+                Some(target) => target,
+                None => break,
+            };
+            dirty.retain(|loc| *loc != target);
+            if let Some(path) = self.route_one(id, target, rng) {
+                // anything it went over on the way is clean too
+                dirty.retain(|loc| !path.contains(loc));
+                washed += 1;
+                at = target;
+            }
+        }
+
+        if washed == 0 || self.route_one(id, wash.waste, rng).is_none() {
+            self.restore(&checkpoint);
+            return false;
+        }
+        self.remove(&id);
+        self.tick();
+
+        info!("Washing {} cells with {:?}", washed, id);
+        self.washes += 1;
+        true
+    }
+
+    /// Routes just droplet `id` to `destination`, with everyone else staying
+    /// put, returning the path it took.
+    fn route_one(
+        &mut self,
+        id: DropletId,
+        destination: Location,
+        rng: &mut impl Rng,
+    ) -> Option<Path> {
+        let mut droplets = self.snapshot().droplets.clone();
+        for droplet in droplets.values_mut() {
+            droplet.destination = None;
+        }
+        droplets.get_mut(&id).unwrap().destination = Some(destination);
+
+        let paths = route_droplets(
+            &droplets,
+            &self.grid,
+            &self.costs(),
+            &self.regions,
+            self.residue_so_far(),
+            &*self.router,
+            rng,
+        ).ok()?;
+        self.take_paths(&paths);
+        Some(paths[&id].clone())
+    }
+
+    /// A view of the part of the board in `mapping`, turned by
+    /// `orientation` to match it.
     pub fn subview(
         &mut self,
         ids: impl IntoIterator<Item = DropletId>,
         mapping: Map<Location, Location>,
+        orientation: Orientation,
     ) -> GridSubView {
-        GridSubView {
+        let unmapping = mapping.iter().map(|(&from, &to)| (to, from)).collect();
+        let mut subview = GridSubView {
             backing_gridview: self,
             mapping: mapping,
-            ids: ids.into_iter().collect(),
+            unmapping,
+            orientation,
+            views: Map::new(),
+        };
+        for id in ids {
+            let droplet = subview.backing_gridview.snapshot().droplets[&id].clone();
+            let view = subview.unmap(droplet);
+            subview.views.insert(id, view);
         }
+        subview
     }
 
     pub fn register(&mut self, cmd: Box<Command>) {
@@ -195,6 +846,7 @@ impl GridView {
     pub fn rollback(&mut self, snapshot: Snapshot) {
         self.history.truncate(self.exec_time);
         self.history[self.exec_time] = snapshot;
+        self.replay_residue();
     }
 
     pub fn perturb(&self, rng: &mut impl Rng) -> Option<Snapshot> {
@@ -228,7 +880,11 @@ impl GridView {
 pub struct GridSubView<'a> {
     backing_gridview: &'a mut GridView,
     mapping: Map<Location, Location>,
-    ids: Set<DropletId>,
+    /// `mapping` the other way around, from the board to the command's view.
+    unmapping: Map<Location, Location>,
+    orientation: Orientation,
+    /// The droplets the command may touch, as it sees them.
+    views: Map<DropletId, Droplet>,
 }
 
 impl<'a> GridSubView<'a> {
@@ -236,14 +892,14 @@ impl<'a> GridSubView<'a> {
         self.backing_gridview.tick()
     }
 
-    // TODO: translate or somehow hide the untranslated location of this
+    /// The droplet as the command sees it, in the same locations and
+    /// orientation as the command's shape.
     pub fn get(&self, id: &DropletId) -> &Droplet {
-        assert!(self.ids.contains(&id));
-        self.backing_gridview.snapshot().droplets.get(id).unwrap()
+        &self.views[id]
     }
 
     fn get_mut(&mut self, id: &DropletId) -> &mut Droplet {
-        assert!(self.ids.contains(&id));
+        assert!(self.views.contains_key(&id));
         self.backing_gridview
             .snapshot_mut()
             .droplets
@@ -251,42 +907,53 @@ impl<'a> GridSubView<'a> {
             .unwrap()
     }
 
-    pub fn insert(&mut self, mut droplet: Droplet) {
-        let new_loc = self.mapping.get(&droplet.location);
-        trace!("Inserting {:#?} at {:?}", droplet, new_loc);
-        droplet.location = *new_loc.unwrap();
-        let was_not_there = self.ids.insert(droplet.id);
-        assert!(was_not_there);
-        self.backing_gridview.insert(droplet);
+    /// Moves a droplet from the command's view onto the board.
+    fn map(&self, mut droplet: Droplet) -> Droplet {
+        let cells = droplet.locations().into_iter().map(|loc| {
+            *self.mapping
+                .get(&loc)
+                .unwrap_or_else(|| panic!("{} was unmapped!, mapping: {:#?}", loc, self.mapping))
+        });
+        let (location, footprint) = Footprint::from_locations(cells).unwrap();
+        droplet.location = location;
+        droplet.footprint = footprint;
+        droplet
     }
 
-    pub fn remove(&mut self, id: &DropletId) -> Droplet {
-        let was_there = self.ids.remove(id);
-        assert!(was_there);
-        let mut droplet = self.backing_gridview.remove(id);
-        // FIXME this is pretty dumb
-        let (unmapped_loc, _) = self.mapping
-            .iter()
-            .find(|(_, &v)| v == droplet.location)
-            .unwrap();
-        droplet.location = *unmapped_loc;
+    /// Moves a droplet from the board into the command's view.
+    fn unmap(&self, mut droplet: Droplet) -> Droplet {
+        let cells = droplet.locations().into_iter().map(|loc| {
+            *self.unmapping
+                .get(&loc)
+                .unwrap_or_else(|| panic!("{} was unmapped!, mapping: {:#?}", loc, self.mapping))
+        });
+        let (location, footprint) = Footprint::from_locations(cells).unwrap();
+        droplet.location = location;
+        droplet.footprint = footprint;
         droplet
     }
 
+    pub fn insert(&mut self, droplet: Droplet) {
+        let mapped = self.map(droplet.clone());
+        trace!("Inserting {:#?}", mapped);
+        let was_there = self.views.insert(droplet.id, droplet);
+        assert!(was_there.is_none());
+        self.backing_gridview.insert(mapped);
+    }
+
+    pub fn remove(&mut self, id: &DropletId) -> Droplet {
+        let was_there = self.views.remove(id);
+        assert!(was_there.is_some());
+        let droplet = self.backing_gridview.remove(id);
+        self.unmap(droplet)
+    }
+
     fn check_droplet(&self, id: &DropletId) {
-        // TODO will this have translated or real location??
-        let droplet = self.get(id);
-        let mapped_to: Set<_> = self.mapping.values().collect();
-        // TODO this is pretty slow
-        for i in 0..droplet.dimensions.y {
-            for j in 0..droplet.dimensions.x {
-                let loc = Location {
-                    y: droplet.location.y + i,
-                    x: droplet.location.x + j,
-                };
-                if !mapped_to.contains(&loc) {
-                    panic!("{} was unmapped!, mapping: {:#?}", loc, self.mapping);
-                }
+        // this is the real location, on the board
+        let droplet = &self.backing_gridview.snapshot().droplets[id];
+        for loc in droplet.locations() {
+            if !self.unmapping.contains_key(&loc) {
+                panic!("{} was unmapped!, mapping: {:#?}", loc, self.mapping);
             }
         }
     }
@@ -296,31 +963,337 @@ impl<'a> GridSubView<'a> {
         self.check_droplet(id);
     }
 
-    pub fn move_west(&mut self, id: DropletId) {
-        trace!("Moving droplet {:?} west", id);
+    /// Moves a droplet one step in `direction`, as the command sees it.
+    fn step(&mut self, id: DropletId, direction: Location) {
+        let view = self.views.get_mut(&id).unwrap();
+        view.location = &view.location + &direction;
+        let direction = self.orientation.apply(&direction);
         self.update(&id, |droplet| {
-            droplet.location = droplet.location.west();
+            droplet.location = &droplet.location + &direction;
         })
     }
 
+    pub fn move_west(&mut self, id: DropletId) {
+        trace!("Moving droplet {:?} west", id);
+        self.step(id, Location { y: 0, x: -1 })
+    }
+
     pub fn move_east(&mut self, id: DropletId) {
         trace!("Moving droplet {:?} east", id);
-        self.update(&id, |droplet| {
-            droplet.location = droplet.location.east();
-        })
+        self.step(id, Location { y: 0, x: 1 })
     }
 
     pub fn move_north(&mut self, id: DropletId) {
         trace!("Moving droplet {:?} north", id);
-        self.update(&id, |droplet| {
-            droplet.location = droplet.location.north();
-        })
+        self.step(id, Location { y: -1, x: 0 })
     }
 
     pub fn move_south(&mut self, id: DropletId) {
         trace!("Moving droplet {:?} south", id);
-        self.update(&id, |droplet| {
-            droplet.location = droplet.location.south();
-        })
+        self.step(id, Location { y: 1, x: 0 })
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    use std::mem;
+
+    use grid::CellKind;
+    use plan::ConflictBased;
+    use util::seed::rng_from_seed;
+
+    fn droplet_at(id: usize, location: Location) -> Droplet {
+        let id = DropletId { id, process_id: 0 };
+        Droplet::new(id, 1.0, location, Location { y: 1, x: 1 })
+    }
+
+    #[test]
+    fn test_mark_faulty_reroutes() {
+        let mut gv = GridView::new(Grid::rectangle(3, 5));
+        let mut rng = rng_from_seed(0);
+        let d = droplet_at(0, Location { y: 1, x: 0 });
+        let id = d.id;
+        gv.insert(d);
+        gv.tick();
+
+        let dest = Location { y: 1, x: 4 };
+        gv.snapshot_mut().droplets.get_mut(&id).unwrap().destination = Some(dest);
+        let paths = gv.route(&mut rng).unwrap();
+        gv.take_paths(&paths);
+        gv.snapshot_mut().droplets.get_mut(&id).unwrap().destination = None;
+
+        // the straight line route goes through here
+        let dead = Location { y: 1, x: 2 };
+        assert!(gv.history.iter().any(|s| s.droplets[&id].covers(&dead)));
+
+        let stranded = gv.mark_faulty(dead, &mut rng).unwrap();
+        assert!(stranded.is_empty());
+
+        assert!(gv.grid.get_cell(&dead).is_none());
+        assert!(gv.history.iter().all(|s| !s.droplets[&id].covers(&dead)));
+        assert_eq!(gv.snapshot().droplets[&id].location, dest);
+        for pair in gv.history.windows(2) {
+            let (a, b) = (&pair[0].droplets[&id], &pair[1].droplets[&id]);
+            assert!(a.location.distance_to(&b.location) <= 1);
+        }
+    }
+
+    #[test]
+    fn test_route_windowed() {
+        let mut gv = GridView::new(Grid::rectangle(5, 9));
+        let mut rng = rng_from_seed(0);
+        let a = droplet_at(0, Location { y: 0, x: 0 });
+        let b = droplet_at(1, Location { y: 4, x: 0 });
+        let (a_id, b_id) = (a.id, b.id);
+        gv.insert(a);
+        gv.insert(b);
+        gv.tick();
+
+        // a crosses the top of the board while b waits
+        gv.snapshot_mut().droplets.get_mut(&a_id).unwrap().destination =
+            Some(Location { y: 0, x: 8 });
+        let paths = gv.route(&mut rng).unwrap();
+        gv.take_paths(&paths);
+        gv.snapshot_mut().droplets.get_mut(&a_id).unwrap().destination = None;
+        let len = gv.history.len();
+
+        // b crosses the bottom at the same time, so nothing gets longer
+        let dest = Location { y: 4, x: 8 };
+        gv.snapshot_mut().droplets.get_mut(&b_id).unwrap().destination = Some(dest);
+
+        // a router that can't route around reservations isn't gone around
+        let prioritized = mem::replace(&mut gv.router, Arc::new(ConflictBased::default()));
+        assert!(!gv.route_windowed(&[b_id]));
+        assert_eq!(gv.history.len(), len);
+        gv.router = prioritized;
+
+        assert!(gv.route_windowed(&[b_id]));
+        assert_eq!(gv.history.len(), len);
+        assert_eq!(gv.history[len - 2].droplets[&b_id].location, dest);
+        assert_eq!(gv.snapshot().droplets[&b_id].location, dest);
+        for pair in gv.history.windows(2) {
+            let (before, after) = (&pair[0].droplets[&b_id], &pair[1].droplets[&b_id]);
+            assert!(before.location.distance_to(&after.location) <= 1);
+        }
+    }
+
+    #[test]
+    fn test_route_windowed_blocked() {
+        let mut gv = GridView::new(Grid::rectangle(1, 9));
+        let mut rng = rng_from_seed(0);
+        let a = droplet_at(0, Location { y: 0, x: 0 });
+        let b = droplet_at(1, Location { y: 0, x: 8 });
+        let (a_id, b_id) = (a.id, b.id);
+        gv.insert(a);
+        gv.insert(b);
+        gv.tick();
+
+        gv.snapshot_mut().droplets.get_mut(&a_id).unwrap().destination =
+            Some(Location { y: 0, x: 4 });
+        let paths = gv.route(&mut rng).unwrap();
+        gv.take_paths(&paths);
+        gv.snapshot_mut().droplets.get_mut(&a_id).unwrap().destination = None;
+        let len = gv.history.len();
+
+        // a is parked in the way, and only routing from the end could move it
+        gv.snapshot_mut().droplets.get_mut(&b_id).unwrap().destination =
+            Some(Location { y: 0, x: 0 });
+        assert!(!gv.route_windowed(&[b_id]));
+        assert_eq!(gv.history.len(), len);
+        assert!(
+            gv.history
+                .iter()
+                .all(|s| s.droplets[&b_id].location == Location { y: 0, x: 8 })
+        );
+    }
+
+    #[test]
+    fn test_route_windowed_residue() {
+        let window = |reagent: &str| {
+            let mut gv = GridView::new(Grid::rectangle(3, 9));
+            gv.set_compatibility(Compatibility {
+                incompatible: vec![("acid".into(), "base".into())],
+                penalty: Some(1),
+            });
+            let mut a = droplet_at(0, Location { y: 0, x: 3 });
+            a.reagents.insert(reagent.into());
+            let mut b = droplet_at(1, Location { y: 2, x: 0 });
+            b.reagents.insert("base".into());
+            let (a_id, b_id) = (a.id, b.id);
+            gv.insert(a);
+            gv.insert(b);
+            gv.tick();
+
+            // a waits a while, then comes down onto the bottom row
+            let mut path = vec![Location { y: 0, x: 3 }; 7];
+            path.push(Location { y: 1, x: 3 });
+            path.push(Location { y: 2, x: 3 });
+            gv.take_paths(&vec![(a_id, path)].into_iter().collect());
+            let len = gv.history.len();
+
+            // b would get along the bottom row first, leaving base behind
+            gv.snapshot_mut().droplets.get_mut(&b_id).unwrap().destination =
+                Some(Location { y: 2, x: 6 });
+            let routed = gv.route_windowed(&[b_id]);
+            assert_eq!(gv.history.len(), len);
+            routed
+        };
+
+        assert!(window("water"));
+        assert!(!window("acid"));
+    }
+
+    #[test]
+    fn test_mark_faulty_strands() {
+        let mut gv = GridView::new(Grid::rectangle(5, 5));
+        let mut rng = rng_from_seed(0);
+        let dead = Location { y: 2, x: 2 };
+        let d = droplet_at(0, dead);
+        let id = d.id;
+        gv.insert(d);
+        let other = droplet_at(1, Location { y: 4, x: 4 });
+        let other_id = other.id;
+        gv.insert(other);
+        gv.tick();
+
+        // the droplet was going to leave, and the other one to come by
+        // where it was
+        let up = vec![dead, Location { y: 1, x: 2 }, Location { y: 0, x: 2 }];
+        let by = vec![
+            Location { y: 4, x: 4 },
+            Location { y: 3, x: 4 },
+            Location { y: 3, x: 3 },
+            Location { y: 3, x: 2 },
+            Location { y: 3, x: 1 },
+            Location { y: 4, x: 1 },
+            Location { y: 4, x: 0 },
+        ];
+        gv.take_paths(&vec![(id, up), (other_id, by)].into_iter().collect());
+
+        let stranded = gv.mark_faulty(dead, &mut rng).unwrap();
+        assert_eq!(stranded, vec![id]);
+        assert_eq!(gv.stranded(&id), Some(dead));
+
+        // it stays put, and the other one keeps away from it
+        for snapshot in &gv.history {
+            let droplet = &snapshot.droplets[&id];
+            assert_eq!(droplet.location, dead);
+            assert_eq!(droplet.info().stranded, Some(dead));
+            assert!(droplet.collision_distance(&snapshot.droplets[&other_id]) > 0);
+        }
+
+        let other = &gv.snapshot().droplets[&other_id];
+        assert_eq!(other.location, Location { y: 4, x: 0 });
+
+        // marking it again does nothing
+        assert!(gv.mark_faulty(dead, &mut rng).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_mark_faulty_retry() {
+        let mut gv = GridView::new(Grid::rectangle(3, 3));
+        let mut rng = rng_from_seed(0);
+        let dead = Location { y: 1, x: 1 };
+        let d = droplet_at(0, Location { y: 0, x: 1 });
+        let id = d.id;
+        gv.insert(d);
+        gv.tick();
+
+        // a command is going to put the droplet on the cell
+        gv.snapshot_mut().droplets.get_mut(&id).unwrap().location = dead;
+        gv.tick();
+
+        match gv.mark_faulty(dead, &mut rng) {
+            Err(PlanError::ReplanError { location, time: 1 }) => assert_eq!(location, dead),
+            result => panic!("the command can't be replanned: {:?}", result),
+        }
+        assert!(gv.grid.get_cell(&dead).is_some());
+        assert_eq!(gv.stranded(&id), None);
+
+        // once it's there, the droplet is stranded instead
+        gv.execute();
+        assert_eq!(gv.mark_faulty(dead, &mut rng).unwrap(), vec![id]);
+        assert!(gv.grid.get_cell(&dead).is_none());
+        assert_eq!(gv.stranded(&id), Some(dead));
+    }
+
+    #[test]
+    fn test_planning_copy() {
+        let mut gv = GridView::new(Grid::rectangle(3, 5));
+        let mut rng = rng_from_seed(0);
+        let d = droplet_at(0, Location { y: 1, x: 0 });
+        let id = d.id;
+        gv.insert(d);
+        gv.tick();
+
+        let there = Location { y: 1, x: 4 };
+        gv.snapshot_mut().droplets.get_mut(&id).unwrap().destination = Some(there);
+        let paths = gv.route(&mut rng).unwrap();
+        gv.take_paths(&paths);
+        gv.execute();
+        gv.execute();
+
+        let mut copy = gv.planning_copy();
+        assert_eq!(copy.planned_steps(), gv.planned_steps());
+        assert_eq!(copy.snapshot().droplets[&id].location, there);
+
+        // planning on the copy leaves the original alone
+        let back = Location { y: 1, x: 0 };
+        copy.snapshot_mut().droplets.get_mut(&id).unwrap().destination = Some(back);
+        let paths = copy.route(&mut rng).unwrap();
+        copy.take_paths(&paths);
+        assert_eq!(copy.snapshot().droplets[&id].location, back);
+        assert!(copy.planned_steps() > gv.planned_steps());
+        assert_eq!(gv.snapshot().droplets[&id].location, there);
+    }
+
+    #[test]
+    fn test_wash() {
+        // the wash droplets are thrown away in the reservoir at the end
+        let mut grid = Grid::rectangle(3, 5);
+        grid.vec[0][4].as_mut().unwrap().kind = CellKind::Reservoir;
+        let mut gv = GridView::new(grid);
+        let mut rng = rng_from_seed(0);
+        gv.set_compatibility(Compatibility {
+            incompatible: vec![("acid".into(), "base".into())],
+            penalty: None,
+        });
+        gv.wash = Some(Wash {
+            reservoir: Location { y: 0, x: 0 },
+            waste: Location { y: 0, x: 4 },
+            volume: 1.0,
+            max_cells: 2,
+        });
+        assert!(!gv.wash(&mut rng));
+
+        let mut acid = droplet_at(0, Location { y: 2, x: 0 });
+        acid.reagents.insert("acid".into());
+        let id = acid.id;
+        gv.insert(acid);
+        gv.tick();
+
+        // the acid dirties the whole bottom row
+        let dest = Location { y: 2, x: 4 };
+        gv.snapshot_mut().droplets.get_mut(&id).unwrap().destination = Some(dest);
+        let paths = gv.route(&mut rng).unwrap();
+        gv.take_paths(&paths);
+        gv.snapshot_mut().droplets.get_mut(&id).unwrap().destination = None;
+
+        // the wash goes after everything planned so far, but only so far
+        assert!(gv.wash(&mut rng));
+        while let ExecResponse::Step(_) = gv.execute() {}
+        assert!(gv.residue.at(&Location { y: 2, x: 2 }).is_some());
+        assert!(gv.wash(&mut rng));
+        while let ExecResponse::Step(_) = gv.execute() {}
+
+        // the cells next to the acid can't be reached without touching it
+        for x in 0..3 {
+            assert_eq!(gv.residue.at(&Location { y: 2, x }), None);
+        }
+        assert!(gv.residue.at(&Location { y: 2, x: 3 }).is_some());
+        assert_eq!(gv.snapshot().droplets.keys().collect::<Vec<_>>(), vec![&id]);
+        assert!(!gv.wash(&mut rng));
     }
 }
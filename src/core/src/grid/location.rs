@@ -1,6 +1,9 @@
+use std::collections::HashMap;
 use std::fmt;
 use std::ops::{Add, Sub};
 
+use ena::unify::{InPlaceUnificationTable, UnifyKey};
+
 #[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Serialize, Deserialize)]
 pub struct Location {
     pub y: i32,
@@ -91,63 +94,64 @@ impl fmt::Display for Location {
     }
 }
 
-#[cfg(test)]
-pub mod tests {
-    use super::*;
-    use std::collections::HashMap;
-
-    use ena::unify::{InPlaceUnificationTable, UnifyKey};
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+struct IntKey(u32);
 
-    #[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
-    struct IntKey(u32);
-
-    impl UnifyKey for IntKey {
-        type Value = ();
-        fn index(&self) -> u32 {
-            self.0
-        }
-        fn from_index(u: u32) -> IntKey {
-            IntKey(u)
-        }
-        fn tag() -> &'static str {
-            "IntKey"
-        }
+impl UnifyKey for IntKey {
+    type Value = ();
+    fn index(&self) -> u32 {
+        self.0
     }
+    fn from_index(u: u32) -> IntKey {
+        IntKey(u)
+    }
+    fn tag() -> &'static str {
+        "IntKey"
+    }
+}
 
-    pub fn connected_components<'a, I>(locs: I) -> HashMap<Location, u32>
-    where
-        I: Iterator<Item = Location>,
-    {
-        // inputs must be in row major order
-        let mut labels = HashMap::new();
-        let mut equivs = InPlaceUnificationTable::<IntKey>::new();
-
-        for loc in locs {
-            let l_north = labels.get(&loc.north()).cloned();
-            let l_west = labels.get(&loc.west()).cloned();
-
-            let label = match (l_north, l_west) {
-                (None, None) => equivs.new_key(()),
-                (None, Some(l)) => l,
-                (Some(l), None) => l,
-                (Some(l1), Some(l2)) => {
-                    equivs.union(l1, l2);
-                    l2 // could be l1 too, doesn't matter
-                }
-            };
-
-            labels.insert(loc, label);
-        }
+/// Labels each location with the connected component it belongs to. Two
+/// locations are connected if they are adjacent in a cardinal direction.
+///
+/// The locations must be given in row major order.
+pub fn connected_components<I>(locs: I) -> HashMap<Location, u32>
+where
+    I: Iterator<Item = Location>,
+{
+    // inputs must be in row major order
+    let mut labels = HashMap::new();
+    let mut equivs = InPlaceUnificationTable::<IntKey>::new();
+
+    for loc in locs {
+        let l_north = labels.get(&loc.north()).cloned();
+        let l_west = labels.get(&loc.west()).cloned();
+
+        let label = match (l_north, l_west) {
+            (None, None) => equivs.new_key(()),
+            (None, Some(l)) => l,
+            (Some(l), None) => l,
+            (Some(l1), Some(l2)) => {
+                equivs.union(l1, l2);
+                l2 // could be l1 too, doesn't matter
+            }
+        };
+
+        labels.insert(loc, label);
+    }
+
+    // return all the locations associated with their root key
+    labels
+        .iter()
+        .map(|(k, v)| {
+            let vv = equivs.find(*v).index();
+            (*k, vv)
+        })
+        .collect()
+}
 
-        // return all the locations associated with their root key
-        labels
-            .iter()
-            .map(|(k, v)| {
-                let vv = equivs.find(*v).index();
-                (*k, vv)
-            })
-            .collect()
-    }
+#[cfg(test)]
+pub mod tests {
+    use super::*;
 
     type Pt = (i32, i32);
     fn dist_to_box(p: Pt, c1: Pt, c2: Pt) -> i32 {
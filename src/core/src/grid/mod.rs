@@ -1,10 +1,27 @@
+pub mod actuate;
+mod bottleneck;
 mod droplet;
+mod footprint;
+mod generate;
 pub mod grid;
 pub mod gridview;
 mod location;
+mod orientation;
 mod parse;
+mod region;
+mod residue;
+pub mod wear;
 
+pub use self::actuate::{ActuationError, ActuationFrame};
+pub use self::bottleneck::Bottlenecks;
 pub use self::droplet::*;
-pub use self::grid::{Cell, Grid};
+pub use self::footprint::Footprint;
+pub use self::generate::{GenError, GenParams};
+pub use self::grid::{Cell, CellKind, Grid};
 pub use self::gridview::{ExecResponse, GridView, Snapshot};
-pub use self::location::Location;
+pub use self::location::{connected_components, Location};
+pub use self::orientation::Orientation;
+pub use self::parse::{Blob, InitialState, ParseError};
+pub use self::region::{Region, RegionError, Regions};
+pub use self::residue::{Compatibility, Reagent, Residue, Wash, WASH_PROCESS};
+pub use self::wear::Wear;
@@ -0,0 +1,139 @@
+use grid::{Footprint, Location};
+
+/// One of the eight ways to turn or flip a shape onto the board.
+///
+/// Offsets are transposed first, if at all, and then flipped. The default is
+/// the identity.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Orientation {
+    transpose: bool,
+    flip_y: bool,
+    flip_x: bool,
+}
+
+impl Orientation {
+    /// Every orientation, starting with the identity.
+    pub fn all() -> Vec<Orientation> {
+        let mut all = Vec::new();
+        for &transpose in &[false, true] {
+            for &flip_y in &[false, true] {
+                for &flip_x in &[false, true] {
+                    all.push(Orientation {
+                        transpose,
+                        flip_y,
+                        flip_x,
+                    });
+                }
+            }
+        }
+        all
+    }
+
+    pub fn is_identity(&self) -> bool {
+        *self == Orientation::default()
+    }
+
+    /// Whether this turns things on their side, swapping height and width.
+    pub fn transposes(&self) -> bool {
+        self.transpose
+    }
+
+    /// Turns an offset or direction.
+    pub fn apply(&self, offset: &Location) -> Location {
+        let (mut y, mut x) = if self.transpose {
+            (offset.x, offset.y)
+        } else {
+            (offset.y, offset.x)
+        };
+        if self.flip_y {
+            y = -y;
+        }
+        if self.flip_x {
+            x = -x;
+        }
+        Location { y, x }
+    }
+
+    /// The footprint turned this way, back at the origin.
+    pub fn footprint(&self, footprint: &Footprint) -> Footprint {
+        let offsets = footprint.offsets().into_iter().map(|off| self.apply(&off));
+        let (_, turned) = Footprint::from_locations(offsets).unwrap();
+        turned
+    }
+
+    /// The corner of a box of `dimensions` that ends up in the top left once
+    /// the box is turned this way.
+    pub fn anchor(&self, dimensions: &Location) -> Location {
+        let (h, w) = (dimensions.y - 1, dimensions.x - 1);
+        let corners = [
+            Location { y: 0, x: 0 },
+            Location { y: 0, x: w },
+            Location { y: h, x: 0 },
+            Location { y: h, x: w },
+        ];
+        *corners
+            .iter()
+            .min_by_key(|corner| {
+                let turned = self.apply(corner);
+                (turned.y, turned.x)
+            })
+            .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use util::collections::Set;
+
+    fn loc(y: i32, x: i32) -> Location {
+        Location { y, x }
+    }
+
+    #[test]
+    fn test_orientations() {
+        let all = Orientation::all();
+        assert!(all[0].is_identity());
+
+        // they all do something different
+        let turned: Set<_> = all.iter().map(|o| o.apply(&loc(1, 2))).collect();
+        assert_eq!(turned.len(), 8);
+
+        // a rectangle is the same flipped, but not turned on its side
+        let rect = Footprint::Rect(loc(1, 2));
+        let same = all.iter().filter(|o| o.footprint(&rect) == rect).count();
+        assert_eq!(same, 4);
+
+        // there are only 4 different 2x2 L's to turn an L into
+        let (_, l) = Footprint::from_locations(vec![loc(0, 0), loc(1, 0), loc(1, 1)]).unwrap();
+        let mut ls = Vec::new();
+        for o in &all {
+            let turned = o.footprint(&l);
+            if !ls.contains(&turned) {
+                ls.push(turned);
+            }
+        }
+        assert_eq!(ls.len(), 4);
+    }
+
+    #[test]
+    fn test_anchor() {
+        let dim = loc(2, 3);
+        for o in Orientation::all() {
+            // the anchor is where the top left of the turned box comes from
+            let anchor = o.anchor(&dim);
+            let turned: Vec<_> = Footprint::Rect(dim)
+                .offsets()
+                .iter()
+                .map(|off| &o.apply(off) - &o.apply(&anchor))
+                .collect();
+            assert!(turned.iter().all(|l| l.y >= 0 && l.x >= 0), "{:?}", o);
+        }
+        let flip_x = Orientation {
+            flip_x: true,
+            ..Orientation::default()
+        };
+        assert_eq!(flip_x.anchor(&dim), loc(0, 2));
+    }
+}
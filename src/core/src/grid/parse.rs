@@ -1,4 +1,11 @@
-use grid::Cell;
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read};
+
+use grid::location::connected_components;
+use grid::{Cell, CellKind, Footprint, Grid, Location};
+use util::collections::Map;
 
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
@@ -8,6 +15,12 @@ enum Mark {
     Empty,
     #[serde(rename = "a")]
     Auto,
+    #[serde(rename = "h")]
+    Heater,
+    #[serde(rename = "s")]
+    Sensor,
+    #[serde(rename = "r")]
+    Reservoir,
 }
 
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
@@ -37,13 +50,17 @@ where
         .iter()
         .map(|row| {
             row.iter()
-                .map(|ci: &CellIndex| match ci {
-                    &Marked(Empty) => None,
-                    &Marked(Auto) => {
-                        let pin = next_pin;
-                        next_pin += 1;
-                        Some(Cell { pin: pin })
-                    }
+                .map(|ci: &CellIndex| {
+                    let kind = match ci {
+                        &Marked(Empty) => return None,
+                        &Marked(Auto) => CellKind::Electrode,
+                        &Marked(Heater) => CellKind::Heater,
+                        &Marked(Sensor) => CellKind::Sensor,
+                        &Marked(Reservoir) => CellKind::Reservoir,
+                    };
+                    let pin = next_pin;
+                    next_pin += 1;
+                    Some(Cell { pin, kind })
                 })
                 .collect()
         })
@@ -61,7 +78,12 @@ where
             row.iter()
                 .map(|opt: &Option<Cell>| match opt {
                     &None => Marked(Empty),
-                    &Some(_) => Marked(Auto),
+                    &Some(cell) => match cell.kind {
+                        CellKind::Electrode => Marked(Auto),
+                        CellKind::Heater => Marked(Heater),
+                        CellKind::Sensor => Marked(Sensor),
+                        CellKind::Reservoir => Marked(Reservoir),
+                    },
                 })
                 .collect()
         })
@@ -70,119 +92,180 @@ where
     pg_vec.serialize(s)
 }
 
-#[cfg(test)]
-mod tests {
-
-    use super::*;
+//
+// ASCII boards
+//
+
+/// A board and the droplets initially sitting on it.
+///
+/// This can be read from an ASCII drawing of the board, one line per row:
+///
+/// - `.` is an electrode,
+/// - ` ` (a space) is a hole in the board, and
+/// - any alphanumeric character is an electrode covered by a droplet. All the
+///   cells with the same character make up one droplet, which must be
+///   connected.
+///
+/// So `".aa.."` is a row of 5 electrodes with a 1x2 droplet called `a` on it.
+/// Each droplet has a volume of 1.0 per electrode it covers.
+#[derive(Debug)]
+pub struct InitialState {
+    pub grid: Grid,
+    pub droplets: Map<char, Blob>,
+}
 
-    use glob::glob;
-    use std::fs::File;
+#[derive(Debug, Clone, PartialEq)]
+pub struct Blob {
+    pub location: Location,
+    pub footprint: Footprint,
+    pub volume: f64,
+}
 
-    use serde_json as sj;
+#[derive(Debug)]
+pub enum ParseError {
+    Io(io::Error),
+    UnexpectedChar { ch: char, location: Location },
+    NotConnected(char),
+    TooClose(char, char),
+}
 
-    use grid::{Grid, Location};
-    use std::collections::{HashMap, HashSet};
-
-    fn parse_strings(rows: &[&str]) -> (Grid, HashMap<char, Blob>) {
-        use grid::location::tests::connected_components;
-
-        let mut droplet_map = HashMap::new();
-        let mut cell_locs = HashSet::new();
-
-        for (i, row) in rows.iter().enumerate() {
-            for (j, ch) in row.chars().enumerate() {
-                // we think this is a droplet is it's alphanumeric
-                if ch.is_alphanumeric() {
-                    // add this location to those for this character
-                    let locs = droplet_map.entry(ch).or_insert_with(|| Vec::new());
-                    locs.push(Location {
-                        y: i as i32,
-                        x: j as i32,
-                    })
-                } else if ch == ' ' {
-                    continue;
-                } else {
-                    // this function should only be used in testing, so for now we just panic if the input isn't well formed.
-                    assert_eq!(ch, '.');
-                }
-
-                cell_locs.insert(Location {
-                    y: i as i32,
-                    x: j as i32,
-                });
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::ParseError::*;
+        match self {
+            Io(err) => write!(f, "{}", err),
+            UnexpectedChar { ch, location } => {
+                write!(f, "unexpected character '{}' at {}", ch, location)
             }
+            NotConnected(ch) => write!(f, "droplet '{}' is not connected", ch),
+            TooClose(ch1, ch2) => write!(f, "droplets '{}' and '{}' are touching", ch1, ch2),
         }
+    }
+}
 
-        let blob_map: HashMap<_, _> = droplet_map
-            .iter()
-            .map(|(&ch, locs)| {
-                // make sure it only has one connected component
-                let labels = connected_components(locs.iter().cloned());
-                assert!(labels.values().all(|v| *v == 0));
-                (ch, Blob::from_locations(&locs).expect("not a blob!"))
-            })
-            .collect();
-
-        let mut next_pin = 0;
-        let to_cell = |loc: Location| {
-            if cell_locs.contains(&loc) {
-                let pin = next_pin;
-                next_pin += 1;
-                Some(Cell { pin })
-            } else {
-                None
-            }
-        };
+impl Error for ParseError {}
 
-        let height = cell_locs.iter().map(|l| l.y as usize).max().unwrap_or(0) + 1;
-        let width = cell_locs.iter().map(|l| l.x as usize).max().unwrap_or(0) + 1;
-        let grid = Grid::from_function(to_cell, height, width);
+impl From<io::Error> for ParseError {
+    fn from(err: io::Error) -> Self {
+        ParseError::Io(err)
+    }
+}
+
+impl InitialState {
+    pub fn from_ascii(s: &str) -> Result<InitialState, ParseError> {
+        parse_ascii(s)
+    }
+
+    pub fn from_reader<R: Read>(mut reader: R) -> Result<InitialState, ParseError> {
+        let mut s = String::new();
+        reader.read_to_string(&mut s)?;
+        parse_ascii(&s)
+    }
+}
+
+impl Blob {
+    fn from_locations(ch: char, locs: &[Location]) -> Result<Blob, ParseError> {
+        // make sure it only has one connected component
+        let labels = connected_components(locs.iter().cloned());
+        if labels.values().collect::<HashSet<_>>().len() > 1 {
+            return Err(ParseError::NotConnected(ch));
+        }
+
+        // the character came from somewhere, so there's at least one location
+        let (location, footprint) = Footprint::from_locations(locs.iter().cloned()).unwrap();
+
+        Ok(Blob {
+            location,
+            footprint,
+            volume: locs.len() as f64,
+        })
+    }
 
-        (grid, blob_map)
+    /// The dimensions of the blob's bounding box.
+    pub fn dimensions(&self) -> Location {
+        self.footprint.dimensions()
     }
 
-    struct Blob {
-        location: Location,
-        dimensions: Location,
+    fn touches(&self, other: &Blob) -> bool {
+        let boxes = other.footprint.boxes(&other.location);
+        self.footprint.offsets().iter().any(|off| {
+            let loc = &self.location + off;
+            boxes
+                .iter()
+                .any(|&(corner1, corner2)| loc.min_distance_to_box(corner1, corner2) <= 0)
+        })
     }
+}
+
+pub fn parse_ascii(s: &str) -> Result<InitialState, ParseError> {
+    let mut droplet_locs = Map::new();
+    let mut cell_locs = HashSet::new();
 
-    impl Blob {
-        fn from_locations(locs: &[Location]) -> Option<Blob> {
+    for (i, row) in s.lines().enumerate() {
+        for (j, ch) in row.chars().enumerate() {
             let location = Location {
-                y: locs.iter().map(|l| l.y).min().unwrap_or(0),
-                x: locs.iter().map(|l| l.x).min().unwrap_or(0),
+                y: i as i32,
+                x: j as i32,
             };
-            let far_corner = Location {
-                y: locs.iter().map(|l| l.y).max().unwrap_or(0) + 1,
-                x: locs.iter().map(|l| l.x).max().unwrap_or(0) + 1,
-            };
-            let dimensions = &far_corner - &location;
-
-            let set1: HashSet<Location> = locs.iter().cloned().collect();
-            let mut set2 = HashSet::new();
-
-            // build set2 with all the locations the rectangle should have
-            for i in 0..dimensions.y {
-                for j in 0..dimensions.x {
-                    set2.insert(Location {
-                        y: location.y + i,
-                        x: location.x + j,
-                    });
-                }
+            // we think this is a droplet is it's alphanumeric
+            if ch.is_alphanumeric() {
+                // add this location to those for this character
+                let locs = droplet_locs.entry(ch).or_insert_with(Vec::new);
+                locs.push(location)
+            } else if ch == ' ' {
+                continue;
+            } else if ch != '.' {
+                return Err(ParseError::UnexpectedChar { ch, location });
             }
 
-            println!("{:?} =? {:?}", set1, set2);
-            if set1 == set2 {
-                Some(Blob {
-                    location,
-                    dimensions,
-                })
-            } else {
-                None
+            cell_locs.insert(location);
+        }
+    }
+
+    let mut droplets = Map::new();
+    for (&ch, locs) in droplet_locs.iter() {
+        let blob = Blob::from_locations(ch, locs)?;
+        for (&other_ch, other) in droplets.iter() {
+            if blob.touches(other) {
+                return Err(ParseError::TooClose(other_ch, ch));
             }
         }
+        droplets.insert(ch, blob);
     }
 
+    let mut next_pin = 0;
+    let to_cell = |loc: Location| {
+        if cell_locs.contains(&loc) {
+            let pin = next_pin;
+            next_pin += 1;
+            Some(Cell {
+                pin,
+                kind: CellKind::Electrode,
+            })
+        } else {
+            None
+        }
+    };
+
+    let height = cell_locs.iter().map(|l| l.y + 1).max().unwrap_or(0);
+    let width = cell_locs.iter().map(|l| l.x + 1).max().unwrap_or(0);
+    let grid = Grid::from_function(to_cell, height as usize, width as usize);
+
+    Ok(InitialState { grid, droplets })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use glob::glob;
+    use std::fs::File;
+
+    use serde_json as sj;
+
+    use grid::{Grid, Location};
+
     #[test]
     fn test_simple_parse() {
         let _: ParsedGridVec = sj::from_str(
@@ -212,15 +295,43 @@ mod tests {
         // test uneven string lengths with gaps
         let strs = vec![".....aa.....", "  ...aa...      ", ".bb.........  "];
 
-        let (grid, blobs) = parse_strings(&strs);
+        let state = parse_ascii(&strs.join("\n")).expect("parse failed");
+        let grid = state.grid;
+        let blobs = state.droplets;
 
         assert_eq!(blobs[&'a'].location, Location { y: 0, x: 5 });
-        assert_eq!(blobs[&'a'].dimensions, Location { y: 2, x: 2 });
+        assert_eq!(blobs[&'a'].dimensions(), Location { y: 2, x: 2 });
+        assert_eq!(blobs[&'a'].volume, 4.0);
 
         assert_eq!(blobs[&'b'].location, Location { y: 2, x: 1 });
-        assert_eq!(blobs[&'b'].dimensions, Location { y: 1, x: 2 });
+        assert_eq!(blobs[&'b'].dimensions(), Location { y: 1, x: 2 });
 
         assert_eq!(grid.max_height(), 3);
         assert_eq!(grid.max_width(), 12);
+        assert!(grid.get_cell(&Location { y: 1, x: 0 }).is_none());
+        assert!(grid.get_cell(&Location { y: 1, x: 2 }).is_some());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let parse = |strs: &[&str]| parse_ascii(&strs.join("\n")).map(|_| ());
+
+        // droplets don't have to be rectangles, just connected
+        let state = parse_ascii(&["..a.", ".aa."].join("\n")).unwrap();
+        assert_eq!(state.droplets[&'a'].dimensions(), Location { y: 2, x: 2 });
+        assert_eq!(state.droplets[&'a'].footprint.cell_count(), 3);
+
+        match parse(&["a.a."]) {
+            Err(ParseError::NotConnected('a')) => (),
+            r => panic!("expected NotConnected, got {:?}", r),
+        }
+        match parse(&["ab.."]) {
+            Err(ParseError::TooClose('a', 'b')) => (),
+            r => panic!("expected TooClose, got {:?}", r),
+        }
+        match parse(&["..", ".#"]) {
+            Err(ParseError::UnexpectedChar { ch: '#', .. }) => (),
+            r => panic!("expected UnexpectedChar, got {:?}", r),
+        }
     }
 }
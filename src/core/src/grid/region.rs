@@ -0,0 +1,177 @@
+use grid::{Grid, Location, WASH_PROCESS};
+use process::ProcessId;
+use util::collections::{Map, Set};
+
+/// A part of the board set aside for one process.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Region {
+    Rect {
+        location: Location,
+        dimensions: Location,
+    },
+    Cells(Vec<Location>),
+}
+
+impl Region {
+    pub fn locations(&self) -> Set<Location> {
+        match self {
+            Region::Rect {
+                location,
+                dimensions,
+            } => (0..dimensions.y)
+                .flat_map(|y| (0..dimensions.x).map(move |x| location + &Location { y, x }))
+                .collect(),
+            Region::Cells(cells) => cells.iter().cloned().collect(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub enum RegionError {
+    /// The cell isn't on the board, or another process already has it.
+    Unavailable(Location),
+    /// A corridor cell isn't in the region of the process sharing it.
+    NotOwned(Location),
+    NoRegion(ProcessId),
+}
+
+/// Who may use which parts of the board.
+///
+/// A process with a region places and routes only inside it. Everyone else
+/// stays out, except that they may route through the corridors a region
+/// shares. Nobody places commands on a corridor, since that would block it.
+#[derive(Debug, Default, Clone)]
+pub struct Regions {
+    owned: Map<ProcessId, Set<Location>>,
+    corridors: Set<Location>,
+}
+
+impl Regions {
+    pub fn is_empty(&self) -> bool {
+        self.owned.is_empty()
+    }
+
+    /// Reserves `region` on `grid` for the process `pid`.
+    pub fn reserve(&mut self, grid: &Grid, pid: ProcessId, region: &Region) -> Result<(), RegionError> {
+        let cells = region.locations();
+        for loc in &cells {
+            let taken = self.owned.values().any(|owned| owned.contains(loc));
+            if taken || grid.get_cell(loc).is_none() {
+                return Err(RegionError::Unavailable(*loc));
+            }
+        }
+        self.owned.entry(pid).or_default().extend(cells);
+        Ok(())
+    }
+
+    /// Lets other processes route through `corridor`, which has to be inside
+    /// the region of `pid`.
+    pub fn share(&mut self, pid: ProcessId, corridor: &Region) -> Result<(), RegionError> {
+        let owned = self.owned.get(&pid).ok_or(RegionError::NoRegion(pid))?;
+        let cells = corridor.locations();
+        if let Some(loc) = cells.iter().find(|loc| !owned.contains(loc)) {
+            return Err(RegionError::NotOwned(*loc));
+        }
+        self.corridors.extend(cells);
+        Ok(())
+    }
+
+    /// Gives up the region of `pid`, along with any corridors through it.
+    pub fn release(&mut self, pid: ProcessId) {
+        if let Some(owned) = self.owned.remove(&pid) {
+            self.corridors = self.corridors.difference(&owned).cloned().collect();
+        }
+    }
+
+    fn owner(&self, loc: &Location) -> Option<ProcessId> {
+        self.owned
+            .iter()
+            .find(|(_, owned)| owned.contains(loc))
+            .map(|(&pid, _)| pid)
+    }
+
+    /// Whether droplets of `pid` may pass over `loc`. Wash droplets may go
+    /// anywhere, since residue inside a region needs cleaning too.
+    pub fn can_route(&self, pid: ProcessId, loc: &Location) -> bool {
+        if pid == WASH_PROCESS {
+            return true;
+        }
+        match self.owner(loc) {
+            Some(owner) => owner == pid || self.corridors.contains(loc),
+            // processes with a region stay inside it or its corridors
+            None => !self.owned.contains_key(&pid),
+        }
+    }
+
+    /// Whether `pid` may put a command on `loc`.
+    pub fn can_place(&self, pid: ProcessId, loc: &Location) -> bool {
+        !self.corridors.contains(loc) && match self.owner(loc) {
+            Some(owner) => owner == pid,
+            None => !self.owned.contains_key(&pid),
+        }
+    }
+
+    /// The part of `grid` that `pid` may place commands on.
+    pub fn placement_grid(&self, grid: &Grid, pid: ProcessId) -> Grid {
+        let mut masked = grid.clone();
+        for (loc, _) in grid.locations() {
+            if !self.can_place(pid, &loc) {
+                masked.remove(&loc);
+            }
+        }
+        masked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn loc(y: i32, x: i32) -> Location {
+        Location { y, x }
+    }
+
+    #[test]
+    fn test_regions() {
+        let grid = Grid::rectangle(4, 6);
+        let mut regions = Regions::default();
+
+        let left = Region::Rect {
+            location: loc(0, 0),
+            dimensions: loc(4, 3),
+        };
+        regions.reserve(&grid, 0, &left).unwrap();
+
+        // can't take somebody else's cells, or cells off the board
+        let overlap = Region::Cells(vec![loc(0, 2), loc(0, 3)]);
+        match regions.reserve(&grid, 1, &overlap) {
+            Err(RegionError::Unavailable(l)) => assert_eq!(l, loc(0, 2)),
+            r => panic!("expected Unavailable, got {:?}", r),
+        }
+        let off_board = Region::Cells(vec![loc(4, 4)]);
+        assert!(regions.reserve(&grid, 1, &off_board).is_err());
+
+        // the owner is confined, others are kept out
+        assert!(regions.can_place(0, &loc(1, 1)));
+        assert!(!regions.can_route(0, &loc(1, 4)));
+        assert!(!regions.can_route(1, &loc(1, 1)));
+        assert!(regions.can_place(1, &loc(1, 4)));
+        // except for washing
+        assert!(regions.can_route(WASH_PROCESS, &loc(1, 1)));
+
+        // corridors let others through, but not stop
+        let corridor = Region::Cells(vec![loc(3, 0), loc(3, 1), loc(3, 2)]);
+        regions.share(0, &corridor).unwrap();
+        assert!(regions.can_route(1, &loc(3, 1)));
+        assert!(!regions.can_place(1, &loc(3, 1)));
+        assert!(!regions.can_place(0, &loc(3, 1)));
+        assert!(regions.share(1, &corridor).is_err());
+
+        let masked = regions.placement_grid(&grid, 0);
+        assert_eq!(masked.locations().count(), 9);
+
+        regions.release(0);
+        assert!(regions.is_empty());
+        assert!(regions.can_place(1, &loc(3, 1)));
+    }
+}
@@ -0,0 +1,241 @@
+use grid::{CellKind, Droplet, DropletId, Grid, Location};
+use process::ProcessId;
+use util::collections::{Map, Set};
+
+/// What a droplet is made of, named however the client likes.
+pub type Reagent = String;
+
+/// Which reagents the client says must not touch each other's residue.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Compatibility {
+    /// Pairs of reagents that contaminate each other, in either order.
+    pub incompatible: Vec<(Reagent, Reagent)>,
+    /// What going over a cell with incompatible residue costs, in the same
+    /// units as wear. Without one, those cells are off limits.
+    #[serde(default)]
+    pub penalty: Option<u32>,
+}
+
+impl Compatibility {
+    pub fn compatible(&self, a: &Reagent, b: &Reagent) -> bool {
+        !self
+            .incompatible
+            .iter()
+            .any(|(x, y)| (x == a && y == b) || (x == b && y == a))
+    }
+
+    /// Whether `reagent` is incompatible with anything at all.
+    pub fn matters(&self, reagent: &Reagent) -> bool {
+        self.incompatible
+            .iter()
+            .any(|(x, y)| x == reagent || y == reagent)
+    }
+}
+
+/// Wash droplets belong to this made up process, so they never get mixed up
+/// with a real process's droplets.
+pub const WASH_PROCESS: ProcessId = ProcessId::MAX;
+
+/// Where to get wash droplets from and where to throw them away, so residue
+/// can be cleaned up between commands; see `GridView::wash`. The waste has to
+/// be a reservoir, since that's where wash droplets are drained.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Wash {
+    pub reservoir: Location,
+    pub waste: Location,
+    pub volume: f64,
+    /// The most dirty cells one wash goes after, so whatever comes next
+    /// doesn't wait long. The rest are left for the next wash.
+    pub max_cells: usize,
+}
+
+impl Wash {
+    pub fn is_wash(id: &DropletId) -> bool {
+        id.process_id == WASH_PROCESS
+    }
+
+    /// Washes from `reservoir` to `waste` on `grid`, with droplets that
+    /// cover one electrode and go after a handful of cells at a time.
+    pub fn new(grid: &Grid, reservoir: Location, waste: Location) -> Wash {
+        Wash {
+            reservoir,
+            waste,
+            volume: grid.electrode_volume().unwrap_or(1.0),
+            max_cells: 8,
+        }
+    }
+
+    /// Washes from the first reservoir on `grid` to the last one. Boards
+    /// with fewer than two reservoirs don't get washed.
+    pub fn from_grid(grid: &Grid) -> Option<Wash> {
+        let reservoirs: Vec<Location> = grid
+            .locations()
+            .filter(|(_, cell)| cell.kind == CellKind::Reservoir)
+            .map(|(loc, _)| loc)
+            .collect();
+        match (reservoirs.first(), reservoirs.last()) {
+            (Some(&reservoir), Some(&waste)) if reservoir != waste => {
+                Some(Wash::new(grid, reservoir, waste))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Which reagents have touched each cell, and the last step they did.
+#[derive(Debug, Default, Clone)]
+pub struct Residue {
+    pub compatibility: Compatibility,
+    cells: Map<Location, Map<Reagent, usize>>,
+}
+
+impl Residue {
+    /// Notes that `droplet` is sitting where it is at step `time`.
+    pub fn record(&mut self, droplet: &Droplet, time: usize) {
+        // everything is kept, since the table may change later
+        if droplet.reagents.is_empty() {
+            return;
+        }
+        for loc in droplet.locations() {
+            let cell = self.cells.entry(loc).or_default();
+            for reagent in &droplet.reagents {
+                let last = cell.entry(reagent.clone()).or_insert(time);
+                *last = (*last).max(time);
+            }
+        }
+    }
+
+    /// Cleans whatever residue is under a wash droplet.
+    pub fn wash(&mut self, droplet: &Droplet) {
+        for loc in droplet.locations() {
+            self.cells.remove(&loc);
+        }
+    }
+
+    /// The cells with residue on them that something could mind.
+    pub fn dirty_cells(&self) -> Vec<Location> {
+        self.cells
+            .iter()
+            .filter(|(_, left)| left.keys().any(|r| self.compatibility.matters(r)))
+            .map(|(loc, _)| *loc)
+            .collect()
+    }
+
+    /// The reagents that have been on `loc`, along with the last step each
+    /// one was there.
+    pub fn at(&self, loc: &Location) -> Option<&Map<Reagent, usize>> {
+        self.cells.get(loc)
+    }
+
+    /// Whether the residue on `loc` would contaminate a droplet made of
+    /// `reagents`. A droplet can't be contaminated by what's already in it,
+    /// so a mix of incompatible reagents can go over its own trail.
+    pub fn contaminates(&self, loc: &Location, reagents: &Set<Reagent>) -> bool {
+        let residue = match self.at(loc) {
+            Some(residue) => residue,
+            None => return false,
+        };
+        residue.keys().filter(|left| !reagents.contains(*left)).any(|left| {
+            reagents
+                .iter()
+                .any(|reagent| !self.compatibility.compatible(left, reagent))
+        })
+    }
+
+    /// The extra cost of a droplet made of `reagents` going over `cells`, or
+    /// `None` if it may not.
+    pub fn cost<I>(&self, reagents: &Set<Reagent>, cells: I) -> Option<u32>
+    where
+        I: IntoIterator<Item = Location>,
+    {
+        let matters = reagents.iter().any(|r| self.compatibility.matters(r));
+        if !matters || self.cells.is_empty() {
+            return Some(0);
+        }
+        let dirty = cells
+            .into_iter()
+            .filter(|loc| self.contaminates(loc, reagents))
+            .count() as u32;
+        match self.compatibility.penalty {
+            _ if dirty == 0 => Some(0),
+            Some(penalty) => Some(penalty * dirty),
+            None => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use grid::DropletId;
+
+    fn loc(y: i32, x: i32) -> Location {
+        Location { y, x }
+    }
+
+    fn reagents(names: &[&str]) -> Set<Reagent> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn test_residue() {
+        let mut residue = Residue::default();
+        residue.compatibility.incompatible = vec![("acid".into(), "base".into())];
+
+        let id = DropletId {
+            id: 0,
+            process_id: 0,
+        };
+        let mut acid = Droplet::new(id, 1.0, loc(0, 0), loc(1, 2));
+        acid.reagents = reagents(&["acid"]);
+        residue.record(&acid, 3);
+        acid.location = loc(1, 0);
+        residue.record(&acid, 5);
+
+        assert_eq!(residue.at(&loc(0, 1)).unwrap()["acid"], 3);
+        assert_eq!(residue.at(&loc(1, 1)).unwrap()["acid"], 5);
+        assert_eq!(residue.at(&loc(2, 0)), None);
+
+        // either way around, and only where the acid has been
+        let base = reagents(&["base"]);
+        assert!(residue.contaminates(&loc(0, 0), &base));
+        assert!(!residue.contaminates(&loc(2, 0), &base));
+        assert!(!residue.contaminates(&loc(0, 0), &reagents(&["acid", "water"])));
+        // nor does a droplet that has the acid in it already
+        assert!(!residue.contaminates(&loc(0, 0), &reagents(&["acid", "base"])));
+
+        let cells = vec![loc(0, 0), loc(0, 1), loc(2, 0)];
+        assert_eq!(residue.cost(&base, cells.clone()), None);
+        assert_eq!(residue.cost(&reagents(&["water"]), cells.clone()), Some(0));
+        residue.compatibility.penalty = Some(10);
+        assert_eq!(residue.cost(&base, cells), Some(20));
+
+        // water doesn't bother anybody, so it doesn't need washing
+        let mut water = Droplet::new(id, 1.0, loc(2, 0), loc(1, 1));
+        water.reagents = reagents(&["water"]);
+        residue.record(&water, 6);
+        assert_eq!(residue.dirty_cells().len(), 4);
+
+        let wash = Droplet::new(id, 1.0, loc(1, 0), loc(1, 3));
+        residue.wash(&wash);
+        assert_eq!(residue.at(&loc(1, 1)), None);
+        assert_eq!(residue.dirty_cells(), vec![loc(0, 0), loc(0, 1)]);
+
+        // boards wash between their reservoirs, if they have two
+        let mut grid = Grid::rectangle(3, 3);
+        assert_eq!(Wash::from_grid(&grid), None);
+        for &(y, x) in &[(0, 0), (2, 2)] {
+            grid.vec[y][x].as_mut().unwrap().kind = CellKind::Reservoir;
+        }
+        let wash = Wash::from_grid(&grid).unwrap();
+        assert_eq!((wash.reservoir, wash.waste), (loc(0, 0), loc(2, 2)));
+
+        // residue is kept while nothing minds it, in case that changes
+        let mut residue = Residue::default();
+        residue.record(&acid, 7);
+        assert_eq!(residue.cost(&base, vec![loc(1, 0)]), Some(0));
+        residue.compatibility.incompatible = vec![("acid".into(), "base".into())];
+        assert_eq!(residue.cost(&base, vec![loc(1, 0)]), None);
+    }
+}
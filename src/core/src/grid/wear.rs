@@ -0,0 +1,154 @@
+use serde_json;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use grid::Location;
+
+/// How many times each electrode has been actuated.
+///
+/// The counts are kept in the same shape as the board, so they can be saved
+/// in a sidecar file next to the architecture file and picked up again on the
+/// next run.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Wear {
+    counts: Vec<Vec<u64>>,
+    /// The highest of `counts`, kept up to date since every penalty needs it.
+    #[serde(skip)]
+    max: u64,
+    /// How hard to avoid worn electrodes, in units of routing cost. The most
+    /// worn electrode costs this much extra to use; 0 ignores wear entirely.
+    #[serde(skip)]
+    pub weight: u32,
+}
+
+impl Wear {
+    /// The sidecar file for an architecture file, so `arch01.json` keeps its
+    /// wear in `arch01.wear.json`.
+    pub fn sidecar_path(arch_path: &Path) -> PathBuf {
+        let stem = arch_path
+            .file_stem()
+            .map_or("arch".into(), |s| s.to_string_lossy().into_owned());
+        arch_path.with_file_name(format!("{}.wear.json", stem))
+    }
+
+    /// Reads the counts from `path`. A missing file just means nothing has
+    /// worn yet.
+    pub fn load(path: &Path) -> io::Result<Wear> {
+        match File::open(path) {
+            Ok(reader) => {
+                let mut wear: Wear = serde_json::from_reader(reader).map_err(io::Error::from)?;
+                wear.max = wear
+                    .counts
+                    .iter()
+                    .flat_map(|row| row.iter())
+                    .cloned()
+                    .max()
+                    .unwrap_or(0);
+                Ok(wear)
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(Wear::default()),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let writer = File::create(path)?;
+        serde_json::to_writer(writer, self).map_err(io::Error::from)
+    }
+
+    pub fn count(&self, loc: &Location) -> u64 {
+        if loc.x < 0 || loc.y < 0 {
+            return 0;
+        }
+        self.counts
+            .get(loc.y as usize)
+            .and_then(|row| row.get(loc.x as usize))
+            .cloned()
+            .unwrap_or(0)
+    }
+
+    pub fn max_count(&self) -> u64 {
+        self.max
+    }
+
+    /// Counts one actuation for each of the locations.
+    pub fn record<I>(&mut self, locs: I)
+    where
+        I: IntoIterator<Item = Location>,
+    {
+        for loc in locs {
+            assert!(loc.x >= 0 && loc.y >= 0);
+            let (i, j) = (loc.y as usize, loc.x as usize);
+            if self.counts.len() <= i {
+                self.counts.resize(i + 1, Vec::new());
+            }
+            let row = &mut self.counts[i];
+            if row.len() <= j {
+                row.resize(j + 1, 0);
+            }
+            row[j] += 1;
+            self.max = self.max.max(row[j]);
+        }
+    }
+
+    /// The extra cost of using the electrode at `loc`, scaled so the most worn
+    /// electrode costs `weight`.
+    pub fn penalty(&self, loc: &Location) -> u32 {
+        if self.weight == 0 || self.max == 0 {
+            return 0;
+        }
+        (self.weight as u64 * self.count(loc) / self.max) as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn loc(y: i32, x: i32) -> Location {
+        Location { y, x }
+    }
+
+    #[test]
+    fn test_sidecar_path() {
+        let path = Wear::sidecar_path(Path::new("tests/arches/arch01.json"));
+        assert_eq!(path, Path::new("tests/arches/arch01.wear.json"));
+    }
+
+    #[test]
+    fn test_penalty() {
+        let mut wear = Wear::default();
+        wear.record(vec![loc(0, 0), loc(0, 0), loc(1, 2), loc(0, 0), loc(0, 0)]);
+
+        assert_eq!(wear.count(&loc(0, 0)), 4);
+        assert_eq!(wear.count(&loc(1, 2)), 1);
+        assert_eq!(wear.count(&loc(5, 5)), 0);
+        assert_eq!(wear.max_count(), 4);
+
+        // no weight, no penalty
+        assert_eq!(wear.penalty(&loc(0, 0)), 0);
+
+        wear.weight = 100;
+        assert_eq!(wear.penalty(&loc(0, 0)), 100);
+        assert_eq!(wear.penalty(&loc(1, 2)), 25);
+        assert_eq!(wear.penalty(&loc(1, 1)), 0);
+    }
+
+    #[test]
+    fn test_save_load() {
+        let path = env::temp_dir().join("puddle-test-wear.json");
+
+        let mut wear = Wear::default();
+        wear.record(vec![loc(0, 1), loc(2, 0)]);
+        wear.save(&path).unwrap();
+
+        let loaded = Wear::load(&path).unwrap();
+        assert_eq!(wear, loaded);
+        assert_eq!(loaded.max_count(), 1);
+
+        let missing = env::temp_dir().join("puddle-test-wear-missing.json");
+        assert_eq!(Wear::load(&missing).unwrap(), Wear::default());
+    }
+}
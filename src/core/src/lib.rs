@@ -30,5 +30,15 @@ mod plan;
 mod process;
 mod util;
 
-pub use grid::{DropletId, DropletInfo, Grid, Location};
+pub use grid::{
+    ActuationError, ActuationFrame, Blob, Bottlenecks, CellKind, Compatibility, Droplet, DropletId,
+    DropletInfo, Footprint, GenError, GenParams, Grid, InitialState, Location, ParseError, Reagent,
+    Region, RegionError, Regions, Residue, Snapshot, Wash, Wear, WASH_PROCESS,
+};
+pub use plan::{
+    builtin_cost_model, builtin_placer, builtin_router, BatchLimits, ConflictBased, CostModel,
+    Costs, Distance, Energy, FirstFit, NearHeaters, Path, PlacementWeights, Placer, PlanError,
+    Prioritized, RouteFailure, Router, BUILTIN_COST_MODELS, BUILTIN_PLACERS, BUILTIN_ROUTERS,
+    MOVE_COST, STAY_COST,
+};
 pub use process::*;
@@ -0,0 +1,196 @@
+use command::Command;
+use grid::{DropletId, GridView};
+use process::{PuddleError, PuddleResult};
+
+type Build = Box<Fn(&GridView) -> PuddleResult<Box<Command>> + Send>;
+
+/// A command that hasn't been built yet. Building it may need droplets that
+/// only exist once the commands before it are planned, and it may get built
+/// more than once while the batch looks for a good order.
+pub struct Pending {
+    inputs: Vec<DropletId>,
+    outputs: Vec<DropletId>,
+    build: Build,
+}
+
+impl Pending {
+    pub fn new<C, F>(inputs: &[DropletId], outputs: &[DropletId], build: F) -> Pending
+    where
+        C: Command + 'static,
+        F: Fn(&GridView) -> PuddleResult<C> + Send + 'static,
+    {
+        Pending {
+            inputs: inputs.to_vec(),
+            outputs: outputs.to_vec(),
+            build: Box::new(move |gv| Ok(Box::new(build(gv)?) as Box<Command>)),
+        }
+    }
+
+    pub fn inputs(&self) -> &[DropletId] {
+        &self.inputs
+    }
+
+    pub fn outputs(&self) -> &[DropletId] {
+        &self.outputs
+    }
+
+    /// Builds the command, unless the board has stopped and it could never
+    /// run.
+    pub fn build(&self, gv: &GridView) -> PuddleResult<Box<Command>> {
+        if let Some(err) = gv.failure() {
+            return Err(PuddleError::ActuationError(err.clone()));
+        }
+        (self.build)(gv)
+    }
+}
+
+/// How hard to look for a better order to plan a batch in.
+#[derive(Debug, Clone)]
+pub struct BatchLimits {
+    /// The most orders to try, counting the one the batch came in.
+    pub orders: usize,
+    /// Once this many steps have been planned across the orders tried, stop
+    /// and go with the best one so far. That's the order the batch came in if
+    /// nothing else did better. Unlike a timeout, this stops at the same
+    /// order every time, so seeded runs plan the same.
+    pub steps: usize,
+}
+
+impl Default for BatchLimits {
+    fn default() -> BatchLimits {
+        BatchLimits {
+            orders: 24,
+            steps: 2000,
+        }
+    }
+}
+
+/// The orders `batch` can be planned in without using a droplet before the
+/// command that makes it, starting with the order it came in. There are at
+/// most `max` of them.
+pub fn orders(batch: &[Pending], max: usize) -> Vec<Vec<usize>> {
+    // a command has to wait for every earlier one that makes its inputs
+    let after: Vec<Vec<usize>> = batch
+        .iter()
+        .enumerate()
+        .map(|(j, later)| {
+            (0..j)
+                .filter(|&i| batch[i].outputs.iter().any(|id| later.inputs.contains(id)))
+                .collect()
+        })
+        .collect();
+
+    fn extend(
+        after: &[Vec<usize>],
+        order: &mut Vec<usize>,
+        orders: &mut Vec<Vec<usize>>,
+        max: usize,
+    ) {
+        if order.len() == after.len() {
+            orders.push(order.clone());
+            return;
+        }
+        for next in 0..after.len() {
+            if orders.len() >= max {
+                return;
+            }
+            let ready = !order.contains(&next) && after[next].iter().all(|i| order.contains(i));
+            if ready {
+                order.push(next);
+                extend(after, order, orders, max);
+                order.pop();
+            }
+        }
+    }
+
+    let mut orders = Vec::new();
+    extend(&after, &mut Vec::new(), &mut orders, max);
+    orders
+}
+
+/// Tries each of `orders` with `attempt`, which says how many steps it
+/// planned and whether the order worked, and returns the index of the
+/// working order that took the fewest. Ties go to the earlier order. Orders
+/// are tried until `budget` steps have been planned, but the first order is
+/// always tried.
+pub fn best_order<F>(orders: &[Vec<usize>], budget: usize, mut attempt: F) -> Option<usize>
+where
+    F: FnMut(&[usize]) -> (usize, bool),
+{
+    let mut spent = 0;
+    let mut best: Option<(usize, usize)> = None;
+    for (i, order) in orders.iter().enumerate() {
+        if i > 0 && spent >= budget {
+            debug!("Ran out of steps after trying {} of {} orders", i, orders.len());
+            break;
+        }
+        let (steps, worked) = attempt(order);
+        spent += steps;
+        if worked {
+            trace!("order {:?} takes {} steps", order, steps);
+            let better = match best {
+                Some((best_steps, _)) => steps < best_steps,
+                None => true,
+            };
+            if better {
+                best = Some((steps, i));
+            }
+        }
+    }
+    best.map(|(_, i)| i)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use command::Move;
+    use grid::Location;
+
+    fn id(id: usize) -> DropletId {
+        DropletId { id, process_id: 0 }
+    }
+
+    fn pending(inputs: &[usize], outputs: &[usize]) -> Pending {
+        let inputs: Vec<_> = inputs.iter().map(|&i| id(i)).collect();
+        let outputs: Vec<_> = outputs.iter().map(|&i| id(i)).collect();
+        let (i, o) = (inputs[0], outputs[0]);
+        Pending::new(&inputs, &outputs, move |_| Move::new(i, Location { y: 0, x: 0 }, o))
+    }
+
+    #[test]
+    fn test_orders() {
+        // 0 -> 1 and 2 -> 3 are chains, but they don't care about each other
+        let batch = vec![
+            pending(&[0], &[1]),
+            pending(&[1], &[2]),
+            pending(&[10], &[11]),
+            pending(&[11], &[12]),
+        ];
+        let orders = orders(&batch, 100);
+        assert_eq!(orders[0], vec![0, 1, 2, 3]);
+        // pick 2 of the 4 spots for the first chain
+        assert_eq!(orders.len(), 6);
+        for order in &orders {
+            let at = |i| order.iter().position(|&j| j == i).unwrap();
+            assert!(at(0) < at(1) && at(2) < at(3), "{:?}", order);
+        }
+
+        assert_eq!(super::orders(&batch, 2).len(), 2);
+    }
+
+    #[test]
+    fn test_best_order() {
+        let orders = vec![vec![0, 1], vec![1, 0], vec![0, 1]];
+        let steps = |order: &[usize]| if order[0] == 1 { (5, true) } else { (8, true) };
+        assert_eq!(best_order(&orders, 100, steps), Some(1));
+
+        // the first one always gets a try, and failures count against the budget
+        assert_eq!(best_order(&orders, 0, steps), Some(0));
+        assert_eq!(best_order(&orders, 8, steps), Some(0));
+        assert_eq!(best_order(&orders, 100, |_| (3, false)), None);
+        let fails_first = |order: &[usize]| (8, order[0] == 1);
+        assert_eq!(best_order(&orders, 8, fails_first), None);
+        assert_eq!(best_order(&orders, 9, fails_first), Some(1));
+    }
+}
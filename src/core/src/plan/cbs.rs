@@ -0,0 +1,301 @@
+use grid::{Droplet, DropletId, Grid, Location, Regions, Residue};
+use plan::minheap::MinHeap;
+use util::collections::{Map, Set};
+
+use super::cost::Costs;
+use super::route::{route_one, with_residue, Cost, NextVec, Node, Path, RouteFailure, Time};
+
+type Conflict = [(DropletId, Node); 2];
+
+/// One node of the constraint tree: the places each droplet may not be, the
+/// best paths that respect them, and where those paths still collide.
+struct Constrained {
+    constraints: Map<DropletId, Set<Node>>,
+    paths: Map<DropletId, Path>,
+    conflicts: Vec<Conflict>,
+}
+
+impl Constrained {
+    fn new(
+        droplets: &Map<DropletId, Droplet>,
+        constraints: Map<DropletId, Set<Node>>,
+        paths: Map<DropletId, Path>,
+    ) -> Constrained {
+        let conflicts = conflicts(droplets, &paths);
+        Constrained {
+            constraints,
+            paths,
+            conflicts,
+        }
+    }
+
+    /// Nodes with fewer conflicts are closer to a routing that works, so
+    /// they go first, and then the ones with shorter paths.
+    fn cost(&self) -> (usize, usize) {
+        let length = self.paths.values().map(|p| p.len()).sum();
+        (self.conflicts.len(), length)
+    }
+}
+
+/// Conflict-based search.
+///
+/// Each droplet is routed on its own, and whenever two of the paths collide,
+/// the search splits in two: one side forbids the first droplet from being
+/// where it was when they collided, the other forbids the second. Any
+/// routing that works has to respect at least one of those, so if every
+/// branch runs out, there is no routing within `horizon` steps. `budget`
+/// limits how many branches to look at before giving up.
+pub fn route_cbs(
+    droplets: &Map<DropletId, Droplet>,
+    grid: &Grid,
+    costs: &Costs,
+    regions: &Regions,
+    residue: &Residue,
+    horizon: Time,
+    budget: usize,
+) -> Result<Map<DropletId, Path>, RouteFailure> {
+    let route = |id: &DropletId, forbidden: &Set<Node>, paths: &Map<DropletId, Path>| {
+        let others: Vec<_> = paths
+            .iter()
+            .filter(|&(other, _)| other != id)
+            .map(|(other, path)| (&droplets[other], path))
+            .collect();
+        let droplet = &droplets[id];
+        let offsets = droplet.footprint.offsets();
+        let allowed = |loc: &Location| {
+            *loc == droplet.location
+                || offsets
+                    .iter()
+                    .all(|off| regions.can_route(id.process_id, &(loc + off)))
+        };
+        let expand = |node: &Node| {
+            let mut next = node.expand(grid, costs, &offsets);
+            next.retain(|(_, n)| allowed(&n.location));
+            with_residue(next, droplet, &offsets, residue)
+        };
+        route_constrained(droplet, horizon, costs, forbidden, &others, expand)
+    };
+
+    let mut paths = Map::new();
+    for id in droplets.keys() {
+        let path = route(id, &Set::new(), &paths).ok_or(RouteFailure::NoRoute)?;
+        paths.insert(*id, path);
+    }
+    let root = Constrained::new(droplets, Map::new(), paths);
+
+    // the heap only holds indices, since the tree nodes aren't comparable
+    let mut tree = vec![root];
+    let mut todo: MinHeap<(usize, usize), usize> = MinHeap::new();
+    todo.push(tree[0].cost(), 0);
+    // the same constraints can come up down different branches
+    let mut seen = Set::new();
+
+    let mut expanded = 0;
+    while let Some((_, i)) = todo.pop() {
+        let conflict = match tree[i].conflicts.first() {
+            None => return Ok(tree.swap_remove(i).paths),
+            Some(&conflict) => conflict,
+        };
+
+        expanded += 1;
+        if expanded > budget {
+            debug!("Gave up routing after looking at {} branches", budget);
+            return Err(RouteFailure::GaveUp);
+        }
+        trace!("branching on conflict {:?}", conflict);
+
+        for &(id, node) in &conflict {
+            let mut constraints = tree[i].constraints.clone();
+            constraints.entry(id).or_default().insert(node);
+            if !seen.insert(constraints.clone()) {
+                continue;
+            }
+
+            // only the droplet that got the new constraint has to move
+            let path = route(&id, &constraints[&id], &tree[i].paths);
+            if let Some(path) = path {
+                let mut paths = tree[i].paths.clone();
+                paths.insert(id, path);
+                let child = Constrained::new(droplets, constraints, paths);
+                todo.push(child.cost(), tree.len());
+                tree.push(child);
+            }
+        }
+    }
+
+    Err(RouteFailure::NoRoute)
+}
+
+/// How much it costs to get in the way of another droplet's path. This
+/// isn't forbidden, but it will have to be sorted out later, so it's
+/// better to go around if that's not too far.
+const CONFLICT_COST: Cost = 1000;
+
+/// Routes a single droplet, staying off the `forbidden` nodes and out of the
+/// way of the `others` if it can. `expand` says where it may go next from a
+/// node, and what that costs.
+fn route_constrained<F>(
+    droplet: &Droplet,
+    horizon: Time,
+    costs: &Costs,
+    forbidden: &Set<Node>,
+    others: &[(&Droplet, &Path)],
+    expand: F,
+) -> Option<Path>
+where
+    F: Fn(&Node) -> NextVec,
+{
+    let start = Node {
+        location: droplet.location,
+        time: 0,
+    };
+    if forbidden.contains(&start) {
+        return None;
+    }
+
+    let in_the_way = |node: &Node| {
+        others.iter().any(|(other, path)| {
+            let t = node.time as usize;
+            (t.saturating_sub(1)..t + 2).any(|t| {
+                let other_loc = path[t.min(path.len() - 1)];
+                touching(droplet, &node.location, other, &other_loc)
+            })
+        })
+    };
+    let dest = droplet.destination.unwrap_or(droplet.location);
+
+    route_one(
+        droplet,
+        horizon,
+        costs,
+        |node| {
+            let mut next = expand(node);
+            next.retain(|(_, n)| n.time <= horizon && !forbidden.contains(n));
+            for (cost, n) in &mut next {
+                if in_the_way(n) {
+                    *cost += CONFLICT_COST;
+                }
+            }
+            next
+        },
+        // it can only stop once nothing would make it move again
+        |node| {
+            node.location == dest
+                && !forbidden
+                    .iter()
+                    .any(|f| f.location == dest && f.time >= node.time)
+        },
+    )
+}
+
+/// Finds every time two droplets get too close, earliest first, along with
+/// where each of them was. Droplets are too close if they touch within a
+/// step of each other, just like the prioritized router avoids.
+fn conflicts(droplets: &Map<DropletId, Droplet>, paths: &Map<DropletId, Path>) -> Vec<Conflict> {
+    let at = |path: &Path, t: usize| Node {
+        location: path[t.min(path.len() - 1)],
+        time: t as Time,
+    };
+    let max_len = paths.values().map(|p| p.len()).max().unwrap_or(0);
+    let ids: Vec<_> = paths.keys().collect();
+
+    let mut conflicts = Vec::new();
+    for t in 0..max_len {
+        for (i, &id1) in ids.iter().enumerate() {
+            for &id2 in &ids[i + 1..] {
+                let (d1, d2) = (&droplets[id1], &droplets[id2]);
+                let (p1, p2) = (&paths[id1], &paths[id2]);
+                for &(t1, t2) in &[(t, t), (t, t + 1), (t + 1, t)] {
+                    let (n1, n2) = (at(p1, t1), at(p2, t2));
+                    // wherever they start is where they start
+                    if n1.time == 0 && n2.time == 0 {
+                        continue;
+                    }
+                    if touching(d1, &n1.location, d2, &n2.location) {
+                        conflicts.push([(*id1, n1), (*id2, n2)]);
+                    }
+                }
+            }
+        }
+    }
+    conflicts
+}
+
+/// Whether any cell of `d1` at `loc1` is next to or on a cell of `d2` at
+/// `loc2`, diagonals included.
+fn touching(d1: &Droplet, loc1: &Location, d2: &Droplet, loc2: &Location) -> bool {
+    let cells2: Vec<Location> = d2.footprint
+        .offsets()
+        .iter()
+        .map(|off| loc2 + off)
+        .collect();
+    d1.footprint.offsets().iter().any(|off| {
+        let c1 = loc1 + off;
+        cells2
+            .iter()
+            .any(|c2| (c1.y - c2.y).abs() <= 1 && (c1.x - c2.x).abs() <= 1)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use grid::Wear;
+    use plan::cost::Distance;
+
+    fn loc(y: i32, x: i32) -> Location {
+        Location { y, x }
+    }
+
+    fn droplet(id: usize, location: Location, destination: Location) -> Droplet {
+        let id = DropletId { id, process_id: 0 };
+        let mut d = Droplet::new(id, 1.0, location, loc(1, 1));
+        d.destination = Some(destination);
+        d
+    }
+
+    fn route(
+        grid: &Grid,
+        droplets: &[Droplet],
+        horizon: Time,
+    ) -> Result<Map<DropletId, Path>, RouteFailure> {
+        let droplets: Map<_, _> = droplets.iter().map(|d| (d.id, d.clone())).collect();
+        let wear = Wear::default();
+        let costs = Costs::new(&Distance, &wear);
+        let regions = Regions::default();
+        let residue = Residue::default();
+        route_cbs(&droplets, grid, &costs, &regions, &residue, horizon, 1000)
+    }
+
+    #[test]
+    fn test_cbs_swap() {
+        // two droplets swap ends of a corridor, which needs one of them to
+        // duck into the bay in the middle and wait
+        let grid = Grid::from_ascii(
+            &[
+                "   .   ", //
+                "   .   ",
+                "   .   ",
+                ".......",
+            ].join("\n"),
+        ).unwrap();
+        let a = droplet(0, loc(3, 0), loc(3, 6));
+        let b = droplet(1, loc(3, 6), loc(3, 0));
+        let paths = route(&grid, &[a.clone(), b.clone()], 30).unwrap();
+
+        assert_eq!(paths[&a.id].last(), Some(&loc(3, 6)));
+        assert_eq!(paths[&b.id].last(), Some(&loc(3, 0)));
+        let droplets: Map<_, _> = vec![(a.id, a), (b.id, b)].into_iter().collect();
+        assert!(conflicts(&droplets, &paths).is_empty());
+    }
+
+    #[test]
+    fn test_cbs_no_route() {
+        // without the bay, they can't get past each other
+        let grid = Grid::rectangle(1, 3);
+        let a = droplet(0, loc(0, 0), loc(0, 2));
+        let b = droplet(1, loc(0, 2), loc(0, 0));
+        assert_eq!(route(&grid, &[a, b], 10).unwrap_err(), RouteFailure::NoRoute);
+    }
+}
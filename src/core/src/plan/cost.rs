@@ -0,0 +1,199 @@
+use std::fmt;
+use std::sync::Arc;
+
+use grid::{CellKind, Grid, Location, Wear};
+
+use super::route::Cost;
+
+/// What a move costs for a droplet the size of one cell.
+pub const MOVE_COST: Cost = 100;
+/// What waiting in place for a step costs a droplet.
+pub const STAY_COST: Cost = 1;
+
+/// Decides what each step of a route costs, so the router can find the
+/// cheapest route instead of just the shortest one.
+///
+/// The router finds routes with A*, estimating how much further a droplet
+/// has to go as `min_move_cost` per cell. Moves that cost less than that
+/// are bumped up to it, so the estimate never overshoots and the routes
+/// found are still the cheapest ones.
+pub trait CostModel: fmt::Debug + Send + Sync {
+    /// What it costs a droplet to move so that it covers `cells`.
+    fn move_cost(&self, grid: &Grid, wear: &Wear, cells: &[Location]) -> Cost;
+    /// What it costs a droplet covering `cells` to stay put for a step.
+    fn stay_cost(&self, grid: &Grid, wear: &Wear, cells: &[Location]) -> Cost;
+    /// The least any move can cost.
+    fn min_move_cost(&self) -> Cost {
+        MOVE_COST
+    }
+}
+
+/// A cost model along with the wear it may look at, the way the router
+/// uses them.
+#[derive(Clone, Copy)]
+pub struct Costs<'a> {
+    pub model: &'a CostModel,
+    pub wear: &'a Wear,
+}
+
+impl<'a> Costs<'a> {
+    pub fn new(model: &'a CostModel, wear: &'a Wear) -> Costs<'a> {
+        Costs { model, wear }
+    }
+
+    /// What a step that ends up covering `cells` costs.
+    pub fn step(&self, grid: &Grid, cells: &[Location], moving: bool) -> Cost {
+        if moving {
+            let cost = self.model.move_cost(grid, self.wear, cells);
+            cost.max(self.model.min_move_cost())
+        } else {
+            self.model.stay_cost(grid, self.wear, cells)
+        }
+    }
+
+    /// The least it could cost to get from `from` to `to`.
+    pub fn estimate(&self, from: &Location, to: &Location) -> Cost {
+        from.distance_to(to) * self.model.min_move_cost()
+    }
+}
+
+fn wear_penalty(wear: &Wear, cells: &[Location]) -> Cost {
+    cells.iter().map(|loc| wear.penalty(loc)).sum()
+}
+
+/// Moves cost `MOVE_COST` and waiting costs `STAY_COST`, so routes are as
+/// short as they can be. Worn cells cost their wear penalty on top; see
+/// `Wear::weight`. This is the default.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Distance;
+
+impl CostModel for Distance {
+    fn move_cost(&self, _grid: &Grid, wear: &Wear, cells: &[Location]) -> Cost {
+        MOVE_COST + wear_penalty(wear, cells)
+    }
+
+    fn stay_cost(&self, _grid: &Grid, wear: &Wear, cells: &[Location]) -> Cost {
+        STAY_COST + wear_penalty(wear, cells)
+    }
+}
+
+/// Counts the electrodes that have to be on. Every cell under a droplet is
+/// actuated on every step, whether it moves or not, so big droplets cost
+/// more and waiting around costs as much as moving.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Energy;
+
+impl CostModel for Energy {
+    fn move_cost(&self, _grid: &Grid, wear: &Wear, cells: &[Location]) -> Cost {
+        MOVE_COST * cells.len() as Cost + wear_penalty(wear, cells)
+    }
+
+    fn stay_cost(&self, grid: &Grid, wear: &Wear, cells: &[Location]) -> Cost {
+        self.move_cost(grid, wear, cells)
+    }
+}
+
+/// Like `Distance`, but each cell on or next to a heater costs `penalty`
+/// more, so droplets that are only passing by don't get warmed up.
+#[derive(Debug, Clone, Copy)]
+pub struct NearHeaters {
+    pub penalty: Cost,
+}
+
+impl Default for NearHeaters {
+    fn default() -> NearHeaters {
+        NearHeaters {
+            penalty: 2 * MOVE_COST,
+        }
+    }
+}
+
+impl NearHeaters {
+    fn heat(&self, grid: &Grid, cells: &[Location]) -> Cost {
+        let is_heater = |loc: &Location| match grid.get_cell(loc) {
+            Some(cell) => cell.kind == CellKind::Heater,
+            None => false,
+        };
+        let warm = cells
+            .iter()
+            .filter(|loc| grid.neighbors9(loc).iter().any(&is_heater))
+            .count();
+        self.penalty * warm as Cost
+    }
+}
+
+impl CostModel for NearHeaters {
+    fn move_cost(&self, grid: &Grid, wear: &Wear, cells: &[Location]) -> Cost {
+        Distance.move_cost(grid, wear, cells) + self.heat(grid, cells)
+    }
+
+    fn stay_cost(&self, grid: &Grid, wear: &Wear, cells: &[Location]) -> Cost {
+        Distance.stay_cost(grid, wear, cells) + self.heat(grid, cells)
+    }
+}
+
+/// The names of the built-in cost models, for `builtin_cost_model`.
+pub const BUILTIN_COST_MODELS: &[&str] = &["distance", "energy", "heaters"];
+
+/// One of the built-in cost models by name, with its default settings.
+pub fn builtin_cost_model(name: &str) -> Option<Arc<CostModel>> {
+    match name {
+        "distance" => Some(Arc::new(Distance)),
+        "energy" => Some(Arc::new(Energy)),
+        "heaters" => Some(Arc::new(NearHeaters::default())),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use grid::Cell;
+
+    fn loc(y: i32, x: i32) -> Location {
+        Location { y, x }
+    }
+
+    #[test]
+    fn test_cost_models() {
+        let wear = Wear::default();
+        let mut grid = Grid::rectangle(3, 5);
+        let heater = Cell {
+            pin: 0,
+            kind: CellKind::Heater,
+        };
+        grid.vec[1][4] = Some(heater);
+
+        let one = [loc(0, 0)];
+        let two = [loc(0, 0), loc(0, 1)];
+        let distance = Costs::new(&Distance, &wear);
+        assert_eq!(distance.step(&grid, &two, true), MOVE_COST);
+        assert_eq!(distance.step(&grid, &two, false), STAY_COST);
+
+        let energy = Costs::new(&Energy, &wear);
+        assert_eq!(energy.step(&grid, &one, true), MOVE_COST);
+        assert_eq!(energy.step(&grid, &two, false), 2 * MOVE_COST);
+
+        let heaters = NearHeaters { penalty: 7 };
+        let heaters = Costs::new(&heaters, &wear);
+        assert_eq!(heaters.step(&grid, &two, true), MOVE_COST);
+        assert_eq!(heaters.step(&grid, &[loc(0, 3)], true), MOVE_COST + 7);
+        assert_eq!(heaters.step(&grid, &[loc(2, 4), loc(2, 3)], false), STAY_COST + 14);
+
+        // a model that undercuts itself still can't throw off the estimate
+        #[derive(Debug)]
+        struct Cheap;
+        impl CostModel for Cheap {
+            fn move_cost(&self, _: &Grid, _: &Wear, _: &[Location]) -> Cost {
+                1
+            }
+            fn stay_cost(&self, _: &Grid, _: &Wear, _: &[Location]) -> Cost {
+                0
+            }
+        }
+        let cheap = Costs::new(&Cheap, &wear);
+        assert_eq!(cheap.step(&grid, &one, true), MOVE_COST);
+        assert_eq!(cheap.estimate(&loc(0, 0), &loc(2, 3)), 5 * MOVE_COST);
+    }
+}
@@ -1,7 +1,19 @@
+mod batch;
+mod cbs;
+mod cost;
 mod minheap;
 mod place;
 pub mod plan;
 mod route;
 
+pub use self::batch::{BatchLimits, Pending};
+pub use self::cost::{
+    builtin_cost_model, CostModel, Costs, Distance, Energy, NearHeaters, BUILTIN_COST_MODELS,
+    MOVE_COST, STAY_COST,
+};
+pub use self::place::{builtin_placer, FirstFit, PlacementWeights, Placer, BUILTIN_PLACERS};
 pub use self::plan::{PlanError, Planner};
-pub use self::route::Path;
+pub use self::route::{
+    builtin_router, route_droplets, ConflictBased, Path, Prioritized, RouteFailure, Router,
+    BUILTIN_ROUTERS,
+};
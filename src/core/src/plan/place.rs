@@ -1 +1,434 @@
+use std::fmt;
+use std::sync::Arc;
 
+use grid::{connected_components, Cell, Droplet, Grid, Location, Orientation, Snapshot};
+use util::collections::{Map, Set};
+
+use super::plan::Placement;
+
+/// Droplets closer than this to a placement crowd it, and the closer they
+/// are the more it costs.
+const SPACING: i32 = 3;
+
+/// How much each part of a placement's score counts. The placement with the
+/// lowest score wins.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlacementWeights {
+    /// Per cell the input droplets have to travel to get to the placement.
+    pub routing: u32,
+    /// Per cell that other droplets are closer than `SPACING`.
+    pub spacing: u32,
+    /// Per free cell cut off from the biggest open part of the board.
+    pub fragmentation: u32,
+    /// Per unit of penalty on the cells the placement covers, like wear.
+    pub penalty: u32,
+}
+
+impl Default for PlacementWeights {
+    fn default() -> PlacementWeights {
+        PlacementWeights {
+            routing: 1,
+            spacing: 2,
+            fragmentation: 1,
+            penalty: 1,
+        }
+    }
+}
+
+/// Decides where on the board an untrusted command goes.
+///
+/// A placer finds a spot on `grid` where `shape`, turned by one of the
+/// `orientations`, fits around the droplets in `snapshot`, and says which
+/// orientation it used. `inputs` are the locations in `shape` that the
+/// input droplets go to, along with those droplets. `penalty` says how much
+/// each cell should be avoided, for things like wear.
+pub trait Placer: fmt::Debug + Send + Sync {
+    fn place(
+        &self,
+        grid: &Grid,
+        shape: &Grid,
+        snapshot: &Snapshot,
+        inputs: &[(Location, &Droplet)],
+        orientations: &[Orientation],
+        penalty: &Fn(&Location) -> u32,
+    ) -> Option<(Placement, Orientation)>;
+}
+
+/// Scores every spot with these weights and takes the best; see `place`.
+/// This is the default.
+impl Placer for PlacementWeights {
+    fn place(
+        &self,
+        grid: &Grid,
+        shape: &Grid,
+        snapshot: &Snapshot,
+        inputs: &[(Location, &Droplet)],
+        orientations: &[Orientation],
+        penalty: &Fn(&Location) -> u32,
+    ) -> Option<(Placement, Orientation)> {
+        place(grid, shape, snapshot, inputs, orientations, self, penalty)
+    }
+}
+
+/// Takes the first spot that fits, in row major order, without looking at
+/// anything else. Quick, but it packs everything into the top left.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FirstFit;
+
+impl Placer for FirstFit {
+    fn place(
+        &self,
+        grid: &Grid,
+        shape: &Grid,
+        snapshot: &Snapshot,
+        inputs: &[(Location, &Droplet)],
+        orientations: &[Orientation],
+        _penalty: &Fn(&Location) -> u32,
+    ) -> Option<(Placement, Orientation)> {
+        // with nothing to score, ties go to the first spot
+        let weights = PlacementWeights {
+            routing: 0,
+            spacing: 0,
+            fragmentation: 0,
+            penalty: 0,
+        };
+        place(grid, shape, snapshot, inputs, orientations, &weights, |_| 0)
+    }
+}
+
+/// The names of the built-in placers, for `builtin_placer`.
+pub const BUILTIN_PLACERS: &[&str] = &["scored", "first-fit"];
+
+/// One of the built-in placers by name, with its default settings.
+pub fn builtin_placer(name: &str) -> Option<Arc<Placer>> {
+    match name {
+        "scored" => Some(Arc::new(PlacementWeights::default())),
+        "first-fit" => Some(Arc::new(FirstFit)),
+        _ => None,
+    }
+}
+
+/// Finds the best spot on `grid` for `shape`, turned by one of the
+/// `orientations`, and says which one it used.
+///
+/// `inputs` are the locations in `shape` that the input droplets go to,
+/// along with those droplets. Every spot where `shape` fits is scored by
+/// `weights`. Ties go to the spot that needs less routing, spacing and
+/// penalty, then to the earliest orientation, then to the first spot in row
+/// major order.
+pub fn place<F>(
+    grid: &Grid,
+    shape: &Grid,
+    snapshot: &Snapshot,
+    inputs: &[(Location, &Droplet)],
+    orientations: &[Orientation],
+    weights: &PlacementWeights,
+    penalty: F,
+) -> Option<(Placement, Orientation)>
+where
+    F: Fn(&Location) -> u32,
+{
+    // orientations that turn the shape into the same cells only differ in
+    // where the inputs go, so they can share the rest of the work
+    let mut turned_shapes: Vec<(Grid, Vec<(Orientation, Placement)>)> = Vec::new();
+    for orientation in orientations {
+        let (turned, to_turned) = turn(shape, orientation);
+        let same_cells = |t: &Grid| {
+            let cells = |g: &Grid| g.locations().map(|(loc, _)| loc).collect::<Vec<_>>();
+            cells(t) == cells(&turned)
+        };
+        match turned_shapes.iter().position(|(t, _)| same_cells(t)) {
+            Some(i) => turned_shapes[i].1.push((*orientation, to_turned)),
+            None => turned_shapes.push((turned, vec![(*orientation, to_turned)])),
+        }
+    }
+
+    let others: Vec<&Droplet> = snapshot
+        .droplets
+        .values()
+        .filter(|d| inputs.iter().all(|(_, input)| input.id != d.id))
+        .collect();
+
+    // everything but fragmentation is cheap to score, so score that first
+    // and only work out the fragmentation of spots that could still win
+    let mut candidates = Vec::new();
+    for (shape_i, (turned, ways)) in turned_shapes.iter().enumerate() {
+        let offsets = grid.vec.iter().enumerate().flat_map(move |(i, row)| {
+            (0..row.len()).map(move |j| Location {
+                y: i as i32,
+                x: j as i32,
+            })
+        });
+
+        for offset in offsets {
+            if !turned.is_compatible_within(offset, grid, snapshot) {
+                continue;
+            }
+            let cells: Vec<Location> = turned.locations().map(|(loc, _)| &loc + &offset).collect();
+
+            let spacing: u32 = others
+                .iter()
+                .map(|d| {
+                    let gap = cells.iter().map(|c| d.distance_to(c)).min().unwrap_or(SPACING);
+                    (SPACING - gap).max(0) as u32
+                })
+                .sum();
+            let penalty: u32 = cells.iter().map(&penalty).sum();
+
+            for (way_i, (orientation, to_turned)) in ways.iter().enumerate() {
+                let routing: u32 = inputs
+                    .iter()
+                    .map(|(loc, droplet)| {
+                        let anchor = loc + &orientation.anchor(&droplet.dimensions());
+                        let destination = &to_turned[&anchor] + &offset;
+                        droplet.location.distance_to(&destination)
+                    })
+                    .sum();
+
+                let score = u64::from(weights.routing) * u64::from(routing)
+                    + u64::from(weights.spacing) * u64::from(spacing)
+                    + u64::from(weights.penalty) * u64::from(penalty);
+                candidates.push((score, candidates.len(), offset, shape_i, way_i));
+            }
+        }
+    }
+    candidates.sort();
+
+    let taken: Set<Location> = others.iter().flat_map(|d| d.locations()).collect();
+    let mut best: Option<(u64, Location, usize, usize)> = None;
+    for (partial, _, offset, shape_i, way_i) in candidates {
+        if let Some((best_score, _, _, _)) = best {
+            if partial >= best_score {
+                break;
+            }
+        }
+
+        let score = if weights.fragmentation > 0 {
+            let turned = &turned_shapes[shape_i].0;
+            let cells = turned.locations().map(|(loc, _)| &loc + &offset).collect();
+            let fragmentation = stranded_cells(grid, &cells, &taken);
+            partial + u64::from(weights.fragmentation) * u64::from(fragmentation)
+        } else {
+            partial
+        };
+        trace!("placement at {} scores {}", offset, score);
+
+        let better = match best {
+            Some((best_score, _, _, _)) => score < best_score,
+            None => true,
+        };
+        if better {
+            best = Some((score, offset, shape_i, way_i));
+        }
+    }
+
+    best.map(|(_, offset, shape_i, way_i)| {
+        let (orientation, ref to_turned) = turned_shapes[shape_i].1[way_i];
+        let placement = to_turned
+            .iter()
+            .map(|(loc, turned)| (*loc, turned + &offset))
+            .collect();
+        (placement, orientation)
+    })
+}
+
+/// Turns `shape` by `orientation`, along with where each of its cells ends
+/// up in the turned shape.
+fn turn(shape: &Grid, orientation: &Orientation) -> (Grid, Placement) {
+    let turned: Vec<(Location, Location, Cell)> = shape
+        .locations()
+        .map(|(loc, cell)| (loc, orientation.apply(&loc), cell))
+        .collect();
+    let corner = Location {
+        y: turned.iter().map(|(_, t, _)| t.y).min().unwrap_or(0),
+        x: turned.iter().map(|(_, t, _)| t.x).min().unwrap_or(0),
+    };
+
+    let mut to_turned = Placement::new();
+    let mut cells = Map::new();
+    for (loc, t, cell) in turned {
+        let t = &t - &corner;
+        to_turned.insert(loc, t);
+        cells.insert(t, cell);
+    }
+
+    let dimensions = orientation.apply(&Location {
+        y: shape.max_height() as i32,
+        x: shape.max_width() as i32,
+    });
+    let (height, width) = (dimensions.y.unsigned_abs(), dimensions.x.unsigned_abs());
+    let turned_shape =
+        Grid::from_function(|loc| cells.get(&loc).cloned(), height as usize, width as usize);
+    (turned_shape, to_turned)
+}
+
+/// How many free cells are left outside the biggest open part of the board
+/// once `cells` and `taken` are taken.
+fn stranded_cells(grid: &Grid, cells: &Set<Location>, taken: &Set<Location>) -> u32 {
+    let free = grid
+        .locations()
+        .map(|(loc, _)| loc)
+        .filter(|loc| !cells.contains(loc) && !taken.contains(loc));
+
+    let mut sizes: Map<u32, u32> = Map::new();
+    for (_, label) in connected_components(free) {
+        *sizes.entry(label).or_insert(0) += 1;
+    }
+    let total: u32 = sizes.values().sum();
+    let biggest = sizes.values().cloned().max().unwrap_or(0);
+    total - biggest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use grid::DropletId;
+
+    fn loc(y: i32, x: i32) -> Location {
+        Location { y, x }
+    }
+
+    fn droplet(id: usize, location: Location) -> Droplet {
+        let id = DropletId { id, process_id: 0 };
+        Droplet::new(id, 1.0, location, loc(1, 1))
+    }
+
+    fn no_penalty(_: &Location) -> u32 {
+        0
+    }
+
+    fn place_upright<F>(
+        grid: &Grid,
+        shape: &Grid,
+        snapshot: &Snapshot,
+        inputs: &[(Location, &Droplet)],
+        weights: &PlacementWeights,
+        penalty: F,
+    ) -> Placement
+    where
+        F: Fn(&Location) -> u32,
+    {
+        let upright = [Orientation::default()];
+        let (map, _) = place(grid, shape, snapshot, inputs, &upright, weights, penalty).unwrap();
+        map
+    }
+
+    #[test]
+    fn test_place_near_inputs() {
+        let grid = Grid::rectangle(5, 7);
+        let shape = Grid::rectangle(1, 1);
+        let mut snapshot = Snapshot::default();
+        let d = droplet(0, loc(4, 6));
+        snapshot.droplets.insert(d.id, d.clone());
+
+        // first fit would go to the top left, but the droplet is over in the
+        // other corner
+        let weights = PlacementWeights::default();
+        let inputs = [(loc(0, 0), &d)];
+        let map = place_upright(&grid, &shape, &snapshot, &inputs, &weights, no_penalty);
+        assert_eq!(map[&loc(0, 0)].distance_to(&d.location), 2);
+    }
+
+    #[test]
+    fn test_first_fit() {
+        let grid = Grid::rectangle(5, 7);
+        let shape = Grid::rectangle(1, 1);
+        let mut snapshot = Snapshot::default();
+        let d = droplet(0, loc(4, 6));
+        snapshot.droplets.insert(d.id, d.clone());
+
+        // it doesn't care how far the droplet has to go
+        let inputs = [(loc(0, 0), &d)];
+        let orientations = [Orientation::default()];
+        let (map, _) = FirstFit
+            .place(&grid, &shape, &snapshot, &inputs, &orientations, &no_penalty)
+            .unwrap();
+        assert_eq!(map[&loc(0, 0)], loc(0, 0));
+    }
+
+    #[test]
+    fn test_place_away_from_others() {
+        let grid = Grid::rectangle(3, 9);
+        let shape = Grid::rectangle(1, 1);
+        let mut snapshot = Snapshot::default();
+        let d = droplet(0, loc(1, 1));
+        snapshot.droplets.insert(d.id, d.clone());
+
+        // with nothing to route, stay clear of the other droplet
+        let weights = PlacementWeights::default();
+        let map = place_upright(&grid, &shape, &snapshot, &[], &weights, no_penalty);
+        assert!(d.distance_to(&map[&loc(0, 0)]) >= SPACING);
+    }
+
+    #[test]
+    fn test_place_fragmentation() {
+        // a 1x3 shape across a 3 wide board cuts it in two unless it's at
+        // one of the ends
+        let grid = Grid::rectangle(5, 3);
+        let shape = Grid::rectangle(1, 3);
+        let mut snapshot = Snapshot::default();
+        let d = droplet(0, loc(4, 0));
+        snapshot.droplets.insert(d.id, d.clone());
+        let inputs = [(loc(0, 0), &d)];
+
+        // the closest spot to the droplet cuts the board in two
+        let weights = PlacementWeights {
+            fragmentation: 0,
+            ..PlacementWeights::default()
+        };
+        let map = place_upright(&grid, &shape, &snapshot, &inputs, &weights, no_penalty);
+        assert_eq!(map[&loc(0, 0)], loc(2, 0));
+
+        let weights = PlacementWeights::default();
+        let map = place_upright(&grid, &shape, &snapshot, &inputs, &weights, no_penalty);
+        assert_eq!(map[&loc(0, 0)], loc(0, 0));
+    }
+
+    #[test]
+    fn test_place_penalty() {
+        let grid = Grid::rectangle(3, 3);
+        let shape = Grid::rectangle(1, 1);
+        let snapshot = Snapshot::default();
+        let weights = PlacementWeights::default();
+
+        // with no penalty, it's just first fit
+        let map = place_upright(&grid, &shape, &snapshot, &[], &weights, no_penalty);
+        assert_eq!(map, grid.place(&shape, &snapshot).unwrap());
+
+        // avoid everything but the middle
+        let middle = loc(1, 1);
+        let penalty = |l: &Location| if *l == middle { 0 } else { 10 };
+        let map = place_upright(&grid, &shape, &snapshot, &[], &weights, penalty);
+        assert_eq!(map[&loc(0, 0)], middle);
+    }
+
+    #[test]
+    fn test_place_turned() {
+        // the shape is too wide for the board unless it's turned on its side
+        let grid = Grid::rectangle(7, 3);
+        let shape = Grid::rectangle(1, 5);
+        let snapshot = Snapshot::default();
+        let weights = PlacementWeights::default();
+        let all = Orientation::all();
+
+        let upright = [Orientation::default()];
+        assert!(place(&grid, &shape, &snapshot, &[], &upright, &weights, no_penalty).is_none());
+
+        let (map, orientation) =
+            place(&grid, &shape, &snapshot, &[], &all, &weights, no_penalty).unwrap();
+        assert!(!orientation.is_identity());
+        let column: Set<_> = map.values().map(|l| l.x).collect();
+        assert_eq!(column.len(), 1);
+        assert_eq!(map.len(), 5);
+
+        // an input droplet goes wherever the shape turned its spot
+        let mut snapshot = Snapshot::default();
+        let d = droplet(0, loc(6, 2));
+        snapshot.droplets.insert(d.id, d.clone());
+        let inputs = [(loc(0, 4), &d)];
+        let (map, _) =
+            place(&grid, &shape, &snapshot, &inputs, &all, &weights, no_penalty).unwrap();
+        assert!(map[&loc(0, 4)].y > map[&loc(0, 0)].y);
+    }
+}
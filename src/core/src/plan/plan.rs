@@ -1,38 +1,244 @@
-use std::sync::{Arc, Mutex};
+use std::cmp::Reverse;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use rand::rngs::StdRng;
 
 use command::Command;
-use grid::{Droplet, GridView, Location};
-use util::collections::Map;
+use grid::{Droplet, DropletId, DropletInfo, Grid, GridView, Location, Orientation, Snapshot};
+use plan::batch::{best_order, orders, BatchLimits, Pending};
+use plan::place::{PlacementWeights, Placer};
+use plan::RouteFailure;
+use process::{ProcessId, PuddleError, PuddleResult};
+use util::collections::{Map, Set};
+use util::seed::rng_from_seed;
 
-#[derive(Debug)]
+/// Why a command couldn't be planned. These go out to clients as the `data`
+/// of JSON-RPC errors, so they say what went wrong in terms of the board.
+#[derive(Debug, Serialize)]
 pub enum PlanError {
     RouteError {
-        placement: Placement,
-        droplets: Vec<Droplet>,
+        /// The command being planned, as `Debug` prints it.
+        command: String,
+        droplets: Vec<DropletInfo>,
+        /// Where the droplets that had to move were headed.
+        destinations: Vec<(DropletId, Location)>,
+        failure: RouteFailure,
+        /// A droplet that couldn't get to its destination even with the board
+        /// to itself. If there's none, the droplets got in each other's way.
+        stuck: Option<DropletId>,
+    },
+    PlaceError {
+        /// The command being planned, as `Debug` prints it.
+        command: String,
+        /// The height and width the command asked for.
+        shape: Location,
+        /// The cells taken up by droplets other than the command's inputs.
+        occupied: Vec<Location>,
+    },
+    StrandedDroplet {
+        id: DropletId,
+        location: Location,
+    },
+    ReplanError {
+        location: Location,
+        time: usize,
+    },
+    /// The command would put a droplet somewhere its process may not use.
+    OutsideRegion {
+        location: Location,
     },
-    PlaceError,
 }
 
 pub type Placement = Map<Location, Location>;
 
+/// How much extra it costs to place a command over a chokepoint, in the same
+/// units as wear.
+const CHOKEPOINT_PENALTY: u32 = 100;
+
+/// The dimensions of the droplets currently on the board, and of a
+/// single-cell droplet.
+fn droplet_dimensions(gv: &GridView) -> Set<Location> {
+    let mut dims: Set<Location> = gv.snapshot()
+        .droplets
+        .values()
+        .map(|d| d.dimensions())
+        .collect();
+    dims.insert(Location { y: 1, x: 1 });
+    dims
+}
+
+/// The cells where parking a command would get in the way of the droplets
+/// currently on the board (or any single-cell droplet). `known` has the
+/// bottleneck cells that were already found, by droplet dimensions.
+fn chokepoints(gv: &GridView, known: &Map<Location, Set<Location>>) -> Set<Location> {
+    let mut cells = Set::new();
+    for dim in &droplet_dimensions(gv) {
+        match known.get(dim) {
+            Some(known) => cells.extend(known.iter().cloned()),
+            None => cells.extend(gv.grid.bottlenecks(dim).cells()),
+        }
+    }
+    cells
+}
+
+/// What an untrusted command needs placed.
+struct PlaceRequest<'a> {
+    pid: Option<ProcessId>,
+    shape: &'a Grid,
+    in_ids: &'a [DropletId],
+    in_locs: &'a [Location],
+    orientable: bool,
+}
+
+/// The part of the board process `pid` may place on, if that isn't all of it.
+fn placement_grid(gv: &GridView, pid: ProcessId) -> Option<Grid> {
+    if gv.regions.is_empty() {
+        None
+    } else {
+        Some(gv.regions.placement_grid(&gv.grid, pid))
+    }
+}
+
+/// How much placement avoids each cell, for wear and chokepoints.
+fn penalty<'a>(
+    gv: &'a GridView,
+    known: &Map<Location, Set<Location>>,
+) -> impl Fn(&Location) -> u32 + 'a {
+    let chokepoints = chokepoints(gv, known);
+    move |loc: &Location| {
+        let choke = if chokepoints.contains(loc) {
+            CHOKEPOINT_PENALTY
+        } else {
+            0
+        };
+        gv.wear.penalty(loc) + choke
+    }
+}
+
+/// The board as it is now, minus the droplets `ids`.
+fn without(gv: &GridView, ids: &[DropletId]) -> Snapshot {
+    let mut snapshot = Snapshot::default();
+    snapshot.droplets = gv.snapshot()
+        .droplets
+        .iter()
+        .filter(|(id, _)| !ids.contains(id))
+        .map(|(id, d)| (*id, d.clone()))
+        .collect();
+    snapshot
+}
+
 pub struct Planner {
     gridview: Arc<Mutex<GridView>>,
+    /// How untrusted commands pick where to go.
+    pub placer: Arc<Placer>,
+    /// How hard `plan_batch` looks for a better order.
+    pub batch_limits: BatchLimits,
+    /// How many idle droplets may be moved out of the way of a command that
+    /// doesn't fit otherwise.
+    pub max_evictions: usize,
+    /// The board's bottleneck cells by droplet dimensions, since they take a
+    /// while to find. They only change when a cell dies.
+    bottlenecks: Map<Location, Set<Location>>,
+    /// Breaks ties while routing, so the same seed always plans the same.
+    rng: StdRng,
 }
 
 impl Planner {
-    pub fn new(gridview: Arc<Mutex<GridView>>) -> Planner {
-        Planner { gridview: gridview }
+    pub fn new(gridview: Arc<Mutex<GridView>>, seed: u64) -> Planner {
+        Planner {
+            gridview: gridview,
+            placer: Arc::new(PlacementWeights::default()),
+            batch_limits: BatchLimits::default(),
+            max_evictions: 0,
+            bottlenecks: Map::new(),
+            rng: rng_from_seed(seed),
+        }
+    }
+
+    pub fn gridview(&self) -> MutexGuard<GridView> {
+        self.gridview.lock().unwrap()
     }
 
     pub fn plan(&mut self, cmd: Box<Command>) -> Result<(), PlanError> {
+        let gridview = Arc::clone(&self.gridview);
+        let mut gv = gridview.lock().unwrap();
+        self.plan_on(&mut gv, cmd)
+    }
+
+    /// Plans a wash after everything planned so far, if there's residue to
+    /// clean up; see `GridView::wash`.
+    pub fn wash(&mut self) -> bool {
+        let gridview = Arc::clone(&self.gridview);
+        let mut gv = gridview.lock().unwrap();
+        gv.wash(&mut self.rng)
+    }
+
+    /// Plans a batch of commands together, in whichever order their
+    /// dependencies allow that gets them all done soonest. Since placing one
+    /// command can box in the next, that may not be the order they came in.
+    ///
+    /// Orders are tried until `batch_limits` runs out. If none of them work,
+    /// the batch is planned in the order it came in, just like planning the
+    /// commands one at a time, and the first error is returned. Either the
+    /// whole batch is planned or none of it is.
+    pub fn plan_batch(&mut self, batch: &[Pending]) -> PuddleResult<()> {
+        info!("Planning a batch of {} commands", batch.len());
+        // orders are tried out on a copy, so the executor can keep going
+        let mut copy = self.gridview.lock().unwrap().planning_copy();
+
+        let orders = orders(batch, self.batch_limits.orders);
+        let checkpoint = copy.checkpoint();
+        let rng = self.rng.clone();
+        let start = copy.planned_steps();
+        let best = best_order(&orders, self.batch_limits.steps, |order| {
+            let result = self.plan_order(&mut copy, batch, order);
+            let steps = copy.planned_steps() - start;
+            copy.restore(&checkpoint);
+            self.rng = rng.clone();
+            (steps, result.is_ok())
+        });
+
+        // only the best one is planned for real
+        let order = best.map_or_else(|| (0..batch.len()).collect(), |i| orders[i].clone());
+        info!("Planning the batch in order {:?}", order);
+        let gridview = Arc::clone(&self.gridview);
+        let mut gv = gridview.lock().unwrap();
+        let checkpoint = gv.checkpoint();
+        let result = self.plan_order(&mut gv, batch, &order);
+        if result.is_err() {
+            // don't leave the commands before the one that failed planned
+            gv.restore(&checkpoint);
+            self.rng = rng;
+        }
+        result
+    }
+
+    fn plan_order(
+        &mut self,
+        gv: &mut GridView,
+        batch: &[Pending],
+        order: &[usize],
+    ) -> PuddleResult<()> {
+        for &i in order {
+            let cmd = batch[i].build(gv)?;
+            self.plan_on(gv, cmd).map_err(PuddleError::PlanError)?;
+        }
+        Ok(())
+    }
+
+    fn plan_on(&mut self, gv: &mut GridView, cmd: Box<Command>) -> Result<(), PlanError> {
         info!("Planning {:?}", cmd);
         debug!("placing (trusted = {}) {:?}", cmd.trust_placement(), cmd);
 
-        let mut gv = self.gridview.lock().unwrap();
-
         let in_ids = cmd.input_droplets();
+        for id in &in_ids {
+            if let Some(location) = gv.stranded(id) {
+                return Err(PlanError::StrandedDroplet { id: *id, location });
+            }
+        }
+
         let (shape, in_locs) = {
-            let command_info = cmd.dynamic_info(&gv);
+            let command_info = cmd.dynamic_info(gv);
             (command_info.shape, command_info.input_locations)
         };
 
@@ -50,17 +256,58 @@ impl Planner {
                 .collect::<Vec<_>>()
         );
 
-        let placement = if cmd.trust_placement() {
+        // the process the command belongs to, so it can be kept to its region
+        let pid = cmd.output_droplets()
+            .iter()
+            .chain(&in_ids)
+            .map(|id| id.process_id)
+            .next();
+
+        self.find_bottlenecks(gv);
+        let (placement, orientation) = if cmd.trust_placement() {
+            // trusted commands still can't go outside their region
+            if let Some(pid) = pid {
+                let mut claimed = cmd.fixed_locations();
+                for (loc, id) in in_locs.iter().zip(&in_ids) {
+                    let droplet = &gv.snapshot().droplets[id];
+                    claimed.extend(droplet.footprint.offsets().iter().map(|off| loc + off));
+                }
+                if let Some(&location) = claimed.iter().find(|l| !gv.regions.can_place(pid, l)) {
+                    return Err(PlanError::OutsideRegion { location });
+                }
+            }
+
             // if we are trusting placement, just use an identity map
-            gv.grid
+            let identity = gv.grid
                 .locations()
                 .map(|(loc, _cell)| (loc, loc))
-                .collect::<Map<_, _>>()
+                .collect::<Map<_, _>>();
+            (identity, Orientation::default())
         } else {
-            // TODO place should be a method of gridview
-            gv.grid
-                .place(&shape, gv.snapshot())
-                .ok_or(PlanError::PlaceError)?
+            let request = PlaceRequest {
+                pid,
+                shape: &shape,
+                in_ids: &in_ids,
+                in_locs: &in_locs,
+                orientable: cmd.orientable(),
+            };
+            let found = match self.place_on(gv, gv.snapshot(), &request) {
+                Some(found) => Some(found),
+                None => self.evict_for(gv, &request),
+            };
+            found.ok_or_else(|| PlanError::PlaceError {
+                command: format!("{:?}", cmd),
+                shape: Location {
+                    y: shape.max_height() as i32,
+                    x: shape.max_width() as i32,
+                },
+                occupied: gv.snapshot()
+                    .droplets
+                    .values()
+                    .filter(|d| !in_ids.contains(&d.id))
+                    .flat_map(|d| d.locations())
+                    .collect(),
+            })?
         };
 
         debug!("placement for {:?}: {:?}", cmd, placement);
@@ -74,29 +321,42 @@ impl Planner {
                 .get_mut(&id)
                 .expect("Command gave back and invalid DropletId");
             assert!(droplet.destination.is_none());
+            let anchor = loc + &orientation.anchor(&droplet.dimensions());
             let mapped_loc = placement
-                .get(loc)
+                .get(&anchor)
                 .expect("input location wasn't in placement");
             droplet.destination = Some(*mapped_loc);
         }
 
         debug!("routing {:?}", cmd);
-        let paths = match gv.route() {
-            Some(p) => p,
-            None => {
-                return Err(PlanError::RouteError {
-                    placement: placement,
-                    droplets: gv.snapshot().droplets.values().map(|d| d.clone()).collect(),
-                })
-            }
-        };
-        debug!("route for {:?}: {:?}", cmd, paths);
+        // the inputs can often get going while earlier commands are still
+        // running, but if that means going through somebody, everyone
+        // routes together after what's already planned
+        if !gv.route_windowed(&in_ids) {
+            let paths = match gv.route(&mut self.rng) {
+                Ok(p) => p,
+                Err(failure) => {
+                    let droplets = &gv.snapshot().droplets;
+                    return Err(PlanError::RouteError {
+                        command: format!("{:?}", cmd),
+                        droplets: droplets.values().map(|d| d.info()).collect(),
+                        destinations: droplets
+                            .values()
+                            .filter_map(|d| d.destination.map(|dest| (d.id, dest)))
+                            .collect(),
+                        failure,
+                        stuck: gv.stuck_droplet(),
+                    });
+                }
+            };
+            debug!("route for {:?}: {:?}", cmd, paths);
 
-        trace!("Taking paths...");
-        gv.take_paths(&paths);
+            trace!("Taking paths...");
+            gv.take_paths(&paths);
+        }
 
         trace!("Running command {:?}", cmd);
-        cmd.run(&mut gv.subview(in_ids.iter().cloned(), placement));
+        cmd.run(&mut gv.subview(in_ids.iter().cloned(), placement, orientation));
         gv.register(cmd);
 
         // teardown destinations if the droplets are still there
@@ -110,4 +370,204 @@ impl Planner {
 
         Ok(())
     }
+
+    /// Finds the best spot for an untrusted command, with the droplets where
+    /// they are in `snapshot`.
+    fn place_on(
+        &self,
+        gv: &GridView,
+        snapshot: &Snapshot,
+        request: &PlaceRequest,
+    ) -> Option<(Placement, Orientation)> {
+        let masked = request.pid.and_then(|pid| placement_grid(gv, pid));
+        let grid = masked.as_ref().unwrap_or(&gv.grid);
+
+        let inputs: Vec<_> = request
+            .in_locs
+            .iter()
+            .zip(request.in_ids)
+            .map(|(loc, id)| (*loc, &snapshot.droplets[id]))
+            .collect();
+        // turning the shape can't change how the input droplets look,
+        // since the command sized the shape around them
+        let (upright, sideways): (Vec<_>, Vec<_>) = if request.orientable {
+            Orientation::all()
+                .into_iter()
+                .filter(|o| {
+                    inputs
+                        .iter()
+                        .all(|(_, d)| o.footprint(&d.footprint) == d.footprint)
+                })
+                .partition(|o| !o.transposes())
+        } else {
+            (vec![Orientation::default()], vec![])
+        };
+
+        let penalty = penalty(gv, &self.bottlenecks);
+        // turning the shape on its side turns the droplets it makes too,
+        // so only do that if it doesn't fit otherwise
+        let try_place = |orientations: &[Orientation]| {
+            let placer = &self.placer;
+            placer.place(grid, request.shape, snapshot, &inputs, orientations, &penalty)
+        };
+        try_place(&upright).or_else(|| try_place(&sideways))
+    }
+
+    /// Makes room for a command that doesn't fit by moving up to
+    /// `max_evictions` idle droplets out of the way, then places it. The
+    /// droplets keep their ids, and they're routed to their new spots like
+    /// any other droplet, so the moves show up in the history. If that
+    /// can't make room, nothing moves.
+    fn evict_for(
+        &mut self,
+        gv: &mut GridView,
+        request: &PlaceRequest,
+    ) -> Option<(Placement, Orientation)> {
+        // anything that isn't an input can move, unless it's stuck on a dead
+        // cell, and the biggest droplets make the most room
+        let mut idle: Vec<&Droplet> = gv.snapshot()
+            .droplets
+            .values()
+            .filter(|d| !request.in_ids.contains(&d.id) && gv.stranded(&d.id).is_none())
+            .collect();
+        idle.sort_by_key(|d| Reverse(d.footprint.cell_count()));
+        let idle: Vec<DropletId> = idle.iter().map(|d| d.id).collect();
+
+        let mut evicted = Vec::new();
+        let mut fits = None;
+        while fits.is_none() && evicted.len() < self.max_evictions {
+            let candidates: Vec<DropletId> = idle.iter()
+                .filter(|id| !evicted.contains(*id))
+                .cloned()
+                .collect();
+            let first = *candidates.first()?;
+            // see if moving one more is enough, or else move the biggest
+            let found = candidates
+                .iter()
+                .filter_map(|&id| {
+                    let mut ids = evicted.clone();
+                    ids.push(id);
+                    let placement = self.place_on(gv, &without(gv, &ids), request)?;
+                    Some((id, placement))
+                })
+                .next();
+            match found {
+                Some((id, (placement, _))) => {
+                    evicted.push(id);
+                    fits = Some(placement);
+                }
+                None => evicted.push(first),
+            }
+        }
+
+        let destinations = self.relocate(gv, &evicted, &fits?)?;
+        for (id, destination) in &destinations {
+            let droplet = gv.snapshot_mut().droplets.get_mut(id).unwrap();
+            droplet.destination = Some(*destination);
+        }
+        let routed = gv.route_windowed(&evicted) || match gv.route(&mut self.rng) {
+            Ok(paths) => {
+                gv.take_paths(&paths);
+                true
+            }
+            Err(failure) => {
+                debug!("couldn't route evicted droplets: {:?}", failure);
+                false
+            }
+        };
+        for id in destinations.keys() {
+            let droplet = gv.snapshot_mut().droplets.get_mut(id).unwrap();
+            droplet.destination = None;
+        }
+        if !routed {
+            return None;
+        }
+        for (id, destination) in &destinations {
+            info!("Evicted {:?} to {}", id, destination);
+        }
+
+        self.place_on(gv, gv.snapshot(), request)
+    }
+
+    /// Finds new spots for the `evicted` droplets, out of the way of
+    /// `placement` and each other.
+    fn relocate(
+        &self,
+        gv: &GridView,
+        evicted: &[DropletId],
+        placement: &Placement,
+    ) -> Option<Map<DropletId, Location>> {
+        // the command is going there, so the evicted droplets keep off of it
+        // and the cells next to it, just like they would a droplet
+        let mut snapshot = without(gv, evicted);
+        let excluded: Set<Location> = placement
+            .values()
+            .flat_map(|loc| gv.grid.neighbors9(loc))
+            .collect();
+
+        let penalty = penalty(gv, &self.bottlenecks);
+        let origin = Location { y: 0, x: 0 };
+        let mut destinations = Map::new();
+        for id in evicted {
+            let mut droplet = gv.snapshot().droplets[id].clone();
+            let dims = droplet.dimensions();
+            let shape = Grid::rectangle(dims.y as usize, dims.x as usize);
+            let mut grid = placement_grid(gv, id.process_id).unwrap_or_else(|| gv.grid.clone());
+            for loc in &excluded {
+                grid.remove(loc);
+            }
+            let inputs = [(origin, &droplet)];
+            let orientations = [Orientation::default()];
+            let placer = &self.placer;
+            let (spot, _) =
+                placer.place(&grid, &shape, &snapshot, &inputs, &orientations, &penalty)?;
+
+            let destination = spot[&origin];
+            droplet.location = destination;
+            snapshot.droplets.insert(*id, droplet);
+            destinations.insert(*id, destination);
+        }
+        Some(destinations)
+    }
+
+    /// Finds the bottlenecks for the droplets on the board, unless they're
+    /// known already.
+    fn find_bottlenecks(&mut self, gv: &GridView) {
+        for dim in droplet_dimensions(gv) {
+            self.bottlenecks
+                .entry(dim)
+                .or_insert_with(|| gv.grid.bottlenecks(&dim).cells());
+        }
+    }
+
+    pub fn mark_faulty(&mut self, loc: Location) -> Result<Vec<DropletId>, PlanError> {
+        info!("Marking {} as faulty", loc);
+        let mut gv = self.gridview.lock().unwrap();
+        // the board is a different shape now
+        self.bottlenecks.clear();
+        let stranded = gv.mark_faulty(loc, &mut self.rng)?;
+        if !stranded.is_empty() {
+            warn!("Droplets stranded on faulty cell {}: {:?}", loc, stranded);
+        }
+        Ok(stranded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bottlenecks_forgotten() {
+        let gridview = Arc::new(Mutex::new(GridView::new(Grid::rectangle(3, 3))));
+        let mut planner = Planner::new(Arc::clone(&gridview), 0);
+        planner.find_bottlenecks(&gridview.lock().unwrap());
+        assert!(chokepoints(&gridview.lock().unwrap(), &planner.bottlenecks).is_empty());
+
+        // without the middle, the board is a ring of corridors
+        planner.mark_faulty(Location { y: 1, x: 1 }).unwrap();
+        planner.find_bottlenecks(&gridview.lock().unwrap());
+        let chokepoints = chokepoints(&gridview.lock().unwrap(), &planner.bottlenecks);
+        assert!(chokepoints.contains(&Location { y: 0, x: 1 }));
+    }
 }
@@ -1,13 +1,18 @@
+use std::cmp::Reverse;
 use std::collections::HashSet;
+use std::fmt;
+use std::sync::Arc;
 use std::time::Instant;
 
-use grid::{Droplet, DropletId, Grid, GridView, Location};
+use grid::{Droplet, DropletId, Footprint, Grid, GridView, Location, Regions, Residue};
+use plan::cbs::route_cbs;
+use plan::cost::Costs;
 use plan::minheap::MinHeap;
 
 use util::collections::Entry::*;
 use util::collections::{Map, Set};
 
-use rand::{thread_rng, Rng};
+use rand::{Rng, RngCore};
 
 pub type Path = Vec<Location>;
 
@@ -24,19 +29,20 @@ fn build_path(mut came_from: Map<Node, Node>, end_node: Node) -> Path {
 }
 
 #[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Debug)]
-struct Node {
-    location: Location,
-    time: Time,
+pub struct Node {
+    pub location: Location,
+    pub time: Time,
 }
 
-type Time = u32;
-type Cost = u32;
-const MOVE_COST: Cost = 100;
-const STAY_COST: Cost = 1;
+pub type Time = u32;
+pub type Cost = u32;
 
-type NextVec = Vec<(Cost, Node)>;
+/// How many times `shorten_paths` goes over all the paths.
+const SHORTEN_PASSES: usize = 3;
 
-#[derive(Default)]
+pub type NextVec = Vec<(Cost, Node)>;
+
+#[derive(Default, Clone)]
 struct AvoidanceSet {
     max_time: Time,
     present: Set<Node>,
@@ -44,12 +50,17 @@ struct AvoidanceSet {
 }
 
 impl AvoidanceSet {
-    fn filter(&self, vec: NextVec) -> NextVec {
+    /// Drops the nodes where a droplet with `offsets` would run into
+    /// something being avoided.
+    fn filter(&self, vec: NextVec, offsets: &[Location]) -> NextVec {
         vec.into_iter()
-            .filter(|&(_cost, node)|
+            .filter(|&(_cost, node)| {
+                offsets.iter().all(|off| {
+                    let cell = node.offset(off);
                     // make sure that it's either not in the map
-                    !self.collides(&node)
-                    && !self.collides_with_final(&node))
+                    !self.collides(&cell) && !self.collides_with_final(&cell)
+                })
+            })
             .collect()
     }
 
@@ -64,38 +75,43 @@ impl AvoidanceSet {
             .map_or(false, |&final_t| node.time >= final_t)
     }
 
-    fn would_finally_collide(&self, node: &Node) -> bool {
+    fn would_finally_collide(&self, node: &Node, offsets: &[Location]) -> bool {
         (node.time..self.max_time)
             .map(|t| Node {
                 time: t,
                 location: node.location,
             })
-            .any(|future_node| self.collides(&future_node))
+            .any(|future_node| offsets.iter().any(|off| self.collides(&future_node.offset(off))))
     }
 
-    fn avoid_path(&mut self, path: &Path, grid: &Grid, droplet_dimensions: &Location) {
+    fn avoid_path(&mut self, path: &Path, grid: &Grid, footprint: &Footprint) {
         let node_path = path.clone().into_iter().enumerate().map(|(i, loc)| Node {
             time: i as Time,
             location: loc,
         });
         for node in node_path {
-            self.avoid_node(grid, node, droplet_dimensions);
+            self.avoid_node(grid, node, footprint);
         }
 
         // Add last element to finals
         let last = path.len() - 1;
-        for loc in grid.neighbors_dimensions(&path[last], droplet_dimensions) {
+        self.avoid_final(grid, &path[last], last as Time, footprint);
+    }
+
+    /// Avoids a droplet that stays at `location` from `time` on.
+    fn avoid_final(&mut self, grid: &Grid, location: &Location, time: Time, footprint: &Footprint) {
+        for loc in grid.neighbors_footprint(location, footprint) {
             let earliest_time = self.finals
                 .get(&loc)
-                .map_or(last as Time, |&prev| prev.min(last as Time));
+                .map_or(time, |&prev| prev.min(time));
             self.finals.insert(loc, earliest_time);
         }
 
-        self.max_time = self.max_time.max(last as Time)
+        self.max_time = self.max_time.max(time)
     }
 
-    fn avoid_node(&mut self, grid: &Grid, node: Node, dimensions: &Location) {
-        for loc in grid.neighbors_dimensions(&node.location, dimensions) {
+    fn avoid_node(&mut self, grid: &Grid, node: Node, footprint: &Footprint) {
+        for loc in grid.neighbors_footprint(&node.location, footprint) {
             for t in -1..2 {
                 let time = (node.time as i32) + t;
                 if time < 0 {
@@ -111,15 +127,30 @@ impl AvoidanceSet {
 }
 
 impl Node {
+    /// The same time, but `offset` away.
+    fn offset(&self, offset: &Location) -> Node {
+        Node {
+            location: &self.location + offset,
+            time: self.time,
+        }
+    }
+
     /// Returns a vector representing possible locations on the given `Grid` that can be the next
     /// location for this `Node`. This uses `neighbors4`, since droplets only move in the cardinal
-    /// directions.
-    fn expand(&self, grid: &Grid) -> NextVec {
+    /// directions. A droplet covering `offsets` only moves where every one of its cells lands on
+    /// an electrode. What each step costs is up to the cost model in `costs`.
+    pub fn expand(&self, grid: &Grid, costs: &Costs, offsets: &[Location]) -> NextVec {
+        let fits = |loc: &Location| offsets.iter().all(|off| grid.get_cell(&(loc + off)).is_some());
+        let cells = |loc: &Location| -> Vec<Location> {
+            offsets.iter().map(|off| loc + off).collect()
+        };
+
         let mut vec: Vec<(Cost, Node)> = grid.neighbors4(&self.location)
             .iter()
+            .filter(|loc| fits(loc))
             .map(|&location| {
                 (
-                    MOVE_COST,
+                    costs.step(grid, &cells(&location), true),
                     Node {
                         location,
                         time: self.time + 1,
@@ -129,7 +160,7 @@ impl Node {
             .collect();
 
         vec.push((
-            STAY_COST,
+            costs.step(grid, &cells(&self.location), false),
             Node {
                 location: self.location,
                 time: self.time + 1,
@@ -140,41 +171,356 @@ impl Node {
     }
 }
 
+/// Adds what the residue under each of `next` costs `droplet`, dropping the
+/// ones it may not go over at all. Like with regions, it can always stay
+/// where it started.
+pub fn with_residue(
+    next: NextVec,
+    droplet: &Droplet,
+    offsets: &[Location],
+    residue: &Residue,
+) -> NextVec {
+    next.into_iter()
+        .filter_map(|(cost, node)| {
+            let extra = if node.location == droplet.location {
+                Some(0)
+            } else {
+                let cells = offsets.iter().map(|off| &node.location + off);
+                residue.cost(&droplet.reagents, cells)
+            };
+            extra.map(|extra| (cost + extra, node))
+        })
+        .collect()
+}
+
+/// How to route all the droplets on the board at once.
+///
+/// Every droplet gets a path to its destination, or stays where it is if it
+/// doesn't have one. Paths start where the droplets are, move at most one
+/// cell a step, and keep droplets from touching each other within a step of
+/// each other. Droplets may only go over cells their process may use, and
+/// what a path costs is up to `costs` and `residue`; see `route_many` for
+/// how the built-in routers do that.
+pub trait Router: fmt::Debug + Send + Sync {
+    fn route(
+        &self,
+        droplets: &Map<DropletId, Droplet>,
+        grid: &Grid,
+        costs: &Costs,
+        regions: &Regions,
+        residue: &Residue,
+        rng: &mut RngCore,
+    ) -> Result<Map<DropletId, Path>, RouteFailure>;
+
+    /// Like `route`, but around droplets that already have the board booked:
+    /// `reserved[t]` are the others `t` steps in, and they stay where the
+    /// last step leaves them. A router that can't work around them returns
+    /// `None`, and the droplets are routed after everything else instead.
+    /// By default, they're routed one at a time; see `route_reserved`.
+    fn route_reserved(
+        &self,
+        droplets: &Map<DropletId, Droplet>,
+        reserved: &[Vec<&Droplet>],
+        grid: &Grid,
+        costs: &Costs,
+        regions: &Regions,
+        residue: &Residue,
+    ) -> Option<Map<DropletId, Path>> {
+        route_reserved(droplets, reserved, grid, costs, regions, residue)
+    }
+
+    /// Tightens up the paths `route` found before they're taken. By default,
+    /// that's `shorten_paths`.
+    fn tighten(
+        &self,
+        paths: &mut Map<DropletId, Path>,
+        droplets: &Map<DropletId, Droplet>,
+        grid: &Grid,
+        costs: &Costs,
+        regions: &Regions,
+        residue: &Residue,
+    ) {
+        shorten_paths(paths, droplets, grid, costs, regions, residue)
+    }
+}
+
+/// Routes droplets one at a time, each avoiding the ones before it. If one
+/// gets stuck, shuffle them and try again, up to 50 times. This is the
+/// default.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Prioritized;
+
+impl Router for Prioritized {
+    fn route(
+        &self,
+        droplets: &Map<DropletId, Droplet>,
+        grid: &Grid,
+        costs: &Costs,
+        regions: &Regions,
+        residue: &Residue,
+        rng: &mut RngCore,
+    ) -> Result<Map<DropletId, Path>, RouteFailure> {
+        route_prioritized(droplets, grid, costs, regions, residue, rng)
+    }
+}
+
+/// Conflict-based search, which finds a routing that takes at most
+/// `horizon` steps whenever there is one. It gives up after branching
+/// `budget` times.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConflictBased {
+    pub horizon: Time,
+    pub budget: usize,
+}
+
+impl Default for ConflictBased {
+    fn default() -> ConflictBased {
+        ConflictBased {
+            horizon: 50,
+            budget: 500,
+        }
+    }
+}
+
+impl Router for ConflictBased {
+    fn route(
+        &self,
+        droplets: &Map<DropletId, Droplet>,
+        grid: &Grid,
+        costs: &Costs,
+        regions: &Regions,
+        residue: &Residue,
+        _rng: &mut RngCore,
+    ) -> Result<Map<DropletId, Path>, RouteFailure> {
+        route_cbs(droplets, grid, costs, regions, residue, self.horizon, self.budget)
+    }
+
+    /// The search doesn't know about droplets outside it, so this always
+    /// leaves the droplets to be routed after everything else.
+    fn route_reserved(
+        &self,
+        _droplets: &Map<DropletId, Droplet>,
+        _reserved: &[Vec<&Droplet>],
+        _grid: &Grid,
+        _costs: &Costs,
+        _regions: &Regions,
+        _residue: &Residue,
+    ) -> Option<Map<DropletId, Path>> {
+        None
+    }
+
+    /// Rerouting droplets one at a time would throw away what the search
+    /// found, so only the waiting at the ends of the paths goes.
+    fn tighten(
+        &self,
+        paths: &mut Map<DropletId, Path>,
+        _droplets: &Map<DropletId, Droplet>,
+        _grid: &Grid,
+        _costs: &Costs,
+        _regions: &Regions,
+        _residue: &Residue,
+    ) {
+        for path in paths.values_mut() {
+            trim_stays(path);
+        }
+    }
+}
+
+/// The names of the built-in routers, for `builtin_router`.
+pub const BUILTIN_ROUTERS: &[&str] = &["prioritized", "conflict-based"];
+
+/// One of the built-in routers by name, with its default settings.
+pub fn builtin_router(name: &str) -> Option<Arc<Router>> {
+    match name {
+        "prioritized" => Some(Arc::new(Prioritized)),
+        "conflict-based" => Some(Arc::new(ConflictBased::default())),
+        _ => None,
+    }
+}
+
+/// Why droplets couldn't be routed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum RouteFailure {
+    /// There is no way to route them in time.
+    NoRoute,
+    /// The router gave up without finding a way or showing there isn't one.
+    GaveUp,
+}
+
 impl GridView {
-    pub fn route(&self) -> Option<Map<DropletId, Path>> {
-        let mut droplets = self.snapshot().droplets.iter().collect::<Vec<_>>();
-        let mut rng = thread_rng();
-        for i in 1..50 {
-            rng.shuffle(&mut droplets);
-            let result = route_many(&droplets, &self.grid);
-            if result.is_some() {
-                return result;
+    pub fn route(&self, rng: &mut impl Rng) -> Result<Map<DropletId, Path>, RouteFailure> {
+        route_droplets(
+            &self.snapshot().droplets,
+            &self.grid,
+            &self.costs(),
+            &self.regions,
+            self.residue_so_far(),
+            &*self.router,
+            rng,
+        )
+    }
+
+    /// See `stuck_droplet`.
+    pub fn stuck_droplet(&self) -> Option<DropletId> {
+        let residue = self.residue_so_far();
+        let droplets = &self.snapshot().droplets;
+        stuck_droplet(droplets, &self.grid, &self.costs(), &self.regions, residue)
+    }
+}
+
+/// Finds a droplet that can't get to its destination even with the board to
+/// itself, to explain why routing `droplets` failed. If there's none, they
+/// only got in each other's way.
+pub fn stuck_droplet(
+    droplets: &Map<DropletId, Droplet>,
+    grid: &Grid,
+    costs: &Costs,
+    regions: &Regions,
+    residue: &Residue,
+) -> Option<DropletId> {
+    droplets
+        .iter()
+        .filter(|(_, d)| d.destination.is_some())
+        .find(|&(id, d)| {
+            route_many(&[(id, d)], grid, costs, regions, residue, AvoidanceSet::default())
+                .is_none()
+        })
+        .map(|(id, _)| *id)
+}
+
+/// Routes every droplet to its destination with `router`, then has it
+/// tighten up the paths it found.
+pub fn route_droplets(
+    droplets: &Map<DropletId, Droplet>,
+    grid: &Grid,
+    costs: &Costs,
+    regions: &Regions,
+    residue: &Residue,
+    router: &Router,
+    rng: &mut impl Rng,
+) -> Result<Map<DropletId, Path>, RouteFailure> {
+    let mut paths = router.route(droplets, grid, costs, regions, residue, rng)?;
+    router.tighten(&mut paths, droplets, grid, costs, regions, residue);
+    Ok(paths)
+}
+
+/// Tightens up `paths` after routing, so `take_paths` has fewer steps to
+/// take. Nobody needs to wait around at the end of their path. And a droplet
+/// that waited or took a detour because of the order it was routed in can
+/// often do better once everyone else's path is known, so each one is
+/// routed again on its own around all the others, longest first.
+///
+/// A new path only replaces the old one if it's shorter. It avoids the
+/// others just like in `route_many`, so the paths stay collision-free.
+pub fn shorten_paths(
+    paths: &mut Map<DropletId, Path>,
+    droplets: &Map<DropletId, Droplet>,
+    grid: &Grid,
+    costs: &Costs,
+    regions: &Regions,
+    residue: &Residue,
+) {
+    for path in paths.values_mut() {
+        trim_stays(path);
+    }
+
+    for _ in 0..SHORTEN_PASSES {
+        let mut ids: Vec<DropletId> = paths.keys().cloned().collect();
+        ids.sort_by_key(|id| Reverse(paths[id].len()));
+
+        let mut shortened = false;
+        for id in ids {
+            let mut av_set = AvoidanceSet::default();
+            for (other, path) in paths.iter().filter(|&(other, _)| *other != id) {
+                av_set.avoid_path(path, grid, &droplets[other].footprint);
+            }
+            let one = [(&id, &droplets[&id])];
+            let rerouted = route_many(&one, grid, costs, regions, residue, av_set)
+                .and_then(|mut rerouted| rerouted.remove(&id));
+            if let Some(mut path) = rerouted {
+                trim_stays(&mut path);
+                if path.len() < paths[&id].len() {
+                    paths.insert(id, path);
+                    shortened = true;
+                }
             }
-            trace!("route failed, trying iteration {}", i);
         }
+        if !shortened {
+            break;
+        }
+    }
+}
 
-        None
+/// Drops the steps at the end of `path` where the droplet just stays put.
+fn trim_stays(path: &mut Path) {
+    while path.len() > 1 && path[path.len() - 1] == path[path.len() - 2] {
+        path.pop();
     }
 }
 
-fn route_many(droplets: &[(&DropletId, &Droplet)], grid: &Grid) -> Option<Map<DropletId, Path>> {
-    let mut av_set = AvoidanceSet::default();
+fn route_prioritized(
+    droplets: &Map<DropletId, Droplet>,
+    grid: &Grid,
+    costs: &Costs,
+    regions: &Regions,
+    residue: &Residue,
+    rng: &mut RngCore,
+) -> Result<Map<DropletId, Path>, RouteFailure> {
+    let mut droplets = droplets.iter().collect::<Vec<_>>();
+    for i in 1..50 {
+        rng.shuffle(&mut droplets);
+        let result = route_many(&droplets, grid, costs, regions, residue, AvoidanceSet::default());
+        if let Some(paths) = result {
+            return Ok(paths);
+        }
+        trace!("route failed, trying iteration {}", i);
+    }
+
+    // failing to find an order doesn't mean there's no routing
+    Err(RouteFailure::GaveUp)
+}
+
+/// Routes `droplets` one after the other, each avoiding the ones before it
+/// and whatever `av_set` already avoids.
+fn route_many(
+    droplets: &[(&DropletId, &Droplet)],
+    grid: &Grid,
+    costs: &Costs,
+    regions: &Regions,
+    residue: &Residue,
+    mut av_set: AvoidanceSet,
+) -> Option<Map<DropletId, Path>> {
     let num_cells = grid.locations().count();
 
     let mut paths = Map::new();
-    let mut max_t = 0;
+    let mut max_t = av_set.max_time;
 
     for &(&id, droplet) in droplets.iter() {
+        // a droplet can always stay put, even if it's somewhere it may no
+        // longer go
+        let offsets = droplet.footprint.offsets();
+        let allowed = |loc: &Location| {
+            *loc == droplet.location
+                || offsets
+                    .iter()
+                    .all(|off| regions.can_route(id.process_id, &(loc + off)))
+        };
+
         // route a single droplet
         let result = route_one(
             &droplet,
             num_cells as Time + max_t,
-            |node| av_set.filter(node.expand(grid)),
+            costs,
+            |node| {
+                let mut next = av_set.filter(node.expand(grid, costs, &offsets), &offsets);
+                next.retain(|(_, n)| allowed(&n.location));
+                with_residue(next, droplet, &offsets, residue)
+            },
             |node| {
                 node.location == match droplet.destination {
                     Some(x) => x,
                     None => droplet.location,
-                } && !av_set.would_finally_collide(node)
+                } && !av_set.would_finally_collide(node, &offsets)
             },
         );
         let path = match result {
@@ -185,16 +531,51 @@ fn route_many(droplets: &[(&DropletId, &Droplet)], grid: &Grid) -> Option<Map<Dr
         max_t = max_t.max(path.len() as Time);
 
         // once we know this path works, add to our avoidance set
-        av_set.avoid_path(&path, grid, &droplet.dimensions);
+        av_set.avoid_path(&path, grid, &droplet.footprint);
         paths.insert(id, path);
     }
 
     Some(paths)
 }
 
-fn route_one<FNext, FDone>(
+/// Routes `droplets` around the others in `reserved`, which holds where every
+/// other droplet is at each step, starting from when these start moving.
+/// Once `reserved` runs out, the others stay where they were last. Nobody in
+/// `reserved` moves out of the way, so this may fail where `route_droplets`
+/// wouldn't.
+pub fn route_reserved(
+    droplets: &Map<DropletId, Droplet>,
+    reserved: &[Vec<&Droplet>],
+    grid: &Grid,
+    costs: &Costs,
+    regions: &Regions,
+    residue: &Residue,
+) -> Option<Map<DropletId, Path>> {
+    let mut av_set = AvoidanceSet::default();
+    for (t, others) in reserved.iter().enumerate() {
+        for d in others {
+            let node = Node {
+                location: d.location,
+                time: t as Time,
+            };
+            av_set.avoid_node(grid, node, &d.footprint);
+        }
+    }
+    if let Some(others) = reserved.last() {
+        let last = (reserved.len() - 1) as Time;
+        for d in others {
+            av_set.avoid_final(grid, &d.location, last, &d.footprint);
+        }
+    }
+
+    let droplets: Vec<_> = droplets.iter().collect();
+    route_many(&droplets, grid, costs, regions, residue, av_set)
+}
+
+pub fn route_one<FNext, FDone>(
     droplet: &Droplet,
     max_time: Time,
+    costs: &Costs,
     mut next_fn: FNext,
     mut done_fn: FDone,
 ) -> Option<Path>
@@ -223,8 +604,8 @@ where
         None => droplet.location,
     };
 
-    // use manhattan distance from goal as the heuristic
-    let heuristic = |node: Node| -> Cost { dest.distance_to(&node.location) * MOVE_COST };
+    // the cheapest it could possibly be from here is the heuristic
+    let heuristic = |node: Node| -> Cost { costs.estimate(&node.location, &dest) };
 
     let result = loop {
         let node = match todo.pop() {
@@ -300,7 +681,299 @@ where
 
 #[cfg(test)]
 pub mod tests {
+    use super::*;
+
+    use grid::{Cell, CellKind, Wear};
+    use plan::cost::{Distance, NearHeaters};
+
+    use util::seed::rng_from_seed;
+
+    fn loc(y: i32, x: i32) -> Location {
+        Location { y, x }
+    }
+
+    fn droplet(id: usize, location: Location, destination: Location) -> Droplet {
+        let id = DropletId { id, process_id: 0 };
+        let mut d = Droplet::new(id, 1.0, location, loc(1, 1));
+        d.destination = Some(destination);
+        d
+    }
+
+    fn big_droplet(id: usize, location: Location, destination: Location) -> Droplet {
+        let mut d = droplet(id, location, destination);
+        d.footprint = Footprint::Rect(loc(2, 2));
+        d
+    }
+
+    fn route(grid: &Grid, droplets: &[Droplet]) -> Result<Map<DropletId, Path>, RouteFailure> {
+        let droplets: Map<_, _> = droplets.iter().map(|d| (d.id, d.clone())).collect();
+        let (wear, regions, residue) = (Wear::default(), Regions::default(), Residue::default());
+        let costs = Costs::new(&Distance, &wear);
+        let mut rng = rng_from_seed(0);
+        route_droplets(&droplets, grid, &costs, &regions, &residue, &Prioritized, &mut rng)
+    }
+
+    /// Where all of `d`'s cells are with its top left at `location`.
+    fn cells(d: &Droplet, location: &Location) -> Vec<Location> {
+        d.footprint.offsets().iter().map(|off| location + off).collect()
+    }
+
+    #[test]
+    fn test_route_big_droplet_on_board() {
+        // the top left could go straight across, but the rest of the droplet
+        // would pass over the hole, so it has to go around underneath
+        let grid = Grid::from_ascii(
+            &[
+                "......", //
+                ".. ...",
+                "......",
+                "......",
+            ].join("\n"),
+        ).unwrap();
+        let d = big_droplet(0, loc(0, 0), loc(0, 4));
+        let paths = route(&grid, &[d.clone()]).unwrap();
+
+        let path = &paths[&d.id];
+        assert_eq!(path.last(), Some(&loc(0, 4)));
+        for location in path {
+            let on_board = cells(&d, location).iter().all(|c| grid.get_cell(c).is_some());
+            assert!(on_board, "droplet hangs off the board at {}", location);
+        }
+    }
+
+    #[test]
+    fn test_route_big_droplet_too_wide() {
+        // a one cell corridor is fine for a small droplet, but not a big one
+        let grid = Grid::from_ascii(
+            &[
+                "..  ..", //
+                "......",
+                "..  ..",
+            ].join("\n"),
+        ).unwrap();
+        let small = droplet(0, loc(1, 0), loc(1, 5));
+        assert!(route(&grid, &[small]).is_ok());
+
+        let big = big_droplet(0, loc(0, 0), loc(0, 4));
+        assert!(route(&grid, &[big]).is_err());
+    }
+
+    #[test]
+    fn test_stuck_droplet() {
+        let grid = Grid::from_ascii(
+            &[
+                "... ..", //
+                "... ..",
+            ].join("\n"),
+        ).unwrap();
+        let droplets = |ds: &[Droplet]| ds.iter().map(|d| (d.id, d.clone())).collect();
+        let (wear, regions, residue) = (Wear::default(), Regions::default(), Residue::default());
+        let costs = Costs::new(&Distance, &wear);
+
+        // the other side is walled off
+        let across = droplet(0, loc(0, 0), loc(0, 5));
+        let down = droplet(1, loc(0, 1), loc(1, 1));
+        let both = droplets(&[across, down.clone()]);
+        let stuck = stuck_droplet(&both, &grid, &costs, &regions, &residue);
+        assert_eq!(stuck, Some(DropletId { id: 0, process_id: 0 }));
+
+        // trading places is hard, but either could do it alone
+        let a = droplet(0, loc(0, 0), loc(0, 2));
+        let b = droplet(1, loc(0, 2), loc(0, 0));
+        let stuck = stuck_droplet(&droplets(&[a, b, down]), &grid, &costs, &regions, &residue);
+        assert_eq!(stuck, None);
+    }
+
+    #[test]
+    fn test_route_around_residue() {
+        let grid = Grid::rectangle(3, 5);
+        let (wear, regions) = (Wear::default(), Regions::default());
+        let costs = Costs::new(&Distance, &wear);
+        let mut residue = Residue::default();
+        residue.compatibility.incompatible = vec![("acid".into(), "base".into())];
+        let mut acid = droplet(0, loc(1, 1), loc(1, 1));
+        acid.reagents.insert("acid".into());
+        for x in 1..4 {
+            acid.location = loc(1, x);
+            residue.record(&acid, 0);
+        }
 
-    // TODO make some tests
+        let mut base = droplet(1, loc(1, 0), loc(1, 4));
+        let straight = 5;
+        let route = |d: &Droplet, residue: &Residue| {
+            let droplets = vec![(d.id, d.clone())].into_iter().collect();
+            let mut rng = rng_from_seed(0);
+            let router = Prioritized;
+            route_droplets(&droplets, &grid, &costs, &regions, residue, &router, &mut rng)
+        };
+
+        // it doesn't care until it knows it's a base
+        assert_eq!(route(&base, &residue).unwrap()[&base.id].len(), straight);
+
+        base.reagents.insert("base".into());
+        let path = route(&base, &residue).unwrap().remove(&base.id).unwrap();
+        assert_eq!(path.last(), Some(&loc(1, 4)));
+        assert!(path.iter().all(|l| l.y != 1 || l.x == 0 || l.x == 4), "{:?}", path);
+
+        // with a penalty, it's cheaper to go straight through than around
+        residue.compatibility.penalty = Some(10);
+        assert_eq!(route(&base, &residue).unwrap()[&base.id].len(), straight);
+    }
+
+    #[test]
+    fn test_route_cost_model() {
+        let mut grid = Grid::rectangle(3, 5);
+        grid.vec[0][2] = Some(Cell {
+            pin: 0,
+            kind: CellKind::Heater,
+        });
+        let d = droplet(0, loc(1, 0), loc(1, 4));
+        let droplets = vec![(d.id, d.clone())].into_iter().collect();
+        let (wear, regions, residue) = (Wear::default(), Regions::default(), Residue::default());
+        let route = |costs: &Costs| {
+            let mut rng = rng_from_seed(0);
+            let router = Prioritized;
+            let paths =
+                route_droplets(&droplets, &grid, costs, &regions, &residue, &router, &mut rng);
+            paths.unwrap().remove(&d.id).unwrap()
+        };
+
+        // straight across goes right past the heater
+        let path = route(&Costs::new(&Distance, &wear));
+        assert_eq!(path.len(), 5);
+        assert!(path.contains(&loc(1, 2)));
 
+        // going around the bottom takes longer, but stays cool
+        let path = route(&Costs::new(&NearHeaters::default(), &wear));
+        assert_eq!(path.len(), 7);
+        assert!(path.iter().all(|l| l.y == 2 || l.x == 0 || l.x == 4), "{:?}", path);
+    }
+
+    #[test]
+    fn test_route_big_droplets_apart() {
+        // two big droplets trade places, so they have to get past each other
+        let grid = Grid::rectangle(6, 8);
+        let a = big_droplet(0, loc(0, 0), loc(4, 6));
+        let b = big_droplet(1, loc(4, 6), loc(0, 0));
+        let paths = route(&grid, &[a.clone(), b.clone()]).unwrap();
+
+        let (pa, pb) = (&paths[&a.id], &paths[&b.id]);
+        assert_eq!(pa.last(), Some(&loc(4, 6)));
+        assert_eq!(pb.last(), Some(&loc(0, 0)));
+        let at = |path: &Path, t: usize| path[t.min(path.len() - 1)];
+        for t in 0..pa.len().max(pb.len()) {
+            let (ca, cb) = (cells(&a, &at(pa, t)), cells(&b, &at(pb, t)));
+            for (c1, c2) in ca.iter().flat_map(|c1| cb.iter().map(move |c2| (c1, c2))) {
+                let touching = (c1.y - c2.y).abs() <= 1 && (c1.x - c2.x).abs() <= 1;
+                assert!(!touching, "droplets touch at time {}", t);
+            }
+        }
+    }
+
+    /// Checks that no two droplets ever touch while following `paths`.
+    fn assert_apart(droplets: &Map<DropletId, Droplet>, paths: &Map<DropletId, Path>) {
+        let at = |path: &Path, t: usize| path[t.min(path.len() - 1)];
+        let steps = paths.values().map(|p| p.len()).max().unwrap_or(0);
+        for t in 0..steps {
+            for (id1, p1) in paths {
+                for (id2, p2) in paths.iter().filter(|&(id2, _)| id1 < id2) {
+                    let mut d1 = droplets[id1].clone();
+                    let mut d2 = droplets[id2].clone();
+                    d1.location = at(p1, t);
+                    d2.location = at(p2, t);
+                    let apart = d1.collision_distance(&d2) > 0;
+                    assert!(apart, "{:?} and {:?} touch at time {}", id1, id2, t);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_shorten_paths() {
+        let grid = Grid::rectangle(5, 5);
+        let a = droplet(0, loc(0, 0), loc(0, 4));
+        let b = droplet(1, loc(4, 0), loc(4, 4));
+        let droplets: Map<_, _> = vec![a.clone(), b.clone()]
+            .into_iter()
+            .map(|d| (d.id, d))
+            .collect();
+        let (wear, regions, residue) = (Wear::default(), Regions::default(), Residue::default());
+        let costs = Costs::new(&Distance, &wear);
+
+        // a dawdles at the start, b takes the long way and then waits
+        let mut paths = Map::new();
+        let slow: Path = vec![loc(0, 0), loc(0, 0), loc(0, 0)];
+        let straight: Path = (0..5).map(|x| loc(0, x)).collect();
+        paths.insert(a.id, slow.iter().chain(&straight[1..]).cloned().collect());
+        let detour: Path = vec![loc(4, 0), loc(3, 0), loc(3, 1), loc(3, 2), loc(3, 3)];
+        let rest = vec![loc(3, 4), loc(4, 4), loc(4, 4), loc(4, 4)];
+        paths.insert(b.id, detour.into_iter().chain(rest).collect());
+        assert_apart(&droplets, &paths);
+
+        shorten_paths(&mut paths, &droplets, &grid, &costs, &regions, &residue);
+        assert_eq!(paths[&a.id], straight);
+        assert_eq!(paths[&b.id].len(), 5);
+        assert_eq!(paths[&b.id].last(), Some(&loc(4, 4)));
+        assert_apart(&droplets, &paths);
+    }
+
+    #[test]
+    fn test_shorten_paths_crowded() {
+        // everybody crosses the middle, so the order they're routed in makes
+        // some of them wait or go around
+        let grid = Grid::rectangle(7, 7);
+        let droplets: Map<_, _> = vec![
+            droplet(0, loc(0, 0), loc(6, 6)),
+            droplet(1, loc(6, 6), loc(0, 0)),
+            droplet(2, loc(0, 6), loc(6, 0)),
+            droplet(3, loc(6, 0), loc(0, 6)),
+            droplet(4, loc(3, 0), loc(3, 6)),
+        ].into_iter()
+            .map(|d| (d.id, d))
+            .collect();
+        let (wear, regions, residue) = (Wear::default(), Regions::default(), Residue::default());
+        let costs = Costs::new(&Distance, &wear);
+
+        for seed in 0..5 {
+            let mut rng = rng_from_seed(seed);
+            let mut paths =
+                route_prioritized(&droplets, &grid, &costs, &regions, &residue, &mut rng).unwrap();
+            let makespan = |paths: &Map<DropletId, Path>| paths.values().map(|p| p.len()).max();
+            let before = makespan(&paths);
+
+            shorten_paths(&mut paths, &droplets, &grid, &costs, &regions, &residue);
+            assert!(makespan(&paths) <= before);
+            for (id, d) in &droplets {
+                assert_eq!(paths[id].last(), d.destination.as_ref());
+            }
+            assert_apart(&droplets, &paths);
+        }
+    }
+
+    #[test]
+    fn test_route_seeded() {
+        // everybody crosses the middle, so the order they go in matters
+        let grid = Grid::rectangle(7, 7);
+        let droplets: Map<_, _> = vec![
+            droplet(0, loc(0, 0), loc(6, 6)),
+            droplet(1, loc(6, 6), loc(0, 0)),
+            droplet(2, loc(0, 6), loc(6, 0)),
+            droplet(3, loc(6, 0), loc(0, 6)),
+        ].into_iter()
+            .map(|d| (d.id, d))
+            .collect();
+        let (wear, regions, residue) = (Wear::default(), Regions::default(), Residue::default());
+        let costs = Costs::new(&Distance, &wear);
+
+        let route = |seed| {
+            let mut rng = rng_from_seed(seed);
+            let router = Prioritized;
+            route_droplets(&droplets, &grid, &costs, &regions, &residue, &router, &mut rng).unwrap()
+        };
+        let paths = route(42);
+        for (id, d) in &droplets {
+            assert_eq!(paths[id].last(), d.destination.as_ref());
+        }
+        assert_eq!(paths, route(42));
+    }
 }
@@ -1,15 +1,21 @@
 use std::ops::{Deref, DerefMut, Drop};
+use std::path::PathBuf;
+use std::sync::mpsc::Sender;
 use std::sync::{Arc, Mutex};
 use std::thread;
 
 use exec::Executor;
-use grid::{DropletInfo, Grid, GridView};
-use process::{Process, ProcessId, PuddleError, PuddleResult};
+use grid::{
+    ActuationFrame, Compatibility, DropletId, DropletInfo, Grid, GridView, InitialState, Location,
+    Region, Wash, Wear,
+};
+use process::{Process, ProcessId, PuddleError, PuddleResult, Scheduler};
 
 use util::collections::Map;
 use util::endpoint::Endpoint;
+use util::seed::random_seed;
 
-use plan::Planner;
+use plan::{BatchLimits, CostModel, PlacementWeights, Placer, Planner, Router};
 
 pub struct ProcessHandle<'a> {
     process: Option<Process>,
@@ -42,41 +48,154 @@ impl<'a> DerefMut for ProcessHandle<'a> {
     }
 }
 
+#[derive(Debug, Default, Clone)]
+pub struct ManagerOptions {
+    /// Where to keep electrode wear counts between runs, if anywhere. See
+    /// `Wear::sidecar_path`.
+    pub wear_path: Option<PathBuf>,
+    /// How hard placement and routing avoid worn electrodes; see `Wear`.
+    pub wear_weight: u32,
+    /// How placement trades off the things that make a spot good; see
+    /// `PlacementWeights`.
+    pub placement: PlacementWeights,
+    /// Where to place commands, if not by scoring spots with `placement`;
+    /// see `Placer`.
+    pub placer: Option<Arc<Placer>>,
+    /// How to route droplets, if not with `Prioritized`; see `Router`.
+    pub router: Option<Arc<Router>>,
+    /// What routes cost, if not just how long they are; see `CostModel`.
+    pub cost_model: Option<Arc<CostModel>>,
+    /// How hard to look for a better order when planning a batch; see
+    /// `Process::set_batching`.
+    pub batch_limits: BatchLimits,
+    /// How many idle droplets may be moved out of the way of a command that
+    /// doesn't fit otherwise. None by default, so droplets stay put.
+    pub max_evictions: usize,
+    /// Which reagents mustn't go over each other's residue; see
+    /// `Manager::set_compatibility`.
+    pub compatibility: Compatibility,
+    /// Where wash droplets come from and go to. With these, residue gets
+    /// washed away after a command when nothing else is waiting to be
+    /// planned; see `GridView::wash`. Without them, the board's reservoirs
+    /// are used; see `Wash::from_grid`.
+    pub wash: Option<Wash>,
+    /// Seeds everything random in planning and execution, so a run can be
+    /// replayed exactly. If there's none, one is picked at random.
+    pub seed: Option<u64>,
+    /// Gets every frame the executor actuates, in order, once it has been
+    /// checked against the plan. This is what hardware should run.
+    pub frames: Option<Sender<ActuationFrame>>,
+}
+
 #[allow(dead_code)]
 pub struct Manager {
     processes: Mutex<Map<ProcessId, Process>>,
     planner: Arc<Mutex<Planner>>,
+    scheduler: Arc<Scheduler>,
     exec_endpoint: Mutex<Endpoint<(), Vec<DropletInfo>>>,
     exec_thread: thread::JoinHandle<()>,
     blocking: bool,
+    initial_droplets: Map<char, DropletId>,
+    seed: u64,
 }
 
 // TODO impl drop
 
 impl Manager {
-    pub fn new(blocking: bool, grid: Grid) -> Manager {
+    pub fn new(blocking: bool, grid: Grid, options: ManagerOptions) -> Manager {
         let (mine, execs) = Endpoint::pair();
 
-        let gridview = GridView::new(grid);
+        let seed = options.seed.unwrap_or_else(random_seed);
+        info!("Using seed {}", seed);
+
+        let mut gridview = GridView::new(grid);
+        if let Some(ref path) = options.wear_path {
+            gridview.wear = Wear::load(path)
+                .unwrap_or_else(|err| panic!("Couldn't load wear from {:?}: {}", path, err));
+        }
+        gridview.wear.weight = options.wear_weight;
+        if let Some(router) = options.router {
+            gridview.router = router;
+        }
+        if let Some(model) = options.cost_model {
+            gridview.cost_model = model;
+        }
+        gridview.set_compatibility(options.compatibility);
+        gridview.wash = options.wash.or_else(|| Wash::from_grid(&gridview.grid));
+
         let gv_lock = Arc::new(Mutex::new(gridview));
-        let executor = Executor::new(blocking, gv_lock.clone());
+        let executor = Executor::new(
+            blocking,
+            gv_lock.clone(),
+            options.wear_path,
+            options.frames,
+            seed,
+        );
 
         let exec_thread = thread::Builder::new()
             .name("exec".into())
             .spawn(move || executor.run(execs))
             .expect("Execution thread failed to start!");
 
-        let planner = Planner::new(gv_lock);
+        let mut planner = Planner::new(gv_lock, seed);
+        let placement = options.placement;
+        planner.placer = options.placer.unwrap_or_else(|| Arc::new(placement));
+        planner.batch_limits = options.batch_limits;
+        planner.max_evictions = options.max_evictions;
+
+        let planner = Arc::new(Mutex::new(planner));
+        let scheduler = Arc::new(Scheduler::new(Arc::clone(&planner)));
 
         Manager {
             exec_thread: exec_thread,
             processes: Mutex::new(Map::new()),
             exec_endpoint: Mutex::new(mine),
-            planner: Arc::new(Mutex::new(planner)),
+            planner,
+            scheduler,
             blocking: blocking,
+            initial_droplets: Map::new(),
+            seed,
         }
     }
 
+    /// Creates a manager whose board already has some droplets on it. The
+    /// droplets are input by a process named "initial", so they are owned by
+    /// that process.
+    pub fn from_initial_state(
+        blocking: bool,
+        state: InitialState,
+        options: ManagerOptions,
+    ) -> PuddleResult<Manager> {
+        let mut manager = Manager::new(blocking, state.grid, options);
+
+        let droplets = {
+            let p = manager.get_new_process("initial");
+            state
+                .droplets
+                .iter()
+                .map(|(&ch, blob)| {
+                    let footprint = blob.footprint.clone();
+                    let id = p.input_footprint(Some(blob.location), blob.volume, footprint)?;
+                    Ok((ch, id))
+                })
+                .collect::<PuddleResult<Map<_, _>>>()?
+        };
+
+        manager.initial_droplets = droplets;
+        Ok(manager)
+    }
+
+    /// The ids of the droplets given to `from_initial_state`, by the
+    /// character they were drawn with.
+    pub fn initial_droplets(&self) -> &Map<char, DropletId> {
+        &self.initial_droplets
+    }
+
+    /// The seed this manager plans with, to replay the run later.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
     fn take_process(&self, pid: ProcessId) -> PuddleResult<Process> {
         self.processes
             .lock()
@@ -102,20 +221,61 @@ impl Manager {
     where
         S: Into<String>,
     {
-        let planner = Arc::clone(&self.planner);
-        let process = Process::new(name.into(), planner);
+        let scheduler = Arc::clone(&self.scheduler);
+        let process = Process::new(name.into(), scheduler);
         let pid = process.id();
         let mut procs = self.processes.lock().unwrap();
         procs.insert(pid, process);
         Ok(pid)
     }
 
+    /// Like `new_process`, but the process gets `region` to itself. It only
+    /// places and routes inside the region, and other processes stay out.
+    pub fn new_process_with_region<S>(&self, name: S, region: &Region) -> PuddleResult<ProcessId>
+    where
+        S: Into<String>,
+    {
+        let pid = self.new_process(name)?;
+        let planner = self.planner.lock().unwrap();
+        let mut gv = planner.gridview();
+        let GridView {
+            ref mut regions,
+            ref grid,
+            ..
+        } = *gv;
+        if let Err(err) = regions.reserve(grid, pid, region) {
+            self.processes.lock().unwrap().remove(&pid);
+            return Err(PuddleError::RegionError(err));
+        }
+        Ok(pid)
+    }
+
+    /// Lets other processes route through `corridor`, a part of the region of
+    /// process `pid`. Nobody may place commands on it.
+    pub fn share_corridor(&self, pid: ProcessId, corridor: &Region) -> PuddleResult<()> {
+        let planner = self.planner.lock().unwrap();
+        let mut gv = planner.gridview();
+        gv.regions
+            .share(pid, corridor)
+            .map_err(PuddleError::RegionError)
+    }
+
     pub fn close_process(&self, pid: ProcessId) -> PuddleResult<()> {
         let p = self.take_process(pid)?;
         p.flush()?;
+        let planner = self.planner.lock().unwrap();
+        planner.gridview().regions.release(pid);
+        self.scheduler.forget(pid);
         Ok(())
     }
 
+    /// Lets the commands of process `pid` get planned ahead of those from
+    /// processes with a lower priority. Processes start at priority 0, and
+    /// ones with the same priority take turns.
+    pub fn set_priority(&self, pid: ProcessId, priority: u32) {
+        self.scheduler.set_priority(pid, priority);
+    }
+
     pub fn get_new_process<S>(&self, name: S) -> ProcessHandle
     where
         S: Into<String>,
@@ -124,6 +284,23 @@ impl Manager {
         self.get_process(pid).expect("get failed")
     }
 
+    /// Stops using the cell at `loc`, replanning around it. Returns the ids of
+    /// the droplets that were stranded on the cell; their owning processes
+    /// will see them stranded in the next flush, and get an error if they try
+    /// to use them.
+    pub fn mark_faulty(&self, loc: Location) -> PuddleResult<Vec<DropletId>> {
+        let mut planner = self.planner.lock().unwrap();
+        planner.mark_faulty(loc).map_err(PuddleError::PlanError)
+    }
+
+    /// Says which reagents contaminate each other. From then on, droplets
+    /// input with a reagent are routed around the residue of the ones they
+    /// don't get along with, or at a cost if the table gives one.
+    pub fn set_compatibility(&self, compatibility: Compatibility) {
+        let planner = self.planner.lock().unwrap();
+        planner.gridview().set_compatibility(compatibility);
+    }
+
     pub fn visualizer_droplet_info(&self) -> PuddleResult<Vec<DropletInfo>> {
         // DONT FLUSH
         let endp = self.exec_endpoint.lock().unwrap();
@@ -1,7 +1,9 @@
 mod manager;
 mod process;
 mod rpc;
+mod schedule;
 
 pub use self::manager::*;
 pub use self::process::*;
 pub use self::rpc::*;
+pub use self::schedule::*;
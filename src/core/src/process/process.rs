@@ -1,24 +1,37 @@
+use std::mem;
 use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering::Relaxed;
 use std::sync::mpsc::channel;
 use std::sync::{Arc, Mutex};
 
-use grid::{DropletId, DropletInfo, Location};
+use grid::{
+    ActuationError, DropletId, DropletInfo, Footprint, GridView, Location, Reagent, RegionError,
+};
 
 use command;
 use command::Command;
 
-use plan::{PlanError, Planner};
+use plan::{Pending, PlanError};
+use process::Scheduler;
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub enum PuddleError {
     PlanError(PlanError),
     NonExistentDropletId(usize),
     NonExistentProcess(ProcessId),
+    /// The volume is more than a droplet of these dimensions can hold, or the
+    /// droplet wouldn't fit on the board.
+    VolumeDoesNotFit { volume: f64, dimensions: Location },
+    /// The droplet would have had a volume outside the board's limits.
+    VolumeOutOfBounds { id: DropletId, volume: f64 },
+    RegionError(RegionError),
+    /// An earlier command that was supposed to make this droplet failed.
+    MissingInput(DropletId),
+    /// The board was asked to do something other than what was planned, so
+    /// it stopped. Nothing else will run.
+    ActuationError(ActuationError),
 }
 
-use PuddleError::*;
-
 pub type PuddleResult<T> = Result<T, PuddleError>;
 
 pub type ProcessId = usize;
@@ -28,7 +41,9 @@ pub struct Process {
     #[allow(dead_code)]
     name: String,
     next_droplet_id: AtomicUsize,
-    planner: Arc<Mutex<Planner>>,
+    scheduler: Arc<Scheduler>,
+    /// The commands held for the next flush, if batching.
+    batch: Mutex<Option<Vec<Pending>>>,
     // TODO we probably want something like this for more precise flushing
     // unresolved_droplet_ids: Mutex<Set<DropletId>>,
 }
@@ -36,12 +51,13 @@ pub struct Process {
 static NEXT_PROCESS_ID: AtomicUsize = AtomicUsize::new(0);
 
 impl Process {
-    pub fn new(name: String, planner: Arc<Mutex<Planner>>) -> Process {
+    pub fn new(name: String, scheduler: Arc<Scheduler>) -> Process {
         Process {
             id: NEXT_PROCESS_ID.fetch_add(1, Relaxed),
             name: name,
             next_droplet_id: AtomicUsize::new(0),
-            planner: planner,
+            scheduler: scheduler,
+            batch: Mutex::new(None),
         }
     }
 
@@ -56,54 +72,141 @@ impl Process {
         }
     }
 
-    fn plan(&self, cmd: Box<Command>) -> PuddleResult<()> {
-        let mut planner = self.planner.lock().unwrap();
-        planner.plan(cmd).map_err(PlanError)
+    /// Plans the command built by `make_cmd`, which gets to look at the
+    /// droplets as they will be once everything planned so far has run. If
+    /// batching, it's held until the next flush instead, so errors only show
+    /// up then.
+    fn plan_with<C, F>(
+        &self,
+        inputs: &[DropletId],
+        outputs: &[DropletId],
+        make_cmd: F,
+    ) -> PuddleResult<()>
+    where
+        C: Command + 'static,
+        F: Fn(&GridView) -> PuddleResult<C> + Send + 'static,
+    {
+        let pending = Pending::new(inputs, outputs, make_cmd);
+        if let Some(ref mut batch) = *self.batch.lock().unwrap() {
+            batch.push(pending);
+            return Ok(());
+        }
+        self.scheduler.submit(self.id, vec![pending])
+    }
+
+    /// Plans the commands held for batching, if there are any.
+    fn plan_batch(&self) -> PuddleResult<()> {
+        let batch = match *self.batch.lock().unwrap() {
+            Some(ref mut batch) => mem::take(batch),
+            None => return Ok(()),
+        };
+        if batch.is_empty() {
+            return Ok(());
+        }
+        self.scheduler.submit(self.id, batch)
+    }
+
+    /// Starts or stops batching. While batching, commands are held until the
+    /// next flush and then planned together, which can find better
+    /// placements than planning them one at a time. Stopping plans whatever
+    /// is held right away.
+    pub fn set_batching(&self, batching: bool) -> PuddleResult<()> {
+        if batching {
+            let mut batch = self.batch.lock().unwrap();
+            if batch.is_none() {
+                *batch = Some(Vec::new());
+            }
+            Ok(())
+        } else {
+            let result = self.plan_batch();
+            *self.batch.lock().unwrap() = None;
+            result
+        }
     }
 }
 
 impl Process {
     pub fn flush(&self) -> PuddleResult<Vec<DropletInfo>> {
+        self.plan_batch()?;
+
         let (tx, rx) = channel();
-        let flush_cmd = command::Flush::new(self.id, tx);
+        let pid = self.id;
+        // the batch is planned by now, so the flush goes straight in
+        let flush = Pending::new(&[], &[], move |_| Ok(command::Flush::new(pid, tx.clone())));
+        self.scheduler.submit(self.id, vec![flush])?;
+        rx.recv().unwrap()
+    }
 
-        self.plan(Box::new(flush_cmd))?;
-        let info = rx.recv().unwrap();
+    pub fn input(
+        &self,
+        loc: Option<Location>,
+        vol: f64,
+        dim: Option<Location>,
+    ) -> PuddleResult<DropletId> {
+        self.input_as(loc, vol, dim, None)
+    }
 
-        Ok(info)
+    /// Like `input`, but says what the droplet is made of. Routing keeps it
+    /// off the residue of anything the compatibility table says it doesn't
+    /// get along with; see `Manager::set_compatibility`.
+    pub fn input_reagent(
+        &self,
+        loc: Option<Location>,
+        vol: f64,
+        dim: Option<Location>,
+        reagent: Reagent,
+    ) -> PuddleResult<DropletId> {
+        self.input_as(loc, vol, dim, Some(reagent))
     }
 
-    pub fn input(
+    fn input_as(
         &self,
         loc: Option<Location>,
         vol: f64,
         dim: Option<Location>,
+        reagent: Option<Reagent>,
+    ) -> PuddleResult<DropletId> {
+        let output = self.new_droplet_id();
+        self.plan_with(&[], &[output], move |gv| {
+            let input = command::Input::new(loc, vol, dim, output, &gv.grid)?;
+            Ok(input.with_reagent(reagent.clone()))
+        })?;
+        Ok(output)
+    }
+
+    /// Like `input`, but the droplet covers exactly `footprint`, which
+    /// doesn't have to be a rectangle.
+    pub fn input_footprint(
+        &self,
+        loc: Option<Location>,
+        vol: f64,
+        footprint: Footprint,
     ) -> PuddleResult<DropletId> {
         let output = self.new_droplet_id();
-        let input_cmd = command::Input::new(loc, vol, dim, output)?;
-        self.plan(Box::new(input_cmd))?;
+        self.plan_with(&[], &[output], move |gv| {
+            command::Input::with_footprint(loc, vol, footprint.clone(), output, &gv.grid)
+        })?;
         Ok(output)
     }
 
     pub fn move_droplet(&self, d1: DropletId, loc: Location) -> PuddleResult<DropletId> {
         let output = self.new_droplet_id();
-        let move_cmd = command::Move::new(d1, loc, output)?;
-        self.plan(Box::new(move_cmd))?;
+        self.plan_with(&[d1], &[output], move |_| command::Move::new(d1, loc, output))?;
         Ok(output)
     }
 
     pub fn mix(&self, d1: DropletId, d2: DropletId) -> PuddleResult<DropletId> {
         let output = self.new_droplet_id();
-        let mix_cmd = command::Mix::new(d1, d2, output)?;
-        self.plan(Box::new(mix_cmd))?;
+        self.plan_with(&[d1, d2], &[output], move |gv| command::Mix::new(d1, d2, output, gv))?;
         Ok(output)
     }
 
     pub fn split(&self, d: DropletId) -> PuddleResult<(DropletId, DropletId)> {
         let out1 = self.new_droplet_id();
         let out2 = self.new_droplet_id();
-        let split_cmd = command::Split::new(d, out1, out2)?;
-        self.plan(Box::new(split_cmd))?;
+        self.plan_with(&[d], &[out1, out2], move |gv| {
+            command::Split::new(d, out1, out2, gv)
+        })?;
         Ok((out1, out2))
     }
 }
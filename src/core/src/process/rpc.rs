@@ -1,4 +1,6 @@
 use jsonrpc_core as rpc;
+use jsonrpc_macros::Trailing;
+use serde_json;
 use std::sync::Arc;
 
 use ::*;
@@ -8,6 +10,8 @@ impl From<PuddleError> for rpc::Error {
         let code = rpc::ErrorCode::ServerError(0);
         let mut err = rpc::Error::new(code);
         err.message = format!("PuddleError: {:?}", p_err);
+        // the same thing, but for programs instead of people
+        err.data = serde_json::to_value(&p_err).ok();
         err
     }
 }
@@ -18,9 +22,17 @@ build_rpc_trait! {
         #[rpc(name = "new_process")]
         fn new_process(
             &self,
-            String
+            String,
+            Trailing<Region>
         ) -> PuddleResult<ProcessId>;
 
+        #[rpc(name = "share_corridor")]
+        fn share_corridor(
+            &self,
+            ProcessId,
+            Region
+        ) -> PuddleResult<()>;
+
         #[rpc(name = "close_process")]
         fn close_process(
             &self,
@@ -38,6 +50,25 @@ build_rpc_trait! {
             &self
         ) -> PuddleResult<Vec<DropletInfo>>;
 
+        #[rpc(name = "mark_faulty")]
+        fn mark_faulty(
+            &self,
+            Location
+        ) -> PuddleResult<Vec<DropletId>>;
+
+        #[rpc(name = "set_compatibility")]
+        fn set_compatibility(
+            &self,
+            Compatibility
+        ) -> PuddleResult<()>;
+
+        #[rpc(name = "set_batching")]
+        fn set_batching(
+            &self,
+            ProcessId,
+            bool
+        ) -> PuddleResult<()>;
+
         #[rpc(name = "flush")]
         fn flush(
             &self,
@@ -50,7 +81,8 @@ build_rpc_trait! {
             ProcessId,
             Option<Location>,
             f64,
-            Option<Location>
+            Option<Location>,
+            Trailing<Reagent>
         ) -> PuddleResult<DropletId>;
 
         #[rpc(name = "move")]
@@ -83,9 +115,17 @@ impl Rpc for Arc<Manager> {
     // process management commands
     //
 
-    fn new_process(&self, name: String) -> PuddleResult<ProcessId> {
+    fn new_process(&self, name: String, region: Trailing<Region>) -> PuddleResult<ProcessId> {
         // can't the function being implemented, use fully qualified name
-        Manager::new_process(&self, name)
+        match region.into() {
+            Some(region) => Manager::new_process_with_region(&self, name, &region),
+            None => Manager::new_process(&self, name),
+        }
+    }
+
+    fn share_corridor(&self, pid: ProcessId, corridor: Region) -> PuddleResult<()> {
+        // can't the function being implemented, use fully qualified name
+        Manager::share_corridor(&self, pid, &corridor)
     }
 
     fn close_process(&self, pid: ProcessId) -> PuddleResult<()> {
@@ -107,11 +147,31 @@ impl Rpc for Arc<Manager> {
         Manager::visualizer_droplet_info(&self)
     }
 
+    //
+    // board management
+    //
+
+    fn mark_faulty(&self, loc: Location) -> PuddleResult<Vec<DropletId>> {
+        // can't the function being implemented, use fully qualified name
+        Manager::mark_faulty(&self, loc)
+    }
+
+    fn set_compatibility(&self, compatibility: Compatibility) -> PuddleResult<()> {
+        // can't the function being implemented, use fully qualified name
+        Manager::set_compatibility(&self, compatibility);
+        Ok(())
+    }
+
     //
     // Droplet manipulation
     // delegate to process
     //
 
+    fn set_batching(&self, pid: ProcessId, batching: bool) -> PuddleResult<()> {
+        let p = self.get_process(pid)?;
+        p.set_batching(batching)
+    }
+
     fn flush(&self, pid: ProcessId) -> PuddleResult<()> {
         let p = self.get_process(pid)?;
         p.flush().map(|_result| ())
@@ -123,9 +183,13 @@ impl Rpc for Arc<Manager> {
         loc: Option<Location>,
         vol: f64,
         dim: Option<Location>,
+        reagent: Trailing<Reagent>,
     ) -> PuddleResult<DropletId> {
         let p = self.get_process(pid)?;
-        p.input(loc, vol, dim)
+        match reagent.into() {
+            Some(reagent) => p.input_reagent(loc, vol, dim, reagent),
+            None => p.input(loc, vol, dim),
+        }
     }
 
     fn move_droplet(&self, pid: ProcessId, d: DropletId, loc: Location) -> PuddleResult<DropletId> {
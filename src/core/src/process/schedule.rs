@@ -0,0 +1,267 @@
+use std::cmp::Reverse;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use grid::DropletId;
+use plan::{Pending, Planner};
+use process::{ProcessId, PuddleError, PuddleResult};
+use util::collections::{Map, Set};
+
+/// Where a job is in line; earlier jobs have smaller tickets.
+type Ticket = usize;
+
+/// Some commands from one process that get planned together, and where to
+/// say how that went.
+struct Job {
+    pid: ProcessId,
+    commands: Vec<Pending>,
+    /// The droplets the job uses that it doesn't make itself.
+    inputs: Set<DropletId>,
+    outputs: Set<DropletId>,
+    done: Sender<PuddleResult<()>>,
+}
+
+impl Job {
+    fn new(pid: ProcessId, commands: Vec<Pending>, done: Sender<PuddleResult<()>>) -> Job {
+        let outputs: Set<_> = commands
+            .iter()
+            .flat_map(|c| c.outputs().iter().cloned())
+            .collect();
+        let inputs = commands
+            .iter()
+            .flat_map(|c| c.inputs().iter().cloned())
+            .filter(|id| !outputs.contains(id))
+            .collect();
+        Job {
+            pid,
+            commands,
+            inputs,
+            outputs,
+            done,
+        }
+    }
+}
+
+/// The jobs waiting to be planned, from every process. A job waits on every
+/// earlier job that makes one of its inputs; the rest are ready.
+#[derive(Default)]
+struct Queue {
+    next_ticket: Ticket,
+    jobs: Map<Ticket, Job>,
+    priorities: Map<ProcessId, u32>,
+    /// Whose job went last, so that the next one goes to somebody else.
+    last_pid: Option<ProcessId>,
+}
+
+impl Queue {
+    fn push(&mut self, job: Job) -> Ticket {
+        let ticket = self.next_ticket;
+        self.next_ticket += 1;
+        self.jobs.insert(ticket, job);
+        ticket
+    }
+
+    fn is_ready(&self, ticket: Ticket) -> bool {
+        let job = &self.jobs[&ticket];
+        self.jobs
+            .range(..ticket)
+            .all(|(_, earlier)| earlier.outputs.is_disjoint(&job.inputs))
+    }
+
+    /// Takes the ready job that should go next. Processes with a higher
+    /// priority go first, and processes with the same priority take turns,
+    /// each going in the order it asked.
+    fn pop(&mut self) -> Option<Job> {
+        let last_pid = self.last_pid;
+        let turn = |pid: ProcessId| match last_pid {
+            Some(last) => (pid <= last, pid),
+            None => (false, pid),
+        };
+        let ticket = self.jobs
+            .keys()
+            .cloned()
+            .filter(|&t| self.is_ready(t))
+            .min_by_key(|t| {
+                let pid = self.jobs[t].pid;
+                let priority = self.priorities.get(&pid).cloned().unwrap_or(0);
+                (Reverse(priority), turn(pid), *t)
+            })?;
+        let job = self.jobs.remove(&ticket).unwrap();
+        self.last_pid = Some(job.pid);
+        Some(job)
+    }
+
+    /// Takes the jobs that can't happen now that `outputs` won't be made,
+    /// along with the droplet each was missing.
+    fn take_dependents(&mut self, outputs: &Set<DropletId>) -> Vec<(Job, DropletId)> {
+        let mut missing = outputs.clone();
+        let mut dependents = Vec::new();
+        let tickets: Vec<Ticket> = self.jobs.keys().cloned().collect();
+        for ticket in tickets {
+            let id = match self.jobs[&ticket].inputs.intersection(&missing).next() {
+                Some(id) => *id,
+                None => continue,
+            };
+            let job = self.jobs.remove(&ticket).unwrap();
+            missing.extend(job.outputs.iter().cloned());
+            dependents.push((job, id));
+        }
+        dependents
+    }
+}
+
+/// Decides what the planner works on next, for all the processes at once.
+///
+/// Commands go into one queue no matter which process sent them. Whoever gets
+/// the planner plans everything that's ready, not just their own commands, so
+/// commands get planned in the queue's order instead of whatever order the
+/// threads got the lock in. Commands from different processes never wait on
+/// each other, so their inputs can often be routed in the same time window
+/// (see `GridView::route_windowed`).
+pub struct Scheduler {
+    planner: Arc<Mutex<Planner>>,
+    queue: Mutex<Queue>,
+}
+
+impl Scheduler {
+    pub fn new(planner: Arc<Mutex<Planner>>) -> Scheduler {
+        Scheduler {
+            planner,
+            queue: Mutex::new(Queue::default()),
+        }
+    }
+
+    pub fn planner(&self) -> MutexGuard<Planner> {
+        self.planner.lock().unwrap()
+    }
+
+    /// Lets the commands of process `pid` go ahead of those from processes
+    /// with a lower priority. Everybody starts at 0.
+    pub fn set_priority(&self, pid: ProcessId, priority: u32) {
+        self.queue.lock().unwrap().priorities.insert(pid, priority);
+    }
+
+    /// Forgets about process `pid`, once it is closed.
+    pub fn forget(&self, pid: ProcessId) {
+        self.queue.lock().unwrap().priorities.remove(&pid);
+    }
+
+    /// Plans `commands` from process `pid` together, returning once they are
+    /// planned. One command is planned as it is, more than that as a batch;
+    /// see `Planner::plan_batch`.
+    pub fn submit(&self, pid: ProcessId, commands: Vec<Pending>) -> PuddleResult<()> {
+        let (tx, rx) = channel();
+        self.queue.lock().unwrap().push(Job::new(pid, commands, tx));
+
+        {
+            let mut planner = self.planner.lock().unwrap();
+            loop {
+                let job = match self.queue.lock().unwrap().pop() {
+                    Some(job) => job,
+                    None => break,
+                };
+                let result = plan_job(&mut planner, &job);
+                if result.is_err() {
+                    let dependents = self.queue.lock().unwrap().take_dependents(&job.outputs);
+                    for (dependent, id) in dependents {
+                        debug!("Dropping a job of process {}, missing {:?}", dependent.pid, id);
+                        let _ = dependent.done.send(Err(PuddleError::MissingInput(id)));
+                    }
+                }
+                // washing between commands, rather than whenever the board
+                // happens to be idle, keeps seeded runs the same
+                if self.queue.lock().unwrap().jobs.is_empty() {
+                    planner.wash();
+                }
+                // the process may have gone away, which is fine
+                let _ = job.done.send(result);
+            }
+        }
+
+        // somebody has planned it by now, if not us
+        rx.recv().expect("Job was dropped without being planned")
+    }
+}
+
+fn plan_job(planner: &mut Planner, job: &Job) -> PuddleResult<()> {
+    if let [ref pending] = *job.commands.as_slice() {
+        let cmd = pending.build(&planner.gridview())?;
+        planner.plan(cmd).map_err(PuddleError::PlanError)
+    } else {
+        planner.plan_batch(&job.commands)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use command::Move;
+    use grid::Location;
+
+    fn id(id: usize) -> DropletId {
+        DropletId { id, process_id: 0 }
+    }
+
+    fn job(pid: ProcessId, input: usize, output: usize) -> Job {
+        let (i, o) = (id(input), id(output));
+        let pending = Pending::new(&[i], &[o], move |_| Move::new(i, Location { y: 0, x: 0 }, o));
+        Job::new(pid, vec![pending], channel().0)
+    }
+
+    fn pop_all(queue: &mut Queue) -> Vec<(ProcessId, DropletId)> {
+        let mut popped = Vec::new();
+        while let Some(job) = queue.pop() {
+            popped.push((job.pid, *job.outputs.iter().next().unwrap()));
+        }
+        popped
+    }
+
+    #[test]
+    fn test_queue_takes_turns() {
+        let mut queue = Queue::default();
+        queue.push(job(0, 0, 1));
+        queue.push(job(0, 1, 2));
+        queue.push(job(0, 2, 3));
+        queue.push(job(1, 10, 11));
+        queue.push(job(2, 20, 21));
+
+        let popped = pop_all(&mut queue);
+        let pids: Vec<_> = popped.iter().map(|&(pid, _)| pid).collect();
+        assert_eq!(pids, vec![0, 1, 2, 0, 0]);
+        // a process's own jobs still go in order
+        assert_eq!(popped[3].1, id(2));
+        assert_eq!(popped[4].1, id(3));
+    }
+
+    #[test]
+    fn test_queue_priority() {
+        let mut queue = Queue::default();
+        queue.priorities.insert(1, 5);
+        queue.push(job(0, 0, 1));
+        queue.push(job(1, 10, 11));
+        // this one can't go until the job that makes its input does
+        queue.push(job(2, 1, 2));
+        queue.priorities.insert(2, 10);
+
+        let pids: Vec<_> = pop_all(&mut queue).iter().map(|&(pid, _)| pid).collect();
+        assert_eq!(pids, vec![1, 0, 2]);
+    }
+
+    #[test]
+    fn test_queue_take_dependents() {
+        let mut queue = Queue::default();
+        queue.push(job(0, 1, 2));
+        queue.push(job(0, 2, 3));
+        queue.push(job(1, 10, 11));
+
+        let failed = vec![id(1)].into_iter().collect();
+        let dropped: Vec<_> = queue
+            .take_dependents(&failed)
+            .iter()
+            .map(|(job, id)| (job.pid, *id))
+            .collect();
+        assert_eq!(dropped, vec![(0, id(1)), (0, id(2))]);
+        assert_eq!(queue.jobs.len(), 1);
+    }
+}
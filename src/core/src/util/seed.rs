@@ -0,0 +1,16 @@
+use rand::rngs::StdRng;
+use rand::{thread_rng, Rng, SeedableRng};
+
+/// An rng that always gives the same numbers for the same `seed`.
+pub fn rng_from_seed(seed: u64) -> StdRng {
+    let mut bytes = [0u8; 32];
+    for (i, byte) in bytes.iter_mut().enumerate().take(8) {
+        *byte = (seed >> (8 * i)) as u8;
+    }
+    StdRng::from_seed(bytes)
+}
+
+/// A seed for when nobody asked for one in particular.
+pub fn random_seed() -> u64 {
+    thread_rng().gen()
+}
@@ -1,11 +1,20 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::channel;
+use std::sync::Arc;
+use std::time::Duration;
 
 extern crate puddle_core;
 
 extern crate crossbeam;
 
+#[macro_use]
+extern crate serde_json;
+
 extern crate env_logger;
 
+extern crate rand;
+
 use puddle_core::*;
 
 fn manager_from_rect<'a>(rows: usize, cols: usize) -> Manager {
@@ -17,7 +26,7 @@ fn manager_from_rect_with_error<'a>(rows: usize, cols: usize) -> Manager {
     // let err_opts = ErrorOptions {
     //     split_error_stdev: split_err,
     // };
-    let man = Manager::new(false, grid);
+    let man = Manager::new(false, grid, ManagerOptions::default());
     let _ = env_logger::try_init();
     man
 }
@@ -147,7 +156,7 @@ fn process_isolation() {
 }
 
 #[test]
-#[should_panic(expected = "PlanError(PlaceError)")]
+#[should_panic(expected = "PlanError(PlaceError")]
 fn input_does_not_fit() {
     let man = manager_from_rect(2, 2);
     let p = man.get_new_process("test");
@@ -156,6 +165,33 @@ fn input_does_not_fit() {
     let _id2 = p.input(None, 1.0, None).unwrap();
 }
 
+#[test]
+fn place_error_says_why() {
+    let man = manager_from_rect(4, 4);
+    let p = man.get_new_process("test");
+
+    let _id = p.input(Some(Location { y: 1, x: 1 }), 1.0, None).unwrap();
+    let big = Some(Location { y: 3, x: 3 });
+    let err = p.input(None, 1.0, big).unwrap_err();
+    match err {
+        PuddleError::PlanError(PlanError::PlaceError {
+            ref shape,
+            ref occupied,
+            ..
+        }) => {
+            assert_eq!(*shape, Location { y: 3, x: 3 });
+            assert_eq!(*occupied, vec![Location { y: 1, x: 1 }]);
+        }
+        ref err => panic!("wrong error: {:?}", err),
+    }
+
+    // clients get the same thing as JSON
+    let json = serde_json::to_value(&err).unwrap();
+    let place_error = &json["PlanError"]["PlaceError"];
+    assert_eq!(place_error["shape"], json!({"y": 3, "x": 3}));
+    assert_eq!(place_error["occupied"][0], json!({"y": 1, "x": 1}));
+}
+
 fn check_mix_dimensions(dim1: Location, dim2: Location, dim_result: Location) {
     let man = manager_from_rect(9, 9);
     let p = man.get_new_process("test");
@@ -275,3 +311,697 @@ fn split_single_nonzero_dimensions() {
     assert_eq!(droplets[&id1].dimensions, dim);
     assert_eq!(droplets[&id2].dimensions, dim);
 }
+
+#[test]
+fn split_on_narrow_board() {
+    // a split is wider than this board, so it has to be turned on its side
+    let man = manager_from_rect(9, 3);
+    let p = man.get_new_process("test");
+
+    let id = p.input(None, 1.0, None).unwrap();
+    let (id1, id2) = p.split(id).unwrap();
+
+    let droplets = info_dict(&p);
+    let (loc1, loc2) = (droplets[&id1].location, droplets[&id2].location);
+    assert_eq!(loc1.x, loc2.x);
+    assert!(loc1.distance_to(&loc2) > 1);
+}
+
+#[test]
+fn initial_state_from_ascii() {
+    let board = "\
+        ............\n\
+        .a....bb....\n\
+        ............\n\
+        ............\n\
+        ....cc......\n\
+        ....cc......\n\
+        ............\n\
+        ............";
+    let state = InitialState::from_ascii(board).unwrap();
+    let man = Manager::from_initial_state(false, state, ManagerOptions::default()).unwrap();
+    let _ = env_logger::try_init();
+
+    let ids = man.initial_droplets().clone();
+    assert_eq!(ids.len(), 3);
+
+    let pid = ids[&'a'].process_id;
+    let p = man.get_process(pid).unwrap();
+    let droplets = info_dict(&p);
+
+    assert_eq!(droplets.len(), 3);
+    assert_eq!(droplets[&ids[&'a']].location, Location { y: 1, x: 1 });
+    assert_eq!(droplets[&ids[&'b']].dimensions, Location { y: 1, x: 2 });
+    assert_eq!(droplets[&ids[&'c']].dimensions, Location { y: 2, x: 2 });
+    assert!(float_epsilon_equal(droplets[&ids[&'c']].volume, 4.0));
+
+    // the droplets are usable like any others
+    let ab = p.mix(ids[&'a'], ids[&'b']).unwrap();
+    let droplets = info_dict(&p);
+    assert!(float_epsilon_equal(droplets[&ab].volume, 3.0));
+}
+
+#[test]
+fn mark_faulty_strands_droplet() {
+    let man = manager_from_rect(5, 5);
+    let p = man.get_new_process("test");
+
+    let loc = Location { y: 2, x: 2 };
+    let id = p.input(Some(loc), 1.0, None).unwrap();
+    p.flush().unwrap();
+
+    let stranded = man.mark_faulty(loc).unwrap();
+    assert_eq!(stranded, vec![id]);
+
+    match p.move_droplet(id, Location { y: 0, x: 0 }) {
+        Err(PuddleError::PlanError(PlanError::StrandedDroplet { id: sid, location })) => {
+            assert_eq!(sid, id);
+            assert_eq!(location, loc);
+        }
+        r => panic!("expected a stranded droplet, got {:?}", r),
+    }
+
+    // the next flush says so too
+    let info = p.flush().unwrap();
+    assert_eq!(info.len(), 1);
+    assert_eq!(info[0].location, loc);
+    assert_eq!(info[0].stranded, Some(loc));
+}
+
+fn manager_with_physical_params(rows: usize, cols: usize) -> Manager {
+    let mut grid = Grid::rectangle(rows, cols);
+    // 2mm pitch, 0.25mm gap: an electrode holds 1µL
+    grid.pitch = Some(2.0);
+    grid.gap_height = Some(0.25);
+    let man = Manager::new(false, grid, ManagerOptions::default());
+    let _ = env_logger::try_init();
+    man
+}
+
+#[test]
+fn volume_determines_footprint() {
+    let man = manager_with_physical_params(12, 12);
+    let p = man.get_new_process("test");
+
+    let id1 = p.input(None, 1.0, None).unwrap();
+    let id2 = p.input(None, 3.0, None).unwrap();
+    let droplets = info_dict(&p);
+    assert_eq!(droplets[&id1].dimensions, Location { y: 1, x: 1 });
+    assert_eq!(droplets[&id2].dimensions, Location { y: 2, x: 2 });
+
+    let id12 = p.mix(id1, id2).unwrap();
+    let droplets = info_dict(&p);
+    assert_eq!(droplets[&id12].dimensions, Location { y: 2, x: 2 });
+
+    let (a, b) = p.split(id12).unwrap();
+    let droplets = info_dict(&p);
+    assert_eq!(droplets[&a].dimensions, Location { y: 1, x: 2 });
+    assert_eq!(droplets[&b].dimensions, Location { y: 1, x: 2 });
+}
+
+#[test]
+fn volume_does_not_fit() {
+    let man = manager_with_physical_params(4, 4);
+    let p = man.get_new_process("test");
+
+    // too much liquid for the requested footprint
+    let dim = Location { y: 1, x: 2 };
+    match p.input(None, 3.0, Some(dim)) {
+        Err(PuddleError::VolumeDoesNotFit { dimensions, .. }) => assert_eq!(dimensions, dim),
+        r => panic!("expected VolumeDoesNotFit, got {:?}", r),
+    }
+
+    // too much liquid for the whole board
+    match p.input(None, 30.0, None) {
+        Err(PuddleError::VolumeDoesNotFit { .. }) => (),
+        r => panic!("expected VolumeDoesNotFit, got {:?}", r),
+    }
+}
+
+#[test]
+fn volume_out_of_bounds() {
+    let mut grid = Grid::rectangle(9, 9);
+    grid.min_volume = Some(1.0);
+    grid.max_volume = Some(3.0);
+    let man = Manager::new(false, grid, ManagerOptions::default());
+    let p = man.get_new_process("test");
+
+    match p.input(None, 4.0, None) {
+        Err(PuddleError::VolumeOutOfBounds { volume, .. }) => {
+            assert!(float_epsilon_equal(volume, 4.0))
+        }
+        r => panic!("expected VolumeOutOfBounds, got {:?}", r),
+    }
+
+    let id1 = p.input(None, 2.0, None).unwrap();
+    let id2 = p.input(None, 2.0, None).unwrap();
+
+    // mixing would make a droplet too big
+    match p.mix(id1, id2) {
+        Err(PuddleError::VolumeOutOfBounds { volume, .. }) => {
+            assert!(float_epsilon_equal(volume, 4.0))
+        }
+        r => panic!("expected VolumeOutOfBounds, got {:?}", r),
+    }
+
+    // splitting once is fine, but not twice
+    let (half, _) = p.split(id1).unwrap();
+    match p.split(half) {
+        Err(PuddleError::VolumeOutOfBounds { volume, .. }) => {
+            assert!(float_epsilon_equal(volume, 0.5))
+        }
+        r => panic!("expected VolumeOutOfBounds, got {:?}", r),
+    }
+}
+
+#[test]
+fn placement_avoids_chokepoints() {
+    // first fit would put the droplet in the top left corner, which is the
+    // only way into the strip along the top
+    let board = [".....", ".    ", ".....", "....."].join("\n");
+    let grid = Grid::from_ascii(&board).unwrap();
+    let chokepoints = grid.bottlenecks(&Location { y: 1, x: 1 }).cells();
+    assert!(chokepoints.contains(&Location { y: 0, x: 0 }));
+
+    let man = Manager::new(false, grid, ManagerOptions::default());
+    let p = man.get_new_process("test");
+
+    let id = p.input(None, 1.0, None).unwrap();
+    let droplets = info_dict(&p);
+    assert!(!chokepoints.contains(&droplets[&id].location));
+}
+
+#[test]
+fn placement_near_inputs() {
+    let man = manager_from_rect(12, 12);
+    let p = man.get_new_process("test");
+
+    // first fit would mix these up in the top left corner
+    let id1 = p.input(Some(Location { y: 10, x: 7 }), 1.0, None).unwrap();
+    let id2 = p.input(Some(Location { y: 10, x: 10 }), 1.0, None).unwrap();
+    let id12 = p.mix(id1, id2).unwrap();
+
+    let droplets = info_dict(&p);
+    let loc = droplets[&id12].location;
+    assert!(loc.y >= 6 && loc.x >= 5, "mixed at {}", loc);
+}
+
+#[test]
+fn non_rectangular_droplets() {
+    // an L-shaped droplet tucked into the corner, with a droplet nestled
+    // into its notch diagonally
+    let board = "\
+        a.........\n\
+        a.b.......\n\
+        aa........\n\
+        ..........\n\
+        ..........\n\
+        ..........";
+    let state = InitialState::from_ascii(board);
+    // b is touching a diagonally
+    assert!(state.is_err());
+
+    let board = "\
+        a.........\n\
+        a..b......\n\
+        aa........\n\
+        ..........\n\
+        ..........\n\
+        ..........";
+    let state = InitialState::from_ascii(board).unwrap();
+    let man = Manager::from_initial_state(false, state, ManagerOptions::default()).unwrap();
+    let _ = env_logger::try_init();
+
+    let ids = man.initial_droplets().clone();
+    let p = man.get_process(ids[&'a'].process_id).unwrap();
+    let droplets = info_dict(&p);
+
+    let a = &droplets[&ids[&'a']];
+    assert_eq!(a.dimensions, Location { y: 3, x: 2 });
+    assert_eq!(a.cells.as_ref().map(|c| c.len()), Some(4));
+    assert_eq!(droplets[&ids[&'b']].cells, None);
+
+    // mixing droplets of different heights leaves an uneven droplet
+    let c = p.input(None, 1.0, Some(Location { y: 2, x: 1 })).unwrap();
+    let d = p.input(None, 1.0, Some(Location { y: 1, x: 1 })).unwrap();
+    let cd = p.mix(c, d).unwrap();
+    let droplets = info_dict(&p);
+    assert_eq!(droplets[&cd].dimensions, Location { y: 2, x: 2 });
+    assert_eq!(droplets[&cd].cells.as_ref().map(|c| c.len()), Some(3));
+
+    // and the L can still be moved around
+    let a2 = p.move_droplet(ids[&'a'], Location { y: 3, x: 3 }).unwrap();
+    let droplets = info_dict(&p);
+    assert_eq!(droplets[&a2].location, Location { y: 3, x: 3 });
+}
+
+#[test]
+fn process_regions() {
+    let man = manager_from_rect(5, 12);
+
+    // process a gets the middle of the board
+    let middle = Region::Rect {
+        location: Location { y: 0, x: 3 },
+        dimensions: Location { y: 5, x: 6 },
+    };
+    let pid_a = man.new_process_with_region("a", &middle).unwrap();
+    let a = man.get_process(pid_a).unwrap();
+    let b = man.get_new_process("b");
+
+    // nobody else can have it
+    assert!(man.new_process_with_region("c", &middle).is_err());
+
+    let da = a.input(None, 1.0, None).unwrap();
+    let loc_b = Location { y: 2, x: 0 };
+    let db = b.input(Some(loc_b), 1.0, None).unwrap();
+
+    let droplets = info_dict(&a);
+    let x = droplets[&da].location.x;
+    assert!(3 <= x && x < 9);
+
+    // a has to stay in its region
+    match a.move_droplet(da, Location { y: 0, x: 0 }) {
+        Err(PuddleError::PlanError(PlanError::OutsideRegion { location })) => {
+            assert_eq!(location, Location { y: 0, x: 0 })
+        }
+        r => panic!("expected OutsideRegion, got {:?}", r),
+    }
+
+    // and b can only get across through a corridor
+    let corridor = Region::Cells((3..9).map(|x| Location { y: 4, x }).collect());
+    man.share_corridor(pid_a, &corridor).unwrap();
+    let db2 = b.move_droplet(db, Location { y: 2, x: 11 }).unwrap();
+    let droplets = info_dict(&b);
+    assert_eq!(droplets[&db2].location, Location { y: 2, x: 11 });
+}
+
+/// Leaves everything to conflict-based search, counting what it's asked to
+/// do.
+#[derive(Debug, Default)]
+struct CountingRouter {
+    inner: ConflictBased,
+    routed: AtomicUsize,
+    tightened: AtomicUsize,
+    /// The most steps any routing it came up with took.
+    longest: AtomicUsize,
+}
+
+impl Router for CountingRouter {
+    fn route(
+        &self,
+        droplets: &BTreeMap<DropletId, Droplet>,
+        grid: &Grid,
+        costs: &Costs,
+        regions: &Regions,
+        residue: &Residue,
+        rng: &mut rand::RngCore,
+    ) -> Result<BTreeMap<DropletId, Path>, RouteFailure> {
+        self.routed.fetch_add(1, Ordering::SeqCst);
+        let paths = self.inner.route(droplets, grid, costs, regions, residue, rng)?;
+        let steps = paths.values().map(|path| path.len() - 1).max().unwrap_or(0);
+        self.longest.fetch_max(steps, Ordering::SeqCst);
+        Ok(paths)
+    }
+
+    fn route_reserved(
+        &self,
+        droplets: &BTreeMap<DropletId, Droplet>,
+        reserved: &[Vec<&Droplet>],
+        grid: &Grid,
+        costs: &Costs,
+        regions: &Regions,
+        residue: &Residue,
+    ) -> Option<BTreeMap<DropletId, Path>> {
+        let paths = self.inner.route_reserved(droplets, reserved, grid, costs, regions, residue);
+        assert!(paths.is_none());
+        paths
+    }
+
+    fn tighten(
+        &self,
+        paths: &mut BTreeMap<DropletId, Path>,
+        droplets: &BTreeMap<DropletId, Droplet>,
+        grid: &Grid,
+        costs: &Costs,
+        regions: &Regions,
+        residue: &Residue,
+    ) {
+        self.tightened.fetch_add(1, Ordering::SeqCst);
+        self.inner.tighten(paths, droplets, grid, costs, regions, residue)
+    }
+}
+
+#[test]
+fn conflict_based_router() {
+    let grid = Grid::rectangle(9, 9);
+    let router = Arc::new(CountingRouter::default());
+    let options = ManagerOptions {
+        router: Some(Arc::clone(&router) as Arc<Router>),
+        ..ManagerOptions::default()
+    };
+    let man = Manager::new(false, grid, options);
+    let _ = env_logger::try_init();
+    let p = man.get_new_process("test");
+
+    let id1 = p.input(None, 1.0, None).unwrap();
+    let id2 = p.input(None, 1.0, None).unwrap();
+    let id12 = p.mix(id1, id2).unwrap();
+    let (id3, id4) = p.split(id12).unwrap();
+
+    let droplets = info_dict(&p);
+    assert_eq!(
+        droplets.keys().collect::<HashSet<_>>(),
+        vec![id3, id4].iter().collect()
+    );
+
+    // every path came from the search, and nothing else touched them after
+    let routed = router.routed.load(Ordering::SeqCst);
+    assert!(routed > 0);
+    assert_eq!(router.tightened.load(Ordering::SeqCst), routed);
+}
+
+#[test]
+fn conflict_based_router_within_horizon() {
+    // b sits in the middle of the corridor, so a can only get by if b ducks
+    // into the bay and comes back. Routing b first and then a, like
+    // prioritized routing might, gets nowhere.
+    let board = ["   .   ", "   .   ", "   .   ", "......."].join("\n");
+    let grid = Grid::from_ascii(&board).unwrap();
+    // just enough steps for b to get out of the way and back in time
+    let horizon = 8;
+    let router = Arc::new(CountingRouter {
+        inner: ConflictBased {
+            horizon,
+            ..ConflictBased::default()
+        },
+        ..CountingRouter::default()
+    });
+    let options = ManagerOptions {
+        router: Some(Arc::clone(&router) as Arc<Router>),
+        ..ManagerOptions::default()
+    };
+    let man = Manager::new(false, grid, options);
+    let _ = env_logger::try_init();
+    let p = man.get_new_process("test");
+
+    let a = p.input(Some(Location { y: 3, x: 0 }), 1.0, None).unwrap();
+    let b = p.input(Some(Location { y: 3, x: 3 }), 1.0, None).unwrap();
+    let a2 = p.move_droplet(a, Location { y: 3, x: 6 }).unwrap();
+
+    let droplets = info_dict(&p);
+    assert_eq!(droplets[&a2].location, Location { y: 3, x: 6 });
+    assert_eq!(droplets[&b].location, Location { y: 3, x: 3 });
+    assert_eq!(router.longest.load(Ordering::SeqCst), horizon as usize);
+}
+
+#[test]
+fn seeded_runs_match() {
+    let run = || {
+        let options = ManagerOptions {
+            seed: Some(42),
+            ..ManagerOptions::default()
+        };
+        let man = Manager::new(false, Grid::rectangle(9, 9), options);
+        assert_eq!(man.seed(), 42);
+        let p = man.get_new_process("test");
+
+        let ids: Vec<_> = (0..4).map(|_| p.input(None, 1.0, None).unwrap()).collect();
+        let id01 = p.mix(ids[0], ids[1]).unwrap();
+        let id23 = p.mix(ids[2], ids[3]).unwrap();
+        p.mix(id01, id23).unwrap();
+
+        let mut droplets: Vec<_> = info_dict(&p).values().map(|d| d.location).collect();
+        droplets.sort();
+        droplets
+    };
+
+    // the same seed plans the same way every time
+    assert_eq!(run(), run());
+}
+
+#[test]
+fn seeded_runs_match_with_washes() {
+    let run = || {
+        let mut grid = Grid::rectangle(7, 7);
+        for &(y, x) in &[(0, 0), (6, 6)] {
+            grid.vec[y][x].as_mut().unwrap().kind = CellKind::Reservoir;
+        }
+        let reservoir_pin = grid.get_cell(&Location { y: 0, x: 0 }).unwrap().pin;
+        let (tx, rx) = channel();
+        let options = ManagerOptions {
+            seed: Some(42),
+            frames: Some(tx),
+            compatibility: Compatibility {
+                incompatible: vec![("acid".into(), "base".into())],
+                penalty: None,
+            },
+            ..ManagerOptions::default()
+        };
+        let man = Manager::new(false, grid, options);
+        let _ = env_logger::try_init();
+        let p = man.get_new_process("test");
+
+        let acid = p.input_reagent(Some(Location { y: 3, x: 0 }), 1.0, None, "acid".into());
+        p.move_droplet(acid.unwrap(), Location { y: 3, x: 6 }).unwrap();
+        p.flush().unwrap();
+
+        // the wash may still be going after the flush, so wait for it to end
+        let frames: Vec<_> = (0..)
+            .map(|_| rx.recv_timeout(Duration::from_millis(500)))
+            .take_while(|frame| frame.is_ok())
+            .map(|frame| frame.unwrap())
+            .collect();
+        assert!(frames.iter().any(|frame| frame.pins.contains(&reservoir_pin)));
+        frames
+    };
+
+    // washes are planned along with everything else, so they replay too
+    assert_eq!(run(), run());
+}
+
+#[test]
+fn batch_reorders_to_fit() {
+    let board = "\
+        ...   \n\
+        ...   \n\
+        ...  .";
+    // with no weights, placement is first fit, so the small droplet takes
+    // the only spot the big one fits unless the big one goes first
+    let first_fit = || ManagerOptions {
+        placement: PlacementWeights {
+            routing: 0,
+            spacing: 0,
+            fragmentation: 0,
+            penalty: 0,
+        },
+        ..ManagerOptions::default()
+    };
+    let big_dims = Some(Location { y: 3, x: 3 });
+
+    let man = Manager::new(false, Grid::from_ascii(board).unwrap(), first_fit());
+    let _ = env_logger::try_init();
+    let p = man.get_new_process("test");
+    p.input(None, 1.0, None).unwrap();
+    assert!(p.input(None, 1.0, big_dims).is_err());
+
+    let man = Manager::new(false, Grid::from_ascii(board).unwrap(), first_fit());
+    let p = man.get_new_process("test");
+    p.set_batching(true).unwrap();
+    let small = p.input(None, 1.0, None).unwrap();
+    let big = p.input(None, 1.0, big_dims).unwrap();
+
+    let droplets = info_dict(&p);
+    assert_eq!(droplets[&big].location, Location { y: 0, x: 0 });
+    assert_eq!(droplets[&small].location, Location { y: 2, x: 5 });
+}
+
+#[test]
+fn batch_errors_at_flush() {
+    let man = manager_from_rect(9, 9);
+    let p = man.get_new_process("test");
+    p.set_batching(true).unwrap();
+
+    // nothing is planned yet, so using a droplet that was never made only
+    // fails once the batch is
+    let id = p.input(None, 1.0, None).unwrap();
+    let bogus = DropletId {
+        id: 100,
+        process_id: id.process_id,
+    };
+    assert!(p.mix(id, bogus).is_ok());
+    assert!(p.flush().is_err());
+
+    // none of the batch was planned, not even the input before the bad mix
+    assert!(info_dict(&p).is_empty());
+
+    // the batch is gone either way, so the next one starts fresh
+    let id1 = p.input(None, 1.0, None).unwrap();
+    let id2 = p.input(None, 1.0, None).unwrap();
+    let id12 = p.mix(id1, id2).unwrap();
+    p.set_batching(false).unwrap();
+    let droplets = info_dict(&p);
+    assert_eq!(droplets.keys().collect::<Vec<_>>(), vec![&id12]);
+}
+
+#[test]
+fn prioritized_processes() {
+    let manager = manager_from_rect(12, 12);
+    let ps: Vec<_> = (0..4)
+        .map(|i| manager.get_new_process(format!("test-{}", i)))
+        .collect();
+    for (i, p) in ps.iter().enumerate() {
+        manager.set_priority(p.id(), i as u32 % 2);
+    }
+
+    // everyone gets planned, whoever goes first
+    crossbeam::scope(|scope| {
+        for p in &ps {
+            scope.spawn(move || {
+                let a = p.input(None, 1.0, None).unwrap();
+                let b = p.input(None, 1.0, None).unwrap();
+                let ab = p.mix(a, b).unwrap();
+                let droplets = info_dict(p);
+                assert_eq!(droplets.keys().collect::<Vec<_>>(), vec![&ab]);
+            });
+        }
+    });
+}
+
+#[test]
+fn eviction_makes_room() {
+    let board = "\
+        ...    \n\
+        .......\n\
+        ...    ";
+    let evicting = |max_evictions| ManagerOptions {
+        placement: PlacementWeights {
+            routing: 0,
+            spacing: 0,
+            fragmentation: 0,
+            penalty: 0,
+        },
+        max_evictions,
+        ..ManagerOptions::default()
+    };
+    let big_dims = Some(Location { y: 3, x: 3 });
+
+    // the small droplet takes the only spot the big one fits, but it can get
+    // out of the way
+    let man = Manager::new(false, Grid::from_ascii(board).unwrap(), evicting(1));
+    let _ = env_logger::try_init();
+    let p = man.get_new_process("test");
+    let small = p.input(None, 1.0, None).unwrap();
+    let big = p.input(None, 1.0, big_dims).unwrap();
+
+    let droplets = info_dict(&p);
+    assert_eq!(droplets[&big].location, Location { y: 0, x: 0 });
+    assert!(droplets[&small].location.x > 3, "{:?}", droplets[&small]);
+
+    // with two in the way, moving one isn't enough
+    let man = Manager::new(false, Grid::rectangle(3, 3), evicting(1));
+    let p = man.get_new_process("test");
+    p.input(Some(Location { y: 0, x: 0 }), 1.0, None).unwrap();
+    p.input(Some(Location { y: 2, x: 2 }), 1.0, None).unwrap();
+    assert!(p.input(None, 1.0, big_dims).is_err());
+}
+
+#[test]
+fn executor_sends_frames() {
+    let (tx, rx) = channel();
+    let options = ManagerOptions {
+        frames: Some(tx),
+        ..ManagerOptions::default()
+    };
+    let grid = Grid::rectangle(5, 5);
+    let pin = |loc: Location| grid.get_cell(&loc).unwrap().pin;
+    let man = Manager::new(false, grid.clone(), options);
+    let _ = env_logger::try_init();
+    let p = man.get_new_process("test");
+
+    let dest = Location { y: 1, x: 3 };
+    let id = p.input(Some(Location { y: 1, x: 1 }), 1.0, None).unwrap();
+    p.move_droplet(id, dest).unwrap();
+    p.flush().unwrap();
+
+    // with one small droplet, each frame turns on just the electrode under it
+    let mut pins = Vec::new();
+    while pins.last() != Some(&pin(dest)) {
+        let frame = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(frame.pins.len(), 1);
+        pins.extend(frame.pins);
+    }
+    assert!(pins.contains(&pin(Location { y: 1, x: 2 })));
+}
+
+#[test]
+fn residue_keeps_reagents_apart() {
+    let compatibility = |penalty| Compatibility {
+        incompatible: vec![("acid".into(), "base".into())],
+        penalty,
+    };
+    let run = |compatibility| {
+        let options = ManagerOptions {
+            compatibility,
+            ..ManagerOptions::default()
+        };
+        let man = Manager::new(false, Grid::rectangle(5, 5), options);
+        let _ = env_logger::try_init();
+        let p = man.get_new_process("test");
+
+        // the acid leaves residue all the way across the middle row
+        let acid = p.input_reagent(Some(Location { y: 2, x: 0 }), 1.0, None, "acid".into())?;
+        p.move_droplet(acid, Location { y: 2, x: 4 })?;
+
+        let base = p.input_reagent(Some(Location { y: 0, x: 0 }), 1.0, None, "base".into())?;
+        p.move_droplet(base, Location { y: 4, x: 0 })
+    };
+
+    assert!(run(Compatibility::default()).is_ok());
+    match run(compatibility(None)) {
+        Err(PuddleError::PlanError(PlanError::RouteError { .. })) => (),
+        result => panic!("the base shouldn't get across: {:?}", result),
+    }
+    assert!(run(compatibility(Some(10))).is_ok());
+}
+
+#[test]
+fn residue_spares_mixed_droplets() {
+    let options = ManagerOptions {
+        compatibility: Compatibility {
+            incompatible: vec![("acid".into(), "base".into())],
+            penalty: None,
+        },
+        ..ManagerOptions::default()
+    };
+    let man = Manager::new(false, Grid::rectangle(12, 12), options);
+    let _ = env_logger::try_init();
+    let p = man.get_new_process("test");
+
+    // the mix has both in it, so neither one's residue bothers it
+    let acid = p.input_reagent(None, 1.0, None, "acid".into()).unwrap();
+    let base = p.input_reagent(None, 1.0, None, "base".into()).unwrap();
+    let ab = p.mix(acid, base).unwrap();
+    let dest = Location { y: 10, x: 10 };
+    let moved = p.move_droplet(ab, dest).unwrap();
+    assert_eq!(info_dict(&p)[&moved].location, dest);
+}
+
+#[test]
+fn choose_placer() {
+    let run = |placer: Option<Arc<Placer>>| {
+        let options = ManagerOptions {
+            placer,
+            ..ManagerOptions::default()
+        };
+        let man = Manager::new(false, Grid::rectangle(5, 5), options);
+        let _ = env_logger::try_init();
+        let p = man.get_new_process("test");
+        p.input(Some(Location { y: 1, x: 2 }), 1.0, None).unwrap();
+        let id = p.input(None, 1.0, None).unwrap();
+        info_dict(&p)[&id].location
+    };
+
+    // the default keeps its distance, first fit just takes the top left
+    assert_ne!(run(None), Location { y: 0, x: 0 });
+    assert_eq!(run(Some(Arc::new(FirstFit))), Location { y: 0, x: 0 });
+}